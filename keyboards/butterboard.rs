@@ -11,9 +11,9 @@ use crate::keys::*;
 use crate::led::{set_animation, Animation, Led, Speed, Ws2812bDriver};
 use crate::power::{set_power_state, PowerState};
 use crate::side::Side;
-use crate::split::trigger_event;
+use crate::split::Wire;
 
-#[derive(Clone, Copy, defmt::Format)]
+#[derive(Clone, Copy, defmt::Format, Wire)]
 pub struct PersistentData {
     keys_animation: usize,
     wings_animation: usize,
@@ -34,6 +34,8 @@ register_callbacks!(Butterboard, Callbacks, [
     NextWingsAnimation,
     NextStatusAnimation,
     ToggleLighting,
+    // Deprecated: animations are always synchronized now. Kept so raw HID
+    // keeps addressing callbacks by the same index.
     SyncAnimations,
 ]);
 
@@ -44,9 +46,6 @@ register_leds!(Butterboard, Leds, [
     Status: Ws2812bDriver<18, SPI2>,
 ]);
 
-#[cfg(feature = "lighting")]
-register_events!(Butterboard, Events, [SyncAnimations]);
-
 #[rustfmt::skip]
 macro_rules! new_layer {
     (
@@ -87,7 +86,7 @@ impl Butterboard {
     ];
     #[rustfmt::skip]
     const SPECIAL: [Mapping; <Butterboard as KeyboardExtension>::KEYS_TOTAL] = new_layer![
-        Callbacks::SyncAnimations, Callbacks::ToggleLighting, NONE, NONE, HOME, END, INSERT, UP, NONE, PAGEUP,
+        NONE, Callbacks::ToggleLighting, NONE, NONE, HOME, END, INSERT, UP, NONE, PAGEUP,
         Callbacks::NextKeysAnimation, Callbacks::NextWingsAnimation, Callbacks::NextStatusAnimation, NONE, TAB, BACKSPACE, LEFT, DOWN, RIGHT, PAGEDOWN,
         NONE, NONE, NONE, NONE, NONE, DELETE, NONE, NONE, NONE, NONE,
         NONE, NONE, NONE, NONE, NONE, NONE, NONE, NONE, NONE, NONE,
@@ -190,11 +189,10 @@ impl Keyboard for Butterboard {
     #[cfg(feature = "lighting")]
     type Callbacks = Callbacks;
     #[cfg(feature = "lighting")]
-    type Events = Events;
-    #[cfg(feature = "lighting")]
     type Leds = Leds;
 
     const DEVICE_NAME: &'static [u8] = b"Butterboard";
+    const HARDWARE_REVISION: &'static [u8] = b"1.0";
     const LAYER_LOOKUP: &'static [&'static [Mapping; Self::KEYS_TOTAL]] = Layers::LAYER_LOOKUP;
     #[cfg(feature = "lighting")]
     const STATUS_LEDS: Leds = Leds::Status;
@@ -213,17 +211,7 @@ impl Keyboard for Butterboard {
             Callbacks::NextWingsAnimation => self.next_wings_animation().await,
             Callbacks::NextStatusAnimation => self.next_status_animation().await,
             Callbacks::ToggleLighting => self.toggle_lighting().await,
-            Callbacks::SyncAnimations => trigger_event(Side::Both, Events::SyncAnimations).await,
-        }
-    }
-
-    #[cfg(feature = "lighting")]
-    async fn event(&mut self, event: Events) {
-        match event {
-            Events::SyncAnimations => {
-                #[cfg(feature = "lighting")]
-                self.toggle_lighting().await;
-            }
+            Callbacks::SyncAnimations => {}
         }
     }
 
@@ -300,4 +288,16 @@ impl Keyboard for Butterboard {
     async fn sides_disconnected(&mut self) {
         set_power_state(Side::This, PowerState::On).await;
     }
+
+    #[cfg(feature = "lighting")]
+    async fn host_suspended(&mut self, suspended: bool) {
+        // Turn the lighting off while the host is suspended and restore the
+        // previous state once it wakes up again.
+        let power_state = match suspended {
+            true => PowerState::Off,
+            false => self.persistent_data.lighting_state,
+        };
+
+        set_power_state(Side::Both, power_state).await;
+    }
 }
@@ -0,0 +1,297 @@
+// https://www.bluetooth.com/specifications/assigned-numbers/
+// Section 2.6 Appearance Values
+pub const KEYBOARD_ICON: u16 = 0x03C1;
+
+const MAXIMUM_ADVERTISE_LENGTH: usize = 31;
+
+// Every element starts with its length and type.
+const ELEMENT_HEADER_SIZE: usize = 2;
+
+// https://www.bluetooth.com/specifications/assigned-numbers/
+// Section 2.3 Common Data Types
+const FLAGS: u8 = 0x01;
+const COMPLETE_SERVICE_UUIDS_16: u8 = 0x03;
+const COMPLETE_SERVICE_UUIDS_128: u8 = 0x07;
+const SHORTENED_LOCAL_NAME: u8 = 0x08;
+const COMPLETE_LOCAL_NAME: u8 = 0x09;
+const TX_POWER_LEVEL: u8 = 0x0A;
+const APPEARANCE: u8 = 0x19;
+const MANUFACTURER_SPECIFIC_DATA: u8 = 0xFF;
+
+#[derive(Clone, Copy)]
+struct Packet {
+    data: [u8; MAXIMUM_ADVERTISE_LENGTH],
+    used_bytes: usize,
+}
+
+impl Packet {
+    const fn new() -> Self {
+        Self {
+            data: [0; MAXIMUM_ADVERTISE_LENGTH],
+            used_bytes: 0,
+        }
+    }
+
+    const fn remaining(&self) -> usize {
+        MAXIMUM_ADVERTISE_LENGTH - self.used_bytes
+    }
+
+    // Append an element with the prefix followed by the first `length` bytes of
+    // the data.
+    const fn push(mut self, element_type: u8, prefix: &[u8], data: &[u8], length: usize) -> Self {
+        let element_length = prefix.len() + length;
+
+        if ELEMENT_HEADER_SIZE + element_length > self.remaining() {
+            panic!("Advertising element does not fit into the packet");
+        }
+
+        self.data[self.used_bytes] = (element_length + 1) as u8;
+        self.data[self.used_bytes + 1] = element_type;
+
+        let mut offset = 0;
+
+        while offset < element_length {
+            self.data[self.used_bytes + ELEMENT_HEADER_SIZE + offset] = match offset < prefix.len() {
+                true => prefix[offset],
+                false => data[offset - prefix.len()],
+            };
+            offset += 1;
+        }
+
+        self.used_bytes += ELEMENT_HEADER_SIZE + element_length;
+
+        self
+    }
+
+    fn get_slice(&self) -> &[u8] {
+        &self.data[..self.used_bytes]
+    }
+}
+
+/// Builder for the advertising data and scan response. Elements are placed
+/// in the advertising data as long as they fit and in the scan response
+/// otherwise, so the most important elements should be added first.
+pub struct AdvertisingData {
+    advertising: Packet,
+    scan_response: Packet,
+}
+
+impl AdvertisingData {
+    pub const fn new() -> Self {
+        Self {
+            advertising: Packet::new(),
+            scan_response: Packet::new(),
+        }
+    }
+
+    const fn add_internal(mut self, element_type: u8, prefix: &[u8], element_data: &[u8]) -> Self {
+        let size = ELEMENT_HEADER_SIZE + prefix.len() + element_data.len();
+
+        if size <= self.advertising.remaining() {
+            self.advertising = self.advertising.push(element_type, prefix, element_data, element_data.len());
+        } else if size <= self.scan_response.remaining() {
+            self.scan_response = self.scan_response.push(element_type, prefix, element_data, element_data.len());
+        } else {
+            panic!("Advertising data is too big. Try shortening the keyboard name");
+        }
+
+        self
+    }
+
+    pub const fn add_flags(self, flags: u8) -> Self {
+        self.add_internal(FLAGS, &[], &[flags])
+    }
+
+    pub const fn add_services(self, services: &[u8]) -> Self {
+        self.add_internal(COMPLETE_SERVICE_UUIDS_16, &[], services)
+    }
+
+    pub const fn add_service_uuid(self, uuid: &[u8; 16]) -> Self {
+        self.add_internal(COMPLETE_SERVICE_UUIDS_128, &[], uuid)
+    }
+
+    /// Add the name of the device. If the complete name does not fit into
+    /// either packet, a shortened name is added instead.
+    pub const fn add_name(mut self, name: &[u8]) -> Self {
+        let size = ELEMENT_HEADER_SIZE + name.len();
+
+        if size <= self.advertising.remaining() || size <= self.scan_response.remaining() {
+            return self.add_internal(COMPLETE_LOCAL_NAME, &[], name);
+        }
+
+        // Shorten the name to fill the packet with the most room left.
+        let use_advertising = self.advertising.remaining() >= self.scan_response.remaining();
+        let remaining = match use_advertising {
+            true => self.advertising.remaining(),
+            false => self.scan_response.remaining(),
+        };
+
+        if remaining <= ELEMENT_HEADER_SIZE {
+            panic!("Advertising data is too big to fit the name");
+        }
+
+        let length = remaining - ELEMENT_HEADER_SIZE;
+
+        match use_advertising {
+            true => self.advertising = self.advertising.push(SHORTENED_LOCAL_NAME, &[], name, length),
+            false => self.scan_response = self.scan_response.push(SHORTENED_LOCAL_NAME, &[], name, length),
+        }
+
+        self
+    }
+
+    pub const fn add_appearance(self, appearance: u16) -> Self {
+        self.add_internal(APPEARANCE, &[], &appearance.to_le_bytes())
+    }
+
+    /// Add the transmit power in dBm.
+    pub const fn add_tx_power(self, power: i8) -> Self {
+        self.add_internal(TX_POWER_LEVEL, &[], &[power as u8])
+    }
+
+    /// Add manufacturer specific data. The company identifiers are assigned by
+    /// the Bluetooth SIG.
+    pub const fn add_manufacturer_data(self, company_id: u16, data: &[u8]) -> Self {
+        self.add_internal(MANUFACTURER_SPECIFIC_DATA, &company_id.to_le_bytes(), data)
+    }
+
+    pub fn get_slice(&self) -> &[u8] {
+        self.advertising.get_slice()
+    }
+
+    pub fn get_scan_response_slice(&self) -> &[u8] {
+        self.scan_response.get_slice()
+    }
+}
+
+/// Check if raw advertising data advertises the service with the given UUID.
+pub fn advertises_service(data: &[u8], uuid: &[u8; 16]) -> bool {
+    contains_element(data, COMPLETE_SERVICE_UUIDS_128, uuid)
+}
+
+fn contains_element(mut data: &[u8], element_type: u8, element_data: &[u8]) -> bool {
+    while let [length, rest @ ..] = data {
+        let length = *length as usize;
+
+        let Some(element) = rest.get(..length) else {
+            return false;
+        };
+
+        if let [found_type, found_data @ ..] = element {
+            if *found_type == element_type && found_data == element_data {
+                return true;
+            }
+        }
+
+        data = &rest[length..];
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &[u8] = b"A keyboard with a name that is much too long";
+
+    #[test]
+    fn element_layout() {
+        let data = AdvertisingData::new().add_flags(0x06).add_appearance(KEYBOARD_ICON);
+
+        assert_eq!(data.get_slice(), &[2, FLAGS, 0x06, 3, APPEARANCE, 0xC1, 0x03]);
+        assert!(data.get_scan_response_slice().is_empty());
+    }
+
+    #[test]
+    fn element_filling_packet() {
+        let packet = Packet::new().push(MANUFACTURER_SPECIFIC_DATA, &[], &[0; 29], 29);
+
+        assert_eq!(packet.remaining(), 0);
+    }
+
+    // The packet has room for one more byte, which is not even enough for the
+    // header. The size check needs to panic before anything is written.
+    #[test]
+    #[should_panic(expected = "Advertising element does not fit into the packet")]
+    fn element_overflowing_packet() {
+        Packet::new()
+            .push(MANUFACTURER_SPECIFIC_DATA, &[], &[0; 28], 28)
+            .push(FLAGS, &[], &[], 0);
+    }
+
+    #[test]
+    fn spill_into_scan_response() {
+        let uuid = [0xAB; 16];
+        let data = AdvertisingData::new()
+            .add_flags(0x06)
+            .add_service_uuid(&uuid)
+            .add_appearance(KEYBOARD_ICON)
+            .add_tx_power(-4)
+            .add_services(&[0x12, 0x18])
+            .add_flags(0x04);
+
+        // Flags, the service UUID, the appearance and the transmit power take up 28
+        // bytes, so only the last flags still fit.
+        assert_eq!(data.get_slice().len(), MAXIMUM_ADVERTISE_LENGTH);
+        assert!(advertises_service(data.get_slice(), &uuid));
+        assert!(data.get_slice().ends_with(&[2, TX_POWER_LEVEL, -4i8 as u8, 2, FLAGS, 0x04]));
+        assert_eq!(data.get_scan_response_slice(), &[3, COMPLETE_SERVICE_UUIDS_16, 0x12, 0x18]);
+    }
+
+    #[test]
+    fn complete_name_in_scan_response() {
+        let data = AdvertisingData::new().add_service_uuid(&[0xAB; 16]).add_name(&NAME[..20]);
+
+        assert_eq!(data.get_scan_response_slice()[..2], [21, COMPLETE_LOCAL_NAME]);
+        assert_eq!(&data.get_scan_response_slice()[2..], &NAME[..20]);
+    }
+
+    #[test]
+    fn shortened_name() {
+        let data = AdvertisingData::new().add_flags(0x06).add_name(NAME);
+
+        // The scan response has more room left than the advertising data.
+        assert_eq!(data.get_slice(), &[2, FLAGS, 0x06]);
+        assert_eq!(data.get_scan_response_slice().len(), MAXIMUM_ADVERTISE_LENGTH);
+        assert_eq!(data.get_scan_response_slice()[..2], [30, SHORTENED_LOCAL_NAME]);
+        assert_eq!(&data.get_scan_response_slice()[2..], &NAME[..29]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Advertising data is too big to fit the name")]
+    fn no_room_for_name() {
+        AdvertisingData::new()
+            .add_manufacturer_data(0xFFFF, &[0; 27])
+            .add_manufacturer_data(0xFFFF, &[0; 27])
+            .add_name(NAME);
+    }
+
+    #[test]
+    fn manufacturer_data() {
+        let data = AdvertisingData::new().add_manufacturer_data(0x0059, &[1, 2, 3]);
+
+        // The company identifier comes first, least significant byte first.
+        assert_eq!(data.get_slice(), &[6, MANUFACTURER_SPECIFIC_DATA, 0x59, 0x00, 1, 2, 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Advertising data is too big")]
+    fn data_too_big() {
+        AdvertisingData::new()
+            .add_manufacturer_data(0xFFFF, &[0; 27])
+            .add_manufacturer_data(0xFFFF, &[0; 27])
+            .add_flags(0x06);
+    }
+
+    #[test]
+    fn find_service() {
+        let uuid = [0xAB; 16];
+        let data = AdvertisingData::new().add_flags(0x06).add_service_uuid(&uuid);
+
+        assert!(advertises_service(data.get_slice(), &uuid));
+        assert!(!advertises_service(data.get_slice(), &[0xCD; 16]));
+        // Elements that claim to be longer than the data are ignored.
+        assert!(!advertises_service(&data.get_slice()[..10], &uuid));
+    }
+}
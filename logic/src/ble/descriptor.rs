@@ -0,0 +1,565 @@
+// https://www.usb.org/document-library/device-class-definition-hid-111
+// Section 6.2.2 Report Descriptor
+const MAXIMUM_DESCRIPTOR_LENGTH: usize = 512;
+
+const USAGE_PAGE: u8 = 0x04;
+const USAGE: u8 = 0x08;
+const USAGE_MINIMUM: u8 = 0x18;
+const USAGE_MAXIMUM: u8 = 0x28;
+const LOGICAL_MINIMUM: u8 = 0x14;
+const LOGICAL_MAXIMUM: u8 = 0x24;
+const REPORT_SIZE: u8 = 0x74;
+const REPORT_ID: u8 = 0x84;
+const REPORT_COUNT: u8 = 0x94;
+const PUSH: u8 = 0xA4;
+const POP: u8 = 0xB4;
+const INPUT: u8 = 0x80;
+const OUTPUT: u8 = 0x90;
+const FEATURE: u8 = 0xB0;
+const COLLECTION: u8 = 0xA0;
+const END_COLLECTION: u8 = 0xC0;
+const LONG_ITEM: u8 = 0xFE;
+
+const COLLECTION_PHYSICAL: u8 = 0x00;
+const COLLECTION_APPLICATION: u8 = 0x01;
+
+const DATA_ARRAY_ABSOLUTE: u8 = 0x00;
+const CONSTANT: u8 = 0x01;
+const DATA_VARIABLE_ABSOLUTE: u8 = 0x02;
+const DATA_VARIABLE_RELATIVE: u8 = 0x06;
+
+// Report types as used by the Report Reference descriptor.
+// https://www.bluetooth.com/specifications/specs/hid-service-1-0/
+// Section 3.6.1 Report Reference Characteristic Descriptor
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReportType {
+    Input = 1,
+    Output = 2,
+    Feature = 3,
+}
+
+impl ReportType {
+    const fn main_item(self) -> u8 {
+        match self {
+            ReportType::Input => INPUT,
+            ReportType::Output => OUTPUT,
+            ReportType::Feature => FEATURE,
+        }
+    }
+}
+
+pub struct ReportDescriptor {
+    data: [u8; MAXIMUM_DESCRIPTOR_LENGTH],
+    used_bytes: usize,
+}
+
+impl ReportDescriptor {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; MAXIMUM_DESCRIPTOR_LENGTH],
+            used_bytes: 0,
+        }
+    }
+
+    const fn add_internal(mut self, tag: u8, value: u32, size: usize) -> Self {
+        if self.used_bytes + 1 + size > MAXIMUM_DESCRIPTOR_LENGTH {
+            panic!("Report descriptor is too big. Try increasing MAXIMUM_DESCRIPTOR_LENGTH");
+        }
+
+        let size_bits = match size {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            _ => 3,
+        };
+
+        self.data[self.used_bytes] = tag | size_bits;
+
+        let mut offset = 0;
+
+        while offset < size {
+            self.data[self.used_bytes + 1 + offset] = (value >> (offset * 8)) as u8;
+            offset += 1;
+        }
+
+        self.used_bytes += 1 + size;
+
+        self
+    }
+
+    const fn add_unsigned(self, tag: u8, value: u32) -> Self {
+        let size = match value {
+            0..=0xFF => 1,
+            0x100..=0xFFFF => 2,
+            _ => 4,
+        };
+
+        self.add_internal(tag, value, size)
+    }
+
+    const fn add_signed(self, tag: u8, value: i32) -> Self {
+        let size = match value {
+            -0x80..=0x7F => 1,
+            -0x8000..=0x7FFF => 2,
+            _ => 4,
+        };
+
+        self.add_internal(tag, value as u32, size)
+    }
+
+    const fn usage_page(self, usage_page: u16) -> Self {
+        self.add_unsigned(USAGE_PAGE, usage_page as u32)
+    }
+
+    const fn usage(self, usage: u16) -> Self {
+        self.add_unsigned(USAGE, usage as u32)
+    }
+
+    const fn usage_range(self, minimum: u16, maximum: u16) -> Self {
+        self.add_unsigned(USAGE_MINIMUM, minimum as u32)
+            .add_unsigned(USAGE_MAXIMUM, maximum as u32)
+    }
+
+    const fn logical_range(self, minimum: i32, maximum: i32) -> Self {
+        self.add_signed(LOGICAL_MINIMUM, minimum).add_signed(LOGICAL_MAXIMUM, maximum)
+    }
+
+    const fn report_layout(self, size: u8, count: u8) -> Self {
+        self.add_unsigned(REPORT_SIZE, size as u32).add_unsigned(REPORT_COUNT, count as u32)
+    }
+
+    const fn report_id(self, report_id: u8) -> Self {
+        if report_id == 0 {
+            panic!("Report id 0 is reserved");
+        }
+
+        self.add_unsigned(REPORT_ID, report_id as u32)
+    }
+
+    const fn main_item(self, tag: u8, flags: u8) -> Self {
+        self.add_unsigned(tag, flags as u32)
+    }
+
+    const fn collection(self, kind: u8) -> Self {
+        self.add_unsigned(COLLECTION, kind as u32)
+    }
+
+    const fn end_collection(self) -> Self {
+        self.add_internal(END_COLLECTION, 0, 0)
+    }
+
+    /// Boot compatible keyboard with 8 modifier bits, 5 LEDs and 6 key slots
+    /// covering the whole keyboard usage page.
+    pub const fn add_keyboard_report(self, report_id: u8) -> Self {
+        self.usage_page(0x01) // Generic Desktop
+            .usage(0x06) // Keyboard
+            .collection(COLLECTION_APPLICATION)
+            .report_id(report_id)
+            // Modifiers
+            .usage_page(0x07) // Key Codes
+            .usage_range(0xE0, 0xE7)
+            .logical_range(0, 1)
+            .report_layout(1, 8)
+            .main_item(INPUT, DATA_VARIABLE_ABSOLUTE)
+            // Reserved byte
+            .report_layout(8, 1)
+            .main_item(INPUT, CONSTANT)
+            // Leds
+            .usage_page(0x08) // Leds
+            .usage_range(0x01, 0x05)
+            .report_layout(1, 5)
+            .main_item(OUTPUT, DATA_VARIABLE_ABSOLUTE)
+            // Led padding
+            .report_layout(3, 1)
+            .main_item(OUTPUT, CONSTANT)
+            // Key codes
+            .usage_page(0x07) // Key Codes
+            .usage_range(0x00, 0xE7)
+            .logical_range(0, 0xE7)
+            .report_layout(8, 6)
+            .main_item(INPUT, DATA_ARRAY_ABSOLUTE)
+            .end_collection()
+    }
+
+    /// Consumer control with a single 16 bit usage.
+    pub const fn add_consumer_report(self, report_id: u8) -> Self {
+        self.usage_page(0x0C) // Consumer
+            .usage(0x01) // Consumer Control
+            .collection(COLLECTION_APPLICATION)
+            .report_id(report_id)
+            .usage_range(0x000, 0x3FF)
+            .logical_range(0x000, 0x3FF)
+            .report_layout(16, 1)
+            .main_item(INPUT, DATA_ARRAY_ABSOLUTE)
+            .end_collection()
+    }
+
+    /// Mouse with 5 buttons, relative movement, vertical and horizontal
+    /// scrolling.
+    pub const fn add_mouse_report(self, report_id: u8) -> Self {
+        self.usage_page(0x01) // Generic Desktop
+            .usage(0x02) // Mouse
+            .collection(COLLECTION_APPLICATION)
+            .report_id(report_id)
+            .usage(0x01) // Pointer
+            .collection(COLLECTION_PHYSICAL)
+            // Buttons
+            .usage_page(0x09) // Buttons
+            .usage_range(0x01, 0x05)
+            .logical_range(0, 1)
+            .report_layout(1, 5)
+            .main_item(INPUT, DATA_VARIABLE_ABSOLUTE)
+            // Button padding
+            .report_layout(3, 1)
+            .main_item(INPUT, CONSTANT)
+            // Movement and vertical scrolling
+            .usage_page(0x01) // Generic Desktop
+            .usage(0x30) // X
+            .usage(0x31) // Y
+            .usage(0x38) // Wheel
+            .logical_range(-127, 127)
+            .report_layout(8, 3)
+            .main_item(INPUT, DATA_VARIABLE_RELATIVE)
+            // Horizontal scrolling
+            .usage_page(0x0C) // Consumer
+            .usage(0x238) // AC Pan
+            .report_layout(8, 1)
+            .main_item(INPUT, DATA_VARIABLE_RELATIVE)
+            .end_collection()
+            .end_collection()
+    }
+
+    /// Vendor defined input and output reports of arbitrary size that can be
+    /// used to exchange data with host applications.
+    pub const fn add_vendor_report(self, report_id: u8, input_size: u8, output_size: u8) -> Self {
+        self.usage_page(0xFF00) // Vendor Defined
+            .usage(0x01)
+            .collection(COLLECTION_APPLICATION)
+            .report_id(report_id)
+            .logical_range(0, 0xFF)
+            .usage(0x02)
+            .report_layout(8, input_size)
+            .main_item(INPUT, DATA_VARIABLE_ABSOLUTE)
+            .usage(0x03)
+            .report_layout(8, output_size)
+            .main_item(OUTPUT, DATA_VARIABLE_ABSOLUTE)
+            .end_collection()
+    }
+
+    /// Parse the descriptor and make sure that it is well formed. Calling this
+    /// in a const context turns a broken descriptor into a compile error.
+    pub const fn validate(self) -> Self {
+        let mut declared_ids = [false; 256];
+        let mut uses_report_ids = false;
+        let mut main_without_id = false;
+        let mut report_id = 0;
+        let mut depth = 0;
+        let mut offset = 0;
+
+        while offset < self.used_bytes {
+            let (tag, value, length) = self.item(offset);
+
+            match tag {
+                REPORT_ID => {
+                    if value == 0 || value > 0xFF {
+                        panic!("Report descriptor contains an invalid report id");
+                    }
+
+                    if declared_ids[value as usize] {
+                        panic!("Report descriptor declares the same report id twice");
+                    }
+
+                    declared_ids[value as usize] = true;
+                    uses_report_ids = true;
+                    report_id = value;
+                }
+                COLLECTION => depth += 1,
+                END_COLLECTION => {
+                    if depth == 0 {
+                        panic!("Report descriptor closes a collection that was never opened");
+                    }
+
+                    depth -= 1;
+
+                    // Report ids are scoped to top level collections in our descriptors.
+                    if depth == 0 {
+                        report_id = 0;
+                    }
+                }
+                INPUT | OUTPUT | FEATURE => {
+                    if depth == 0 {
+                        panic!("Report descriptor contains a main item outside of a collection");
+                    }
+
+                    if report_id == 0 {
+                        main_without_id = true;
+                    }
+                }
+                PUSH | POP | LONG_ITEM => panic!("Report descriptor contains items that are not supported by the parser"),
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        if depth != 0 {
+            panic!("Report descriptor has unclosed collections");
+        }
+
+        if uses_report_ids && main_without_id {
+            panic!("Report descriptor mixes reports with and without report ids");
+        }
+
+        let mut id = 1;
+
+        while id < declared_ids.len() {
+            if declared_ids[id] {
+                let mut report_type = 0;
+
+                while report_type < 3 {
+                    let report_type_value = match report_type {
+                        0 => ReportType::Input,
+                        1 => ReportType::Output,
+                        _ => ReportType::Feature,
+                    };
+
+                    if self.report_bits(id as u8, report_type_value) % 8 != 0 {
+                        panic!("Report descriptor contains a report that is not byte aligned");
+                    }
+
+                    report_type += 1;
+                }
+            }
+
+            id += 1;
+        }
+
+        self
+    }
+
+    // Returns the tag (with the size bits masked out), the unsigned value and the
+    // total length of the item at the given offset.
+    const fn item(&self, offset: usize) -> (u8, u32, usize) {
+        let prefix = self.data[offset];
+
+        if prefix == LONG_ITEM {
+            return (LONG_ITEM, 0, 3 + self.data[offset + 1] as usize);
+        }
+
+        let size = match prefix & 0b11 {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            _ => 4,
+        };
+
+        if offset + 1 + size > self.used_bytes {
+            panic!("Report descriptor ends in the middle of an item");
+        }
+
+        let mut value = 0;
+        let mut index = 0;
+
+        while index < size {
+            value |= (self.data[offset + 1 + index] as u32) << (index * 8);
+            index += 1;
+        }
+
+        (prefix & !0b11, value, 1 + size)
+    }
+
+    const fn report_bits(&self, report_id: u8, report_type: ReportType) -> usize {
+        let main_item = report_type.main_item();
+        let mut current_id = 0;
+        let mut report_size = 0;
+        let mut report_count = 0;
+        let mut bits = 0;
+        let mut offset = 0;
+
+        while offset < self.used_bytes {
+            let (tag, value, length) = self.item(offset);
+
+            match tag {
+                REPORT_ID => current_id = value,
+                REPORT_SIZE => report_size = value as usize,
+                REPORT_COUNT => report_count = value as usize,
+                _ if tag == main_item && current_id == report_id as u32 => bits += report_size * report_count,
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        bits
+    }
+
+    /// Size in bytes of a report as it is transmitted over BLE, meaning without
+    /// the report id.
+    pub const fn report_size(&self, report_id: u8, report_type: ReportType) -> usize {
+        self.report_bits(report_id, report_type) / 8
+    }
+
+    pub const fn length(&self) -> usize {
+        self.used_bytes
+    }
+
+    pub const fn to_array<const N: usize>(&self) -> [u8; N] {
+        if N != self.used_bytes {
+            panic!("Array size does not match the length of the report descriptor");
+        }
+
+        let mut array = [0; N];
+        let mut offset = 0;
+
+        while offset < N {
+            array[offset] = self.data[offset];
+            offset += 1;
+        }
+
+        array
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        CONSUMER_REPORT_ID, KEYBOARD_REPORT_ID, MAP_DATA, MOUSE_REPORT_ID, RAW_HID_REPORT_ID, RAW_HID_REPORT_SIZE, REPORT_DESCRIPTOR,
+    };
+    use super::*;
+
+    // Independent parser for the generated bytes, so the tests do not rely on the
+    // parser of the builder. Returns the report ids in the order they are declared
+    // and the number of bits of every report.
+    fn parse(descriptor: &[u8]) -> (Vec<u8>, Vec<(u8, ReportType, usize)>) {
+        let mut report_ids = Vec::new();
+        let mut reports: Vec<(u8, ReportType, usize)> = Vec::new();
+        let mut report_id = 0;
+        let mut report_size = 0;
+        let mut report_count = 0;
+        let mut offset = 0;
+
+        while offset < descriptor.len() {
+            let prefix = descriptor[offset];
+            assert_ne!(prefix, LONG_ITEM, "Long items are never generated");
+
+            let size = [0, 1, 2, 4][(prefix & 0b11) as usize];
+            let value = descriptor[offset + 1..offset + 1 + size]
+                .iter()
+                .rev()
+                .fold(0u32, |value, byte| (value << 8) | *byte as u32);
+
+            let report_type = match prefix & !0b11 {
+                REPORT_ID => {
+                    report_id = value as u8;
+                    report_ids.push(report_id);
+                    None
+                }
+                REPORT_SIZE => {
+                    report_size = value as usize;
+                    None
+                }
+                REPORT_COUNT => {
+                    report_count = value as usize;
+                    None
+                }
+                INPUT => Some(ReportType::Input),
+                OUTPUT => Some(ReportType::Output),
+                FEATURE => Some(ReportType::Feature),
+                _ => None,
+            };
+
+            if let Some(report_type) = report_type {
+                match reports.iter_mut().find(|(id, kind, _)| *id == report_id && *kind == report_type) {
+                    Some((_, _, bits)) => *bits += report_size * report_count,
+                    None => reports.push((report_id, report_type, report_size * report_count)),
+                }
+            }
+
+            offset += 1 + size;
+        }
+
+        assert_eq!(offset, descriptor.len(), "Descriptor ends in the middle of an item");
+
+        (report_ids, reports)
+    }
+
+    #[test]
+    fn report_map_matches_descriptor() {
+        assert_eq!(MAP_DATA.len(), REPORT_DESCRIPTOR.length());
+        assert_eq!(&MAP_DATA[..], &REPORT_DESCRIPTOR.data[..REPORT_DESCRIPTOR.length()]);
+    }
+
+    #[test]
+    fn report_ids() {
+        let (report_ids, _) = parse(&MAP_DATA);
+
+        assert_eq!(report_ids, [
+            KEYBOARD_REPORT_ID,
+            CONSUMER_REPORT_ID,
+            MOUSE_REPORT_ID,
+            RAW_HID_REPORT_ID
+        ]);
+    }
+
+    #[test]
+    fn report_sizes() {
+        let (_, reports) = parse(&MAP_DATA);
+
+        for (report_id, report_type, bits) in &reports {
+            assert_eq!(bits % 8, 0, "Report {} ({:?}) is not byte aligned", report_id, report_type);
+            assert_eq!(REPORT_DESCRIPTOR.report_size(*report_id, *report_type), bits / 8);
+        }
+
+        let size_of = |report_id, report_type| {
+            reports
+                .iter()
+                .find(|(id, kind, _)| *id == report_id && *kind == report_type)
+                .map_or(0, |(_, _, bits)| bits / 8)
+        };
+
+        // Modifiers, reserved byte and six key slots, like the boot protocol.
+        assert_eq!(size_of(KEYBOARD_REPORT_ID, ReportType::Input), 8);
+        assert_eq!(size_of(KEYBOARD_REPORT_ID, ReportType::Output), 1);
+        assert_eq!(size_of(CONSUMER_REPORT_ID, ReportType::Input), 2);
+        assert_eq!(size_of(MOUSE_REPORT_ID, ReportType::Input), 5);
+        assert_eq!(size_of(RAW_HID_REPORT_ID, ReportType::Input), RAW_HID_REPORT_SIZE);
+        assert_eq!(size_of(RAW_HID_REPORT_ID, ReportType::Output), RAW_HID_REPORT_SIZE);
+
+        // No report declares feature items.
+        assert!(reports.iter().all(|(_, kind, _)| *kind != ReportType::Feature));
+    }
+
+    #[test]
+    fn multi_byte_values_are_little_endian() {
+        let descriptor = ReportDescriptor::new().usage_range(0x000, 0x3FF);
+
+        assert_eq!(&descriptor.data[..descriptor.length()], &[
+            USAGE_MINIMUM | 1,
+            0x00,
+            USAGE_MAXIMUM | 2,
+            0xFF,
+            0x03
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "same report id twice")]
+    fn duplicate_report_id() {
+        ReportDescriptor::new().add_consumer_report(1).add_consumer_report(1).validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "not byte aligned")]
+    fn unaligned_report() {
+        ReportDescriptor::new()
+            .usage_page(0x01)
+            .collection(COLLECTION_APPLICATION)
+            .report_id(1)
+            .report_layout(1, 3)
+            .main_item(INPUT, DATA_VARIABLE_ABSOLUTE)
+            .end_collection()
+            .validate();
+    }
+}
@@ -0,0 +1,41 @@
+// Size of an identity when it is sent to the other half. The first six bytes
+// are the static address and the rest is the identity resolution key.
+pub const IDENTITY_SIZE: usize = 22;
+
+/// Check if the bytes hold an identity that was received from the other half.
+/// Erased flash reads as all ones and cleared flash as all zeros, so only a
+/// static address with a key that is neither counts as a stored identity.
+pub fn is_known_identity(bytes: &[u8; IDENTITY_SIZE]) -> bool {
+    let (address, key) = bytes.split_at(6);
+
+    // https://www.bluetooth.com/specifications/specs/core-specification/
+    // Vol 6, Part B, Section 1.3.2.1 Static device address
+    let is_static_address = address[5] & 0b1100_0000 == 0b1100_0000;
+    let valid_key = key.iter().any(|byte| *byte != 0x00) && key.iter().any(|byte| *byte != 0xFF);
+
+    is_static_address && valid_key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_identity() {
+        let mut bytes = [0x42; IDENTITY_SIZE];
+        bytes[5] = 0xC2;
+        assert!(is_known_identity(&bytes));
+
+        assert!(!is_known_identity(&[0xFF; IDENTITY_SIZE]));
+        assert!(!is_known_identity(&[0x00; IDENTITY_SIZE]));
+
+        // The top bits mark a static address.
+        bytes[5] = 0x42;
+        assert!(!is_known_identity(&bytes));
+
+        // Leftover bytes of erased flash in the key.
+        bytes[5] = 0xC2;
+        bytes[6..].fill(0xFF);
+        assert!(!is_known_identity(&bytes));
+    }
+}
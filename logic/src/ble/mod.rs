@@ -0,0 +1,43 @@
+mod advertising;
+mod descriptor;
+mod identity;
+
+pub use self::advertising::{advertises_service, AdvertisingData, KEYBOARD_ICON};
+pub use self::descriptor::{ReportDescriptor, ReportType};
+pub use self::identity::{is_known_identity, IDENTITY_SIZE};
+
+// Size of the vendor defined input and output reports.
+pub const RAW_HID_REPORT_SIZE: usize = 32;
+
+pub const KEYBOARD_REPORT_ID: u8 = 1;
+pub const CONSUMER_REPORT_ID: u8 = 2;
+pub const MOUSE_REPORT_ID: u8 = 3;
+pub const RAW_HID_REPORT_ID: u8 = 4;
+
+pub const REPORT_DESCRIPTOR: ReportDescriptor = ReportDescriptor::new()
+    .add_keyboard_report(KEYBOARD_REPORT_ID)
+    .add_consumer_report(CONSUMER_REPORT_ID)
+    .add_mouse_report(MOUSE_REPORT_ID)
+    .add_vendor_report(RAW_HID_REPORT_ID, RAW_HID_REPORT_SIZE as u8, RAW_HID_REPORT_SIZE as u8)
+    .validate();
+
+pub const KEYBOARD_INPUT_REPORT_SIZE: usize = REPORT_DESCRIPTOR.report_size(KEYBOARD_REPORT_ID, ReportType::Input);
+pub const KEYBOARD_OUTPUT_REPORT_SIZE: usize = REPORT_DESCRIPTOR.report_size(KEYBOARD_REPORT_ID, ReportType::Output);
+pub const CONSUMER_INPUT_REPORT_SIZE: usize = REPORT_DESCRIPTOR.report_size(CONSUMER_REPORT_ID, ReportType::Input);
+pub const MOUSE_INPUT_REPORT_SIZE: usize = REPORT_DESCRIPTOR.report_size(MOUSE_REPORT_ID, ReportType::Input);
+
+const _: () = assert!(
+    REPORT_DESCRIPTOR.report_size(RAW_HID_REPORT_ID, ReportType::Input) == RAW_HID_REPORT_SIZE
+        && REPORT_DESCRIPTOR.report_size(RAW_HID_REPORT_ID, ReportType::Output) == RAW_HID_REPORT_SIZE,
+    "Raw HID reports do not match the report descriptor"
+);
+
+// The boot protocol uses the same report layout as our keyboard report, so
+// make sure that they never diverge.
+pub const BOOT_INPUT_REPORT_SIZE: usize = 8;
+const _: () = assert!(
+    REPORT_DESCRIPTOR.report_size(KEYBOARD_REPORT_ID, ReportType::Input) == BOOT_INPUT_REPORT_SIZE,
+    "Keyboard input report does not match the boot keyboard input report"
+);
+
+pub const MAP_DATA: [u8; REPORT_DESCRIPTOR.length()] = REPORT_DESCRIPTOR.to_array();
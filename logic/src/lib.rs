@@ -0,0 +1,8 @@
+// Tests run on the host with the standard library, while the firmware uses the
+// crate without it.
+#![cfg_attr(not(test), no_std)]
+#![feature(never_type)]
+#![feature(array_try_from_fn)]
+
+pub mod ble;
+pub mod split;
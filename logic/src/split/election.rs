@@ -0,0 +1,121 @@
+use super::Wire;
+
+// Battery levels that differ by less than this many percent are considered
+// equal, so measurement noise does not decide the election.
+pub const BATTERY_LEVEL_MARGIN: u8 = 10;
+
+/// What a half brings to the election of the master.
+#[derive(Clone, Copy, Debug, Wire)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[wire(validate = "Candidate::is_valid")]
+pub struct Candidate {
+    /// Battery level in percent, if it was measured already.
+    pub battery_level: Option<u8>,
+    /// Number of times the half was elected master.
+    pub master_elections: u32,
+    pub random_number: u32,
+}
+
+impl Candidate {
+    fn is_valid(&self) -> bool {
+        self.battery_level.map_or(true, |battery_level| battery_level <= 100)
+    }
+
+    /// Whether this half should become the master rather than the other one.
+    /// The half with noticeably more charge wins. Otherwise the halves take
+    /// turns, so the half that was master fewer times wins. Only if that is
+    /// equal as well, the random numbers decide. If even those are equal,
+    /// this half wins, since only one half runs the election.
+    pub fn wins_against(&self, other: &Self) -> bool {
+        if let (Some(battery_level), Some(other_battery_level)) = (self.battery_level, other.battery_level) {
+            if battery_level.abs_diff(other_battery_level) >= BATTERY_LEVEL_MARGIN {
+                return battery_level > other_battery_level;
+            }
+        }
+
+        // Compare the difference, so the counters can wrap around.
+        let elections_difference = self.master_elections.wrapping_sub(other.master_elections) as i32;
+        if elections_difference != 0 {
+            return elections_difference < 0;
+        }
+
+        self.random_number >= other.random_number
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::split::{decode_message, encode_message, WireError, WireMessage, WIRE_VERSION};
+
+    fn candidate(battery_level: Option<u8>, master_elections: u32, random_number: u32) -> Candidate {
+        Candidate {
+            battery_level,
+            master_elections,
+            random_number,
+        }
+    }
+
+    // Exactly one half needs to win, no matter which half runs the election.
+    fn assert_winner(winner: &Candidate, loser: &Candidate) {
+        assert!(winner.wins_against(loser));
+        assert!(!loser.wins_against(winner));
+    }
+
+    #[test]
+    fn battery_margin() {
+        // More charge wins, even if that half was master more often.
+        assert_winner(&candidate(Some(80), 5, 0), &candidate(Some(80 - BATTERY_LEVEL_MARGIN), 0, 1));
+        // Smaller differences are noise, so the halves take turns.
+        assert_winner(&candidate(Some(80), 0, 0), &candidate(Some(81 - BATTERY_LEVEL_MARGIN), 1, 1));
+        // Without both levels, the battery does not decide.
+        assert_winner(&candidate(None, 0, 0), &candidate(Some(100), 1, 1));
+        assert_winner(&candidate(Some(0), 0, 0), &candidate(None, 1, 1));
+    }
+
+    #[test]
+    fn turn_taking() {
+        assert_winner(&candidate(Some(50), 3, 0), &candidate(Some(50), 4, 1));
+
+        // Once the winner records the election, the other half gets its turn.
+        let mut left = candidate(Some(50), 7, 0);
+        let mut right = candidate(Some(50), 7, 1);
+
+        for _ in 0..4 {
+            let (winner, loser) = match left.wins_against(&right) {
+                true => (&mut left, &right),
+                false => (&mut right, &left),
+            };
+
+            assert!(!loser.wins_against(winner));
+            winner.master_elections = winner.master_elections.wrapping_add(1);
+            assert!(loser.wins_against(winner));
+        }
+    }
+
+    #[test]
+    fn counter_wraparound() {
+        // A counter that wrapped around to 0 was elected once more than u32::MAX.
+        assert_winner(&candidate(None, u32::MAX, 0), &candidate(None, 0, 1));
+        assert_winner(&candidate(None, u32::MAX - 1, 0), &candidate(None, 1, 1));
+    }
+
+    #[test]
+    fn tie_break() {
+        assert_winner(&candidate(Some(50), 2, 1000), &candidate(Some(50), 2, 999));
+        assert_winner(&candidate(Some(50), 2, u32::MAX), &candidate(Some(55), 2, 0));
+
+        // If everything is equal, the half that runs the election wins.
+        let equal = candidate(Some(50), 2, 1000);
+        assert!(equal.wins_against(&equal));
+    }
+
+    #[test]
+    fn invalid_battery_level() {
+        let message: WireMessage<16> = encode_message(&candidate(Some(100), 1, 2)).unwrap();
+        assert_eq!(message, [WIRE_VERSION, 1, 100, 1, 0, 0, 0, 2, 0, 0, 0]);
+
+        let message: WireMessage<16> = encode_message(&candidate(Some(101), 1, 2)).unwrap();
+        assert_eq!(decode_message::<Candidate>(&message).err(), Some(WireError::InvalidValue));
+    }
+}
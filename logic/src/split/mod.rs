@@ -0,0 +1,7 @@
+mod election;
+mod wire;
+
+pub use procedural::Wire;
+
+pub use self::election::{Candidate, BATTERY_LEVEL_MARGIN};
+pub use self::wire::{decode_message, encode_message, message_size, Wire, WireError, WireMessage, WireReader, WireWriter, WIRE_VERSION};
@@ -0,0 +1,558 @@
+use heapless::Vec;
+#[cfg(feature = "softdevice")]
+use nrf_softdevice::ble::{Address, EncryptionInfo, IdentityKey, IdentityResolutionKey, MasterId};
+#[cfg(feature = "softdevice")]
+use nrf_softdevice::raw;
+
+/// Version of the wire format. Every message starts with it, so halves
+/// running firmware with a different layout reject messages instead of
+/// misinterpreting them. Increase it whenever the layout of any split message
+/// changes.
+pub const WIRE_VERSION: u8 = 5;
+
+/// Encoded message that is sent to the other half.
+pub type WireMessage<const N: usize> = Vec<u8, N>;
+
+/// Number of bytes needed to send a value of the given type, including the
+/// version.
+pub const fn message_size<T: Wire>() -> usize {
+    T::SIZE + 1
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WireError {
+    /// The message ended before the value was decoded.
+    UnexpectedEnd,
+    /// The value does not fit into the buffer.
+    BufferFull,
+    /// The message was encoded with a different version of the wire format.
+    VersionMismatch { expected: u8, found: u8 },
+    /// The tag does not belong to any variant of the enum.
+    UnknownVariant { tag: u8 },
+    /// The bytes do not form a valid value.
+    InvalidValue,
+    /// The message has bytes left over after the value was decoded.
+    TrailingBytes,
+}
+
+pub struct WireWriter<'a> {
+    buffer: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> WireWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, position: 0 }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), WireError> {
+        let end = self.position + bytes.len();
+        let target = self.buffer.get_mut(self.position..end).ok_or(WireError::BufferFull)?;
+
+        target.copy_from_slice(bytes);
+        self.position = end;
+        Ok(())
+    }
+
+    /// Number of bytes written so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+pub struct WireReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> WireReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn read<const N: usize>(&mut self) -> Result<[u8; N], WireError> {
+        if self.data.len() < N {
+            return Err(WireError::UnexpectedEnd);
+        }
+
+        let (bytes, remaining) = self.data.split_at(N);
+        self.data = remaining;
+
+        let mut buffer = [0; N];
+        buffer.copy_from_slice(bytes);
+        Ok(buffer)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// Value that can be sent to the other half. The layout is explicit and does
+/// not depend on the memory layout of the type: integers are little endian,
+/// fields are encoded in the order they are declared in and enums start with
+/// a single byte tag, which is the index of the variant. Decoding checks every
+/// value, so malformed messages are rejected instead of causing undefined
+/// behavior.
+///
+/// Structs and enums usually implement this trait with
+/// `#[derive(procedural::Wire)]`. Adding `#[wire(validate = "function")]`
+/// to the type rejects decoded values for which `function(&value)` returns
+/// `false`.
+pub trait Wire: Sized {
+    /// Maximum number of bytes that a value takes on the wire.
+    const SIZE: usize;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError>;
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError>;
+}
+
+/// Encode a value into a message for the other half.
+pub fn encode_message<T: Wire, const N: usize>(value: &T) -> Result<WireMessage<N>, WireError> {
+    let mut buffer = [0; N];
+    let mut writer = WireWriter::new(&mut buffer);
+
+    WIRE_VERSION.encode(&mut writer)?;
+    value.encode(&mut writer)?;
+
+    let length = writer.position();
+    Vec::from_slice(&buffer[..length]).map_err(|_| WireError::BufferFull)
+}
+
+/// Decode a message from the other half.
+pub fn decode_message<T: Wire>(data: &[u8]) -> Result<T, WireError> {
+    let mut reader = WireReader::new(data);
+
+    let version = u8::decode(&mut reader)?;
+    if version != WIRE_VERSION {
+        return Err(WireError::VersionMismatch {
+            expected: WIRE_VERSION,
+            found: version,
+        });
+    }
+
+    let value = T::decode(&mut reader)?;
+
+    match reader.is_empty() {
+        true => Ok(value),
+        false => Err(WireError::TrailingBytes),
+    }
+}
+
+macro_rules! implement_integer {
+    ($($integer:ty),*) => {
+        $(
+            impl Wire for $integer {
+                const SIZE: usize = core::mem::size_of::<$integer>();
+
+                fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+                    writer.write(&self.to_le_bytes())
+                }
+
+                fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+                    reader.read().map(<$integer>::from_le_bytes)
+                }
+            }
+        )*
+    };
+}
+
+implement_integer!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+// The size of usize depends on the platform, so it is always sent as 32 bits.
+impl Wire for usize {
+    const SIZE: usize = u32::SIZE;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        u32::try_from(*self).map_err(|_| WireError::InvalidValue)?.encode(writer)
+    }
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        usize::try_from(u32::decode(reader)?).map_err(|_| WireError::InvalidValue)
+    }
+}
+
+impl Wire for bool {
+    const SIZE: usize = 1;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        (*self as u8).encode(writer)
+    }
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        match u8::decode(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(WireError::InvalidValue),
+        }
+    }
+}
+
+// Only finite numbers are accepted, since nothing on the keyboard expects NaN
+// or infinity.
+impl Wire for f32 {
+    const SIZE: usize = 4;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        self.to_bits().encode(writer)
+    }
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        let value = f32::from_bits(u32::decode(reader)?);
+
+        match value.is_finite() {
+            true => Ok(value),
+            false => Err(WireError::InvalidValue),
+        }
+    }
+}
+
+impl<T: Wire, const N: usize> Wire for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        self.iter().try_for_each(|element| element.encode(writer))
+    }
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        core::array::try_from_fn(|_| T::decode(reader))
+    }
+}
+
+impl<T: Wire> Wire for Option<T> {
+    const SIZE: usize = 1 + T::SIZE;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        match self {
+            Some(value) => {
+                true.encode(writer)?;
+                value.encode(writer)
+            }
+            None => false.encode(writer),
+        }
+    }
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        match bool::decode(reader)? {
+            true => T::decode(reader).map(Some),
+            false => Ok(None),
+        }
+    }
+}
+
+impl Wire for () {
+    const SIZE: usize = 0;
+
+    fn encode(&self, _writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        Ok(())
+    }
+
+    fn decode(_reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        Ok(())
+    }
+}
+
+// Used for types that a keyboard does not define, like events of keyboards
+// without events. No value can be sent, so every message is rejected.
+impl Wire for ! {
+    const SIZE: usize = 0;
+
+    fn encode(&self, _writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        *self
+    }
+
+    fn decode(_reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        Err(WireError::InvalidValue)
+    }
+}
+
+// Types of the SoftDevice that are part of bonds, which the halves share.
+#[cfg(feature = "softdevice")]
+impl Wire for Address {
+    const SIZE: usize = u8::SIZE + <[u8; 6]>::SIZE;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        self.flags.encode(writer)?;
+        self.bytes.encode(writer)
+    }
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            flags: u8::decode(reader)?,
+            bytes: <[u8; 6]>::decode(reader)?,
+        })
+    }
+}
+
+#[cfg(feature = "softdevice")]
+impl Wire for MasterId {
+    const SIZE: usize = u16::SIZE + <[u8; 8]>::SIZE;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        self.ediv.encode(writer)?;
+        self.rand.encode(writer)
+    }
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            ediv: u16::decode(reader)?,
+            rand: <[u8; 8]>::decode(reader)?,
+        })
+    }
+}
+
+#[cfg(feature = "softdevice")]
+impl Wire for EncryptionInfo {
+    const SIZE: usize = <[u8; 16]>::SIZE + u8::SIZE;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        self.ltk.encode(writer)?;
+        self.flags.encode(writer)
+    }
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            ltk: <[u8; 16]>::decode(reader)?,
+            flags: u8::decode(reader)?,
+        })
+    }
+}
+
+#[cfg(feature = "softdevice")]
+impl Wire for IdentityKey {
+    const SIZE: usize = <[u8; 16]>::SIZE + Address::SIZE;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), WireError> {
+        self.irk.as_raw().irk.encode(writer)?;
+        self.addr.encode(writer)
+    }
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, WireError> {
+        let irk = raw::ble_gap_irk_t {
+            irk: <[u8; 16]>::decode(reader)?,
+        };
+
+        Ok(Self {
+            irk: IdentityResolutionKey::from_raw(irk),
+            addr: Address::decode(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::split::Wire;
+
+    const BUFFER_SIZE: usize = 1024;
+    const MAXIMUM_SLOTS: u8 = 4;
+
+    // Types shaped like the split messages, so the derived implementations are
+    // covered without depending on the firmware.
+    #[derive(Clone, Copy, Debug, PartialEq, Wire)]
+    #[wire(validate = "Slot::is_valid")]
+    struct Slot(u8);
+
+    impl Slot {
+        fn is_valid(&self) -> bool {
+            self.0 < MAXIMUM_SLOTS
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Wire)]
+    struct Entry {
+        slot: Slot,
+        key: [u8; 16],
+        last_used: Option<u64>,
+        brightness: f32,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Wire)]
+    enum Operation {
+        Store { slot: Slot, entry: Entry },
+        Remove(Slot),
+        Counter(u32),
+        Clear,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Wire)]
+    enum Frame {
+        Message { sequence: u8, message: Operation },
+        Acknowledge { sequence: u8 },
+        Priority { message: Operation },
+    }
+
+    // Encode and decode the value and check that the decoded value encodes to the
+    // same bytes.
+    fn round_trip<T: Wire>(value: &T) -> T {
+        let message: WireMessage<BUFFER_SIZE> = encode_message(value).unwrap();
+        assert!(message.len() <= message_size::<T>());
+
+        let decoded = decode_message::<T>(&message).unwrap();
+        let encoded_again: WireMessage<BUFFER_SIZE> = encode_message(&decoded).unwrap();
+        assert_eq!(message, encoded_again);
+
+        decoded
+    }
+
+    fn entry() -> Entry {
+        Entry {
+            slot: Slot(2),
+            key: [3; 16],
+            last_used: Some(123_456),
+            brightness: 0.5,
+        }
+    }
+
+    #[test]
+    fn integers_are_little_endian() {
+        let message: WireMessage<BUFFER_SIZE> = encode_message(&0x12345678u32).unwrap();
+
+        assert_eq!(message, [WIRE_VERSION, 0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn derived_layout() {
+        let message: WireMessage<BUFFER_SIZE> = encode_message(&Frame::Message {
+            sequence: 200,
+            message: Operation::Remove(Slot(1)),
+        })
+        .unwrap();
+
+        // Version, variant of the frame, sequence, variant of the operation and slot.
+        assert_eq!(message, [WIRE_VERSION, 0, 200, 1, 1]);
+        assert_eq!(message_size::<Slot>(), 2);
+        assert_eq!(Entry::SIZE, 1 + 16 + 9 + 4);
+        assert_eq!(Operation::SIZE, 1 + 1 + Entry::SIZE);
+    }
+
+    #[test]
+    fn round_trips() {
+        let frames = [
+            Frame::Message {
+                sequence: 200,
+                message: Operation::Store {
+                    slot: Slot(3),
+                    entry: entry(),
+                },
+            },
+            Frame::Message {
+                sequence: 0,
+                message: Operation::Counter(u32::MAX),
+            },
+            Frame::Acknowledge { sequence: 3 },
+            Frame::Priority { message: Operation::Clear },
+        ];
+
+        for frame in frames {
+            assert_eq!(round_trip(&frame), frame);
+        }
+
+        assert_eq!(round_trip(&Some(-5i16)), Some(-5));
+        assert_eq!(round_trip(&None::<u64>), None);
+        assert_eq!(round_trip(&(u32::MAX as usize)), u32::MAX as usize);
+    }
+
+    #[test]
+    fn unknown_tag() {
+        // Frames only have three variants.
+        assert_eq!(
+            decode_message::<Frame>(&[WIRE_VERSION, 3, 0]).err(),
+            Some(WireError::UnknownVariant { tag: 3 })
+        );
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut message: WireMessage<BUFFER_SIZE> = encode_message(&Frame::Acknowledge { sequence: 1 }).unwrap();
+        message[0] = WIRE_VERSION.wrapping_add(1);
+
+        assert_eq!(
+            decode_message::<Frame>(&message).err(),
+            Some(WireError::VersionMismatch {
+                expected: WIRE_VERSION,
+                found: WIRE_VERSION.wrapping_add(1),
+            })
+        );
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut message: WireMessage<BUFFER_SIZE> = encode_message(&Frame::Acknowledge { sequence: 1 }).unwrap();
+        message.push(0).unwrap();
+
+        assert_eq!(decode_message::<Frame>(&message).err(), Some(WireError::TrailingBytes));
+    }
+
+    #[test]
+    fn unexpected_end() {
+        let message: WireMessage<BUFFER_SIZE> = encode_message(&Operation::Counter(5)).unwrap();
+
+        assert_eq!(
+            decode_message::<Operation>(&message[..message.len() - 1]).err(),
+            Some(WireError::UnexpectedEnd)
+        );
+        assert_eq!(decode_message::<u8>(&[]).err(), Some(WireError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn buffer_full() {
+        assert_eq!(encode_message::<u32, 4>(&0).err(), Some(WireError::BufferFull));
+    }
+
+    #[test]
+    fn usize_out_of_range() {
+        let mut buffer = [0; 8];
+        let mut writer = WireWriter::new(&mut buffer);
+
+        if usize::BITS > u32::BITS {
+            assert_eq!(usize::MAX.encode(&mut writer).err(), Some(WireError::InvalidValue));
+        }
+    }
+
+    #[test]
+    fn non_finite_float() {
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let message: WireMessage<BUFFER_SIZE> = encode_message(&value).unwrap();
+            assert_eq!(decode_message::<f32>(&message).err(), Some(WireError::InvalidValue));
+        }
+
+        assert_eq!(round_trip(&-0.25f32), -0.25);
+    }
+
+    #[test]
+    fn invalid_bool() {
+        assert_eq!(decode_message::<bool>(&[WIRE_VERSION, 2]).err(), Some(WireError::InvalidValue));
+        assert_eq!(
+            decode_message::<Option<u8>>(&[WIRE_VERSION, 2, 0]).err(),
+            Some(WireError::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let message: WireMessage<BUFFER_SIZE> = encode_message(&Slot(MAXIMUM_SLOTS)).unwrap();
+        assert_eq!(decode_message::<Slot>(&message).err(), Some(WireError::InvalidValue));
+
+        // Nested values are validated as well.
+        let message: WireMessage<BUFFER_SIZE> = encode_message(&Operation::Remove(Slot(255))).unwrap();
+        assert_eq!(decode_message::<Operation>(&message).err(), Some(WireError::InvalidValue));
+
+        let mut invalid_entry = entry();
+        invalid_entry.slot = Slot(MAXIMUM_SLOTS);
+        let message: WireMessage<BUFFER_SIZE> = encode_message(&Frame::Priority {
+            message: Operation::Store {
+                slot: Slot(0),
+                entry: invalid_entry,
+            },
+        })
+        .unwrap();
+        assert_eq!(decode_message::<Frame>(&message).err(), Some(WireError::InvalidValue));
+    }
+
+    #[test]
+    fn never_decodes() {
+        assert_eq!(decode_message::<!>(&[WIRE_VERSION]).err(), Some(WireError::InvalidValue));
+    }
+}
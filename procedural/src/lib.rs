@@ -4,6 +4,7 @@ use std::ffi::{OsStr, OsString};
 use std::path::Path;
 use std::{fs, io};
 
+#[cfg(any(feature = "left", feature = "right"))]
 use colored::Colorize;
 use convert_case::{Case, Casing};
 use derive_syn_parse::Parse;
@@ -126,3 +127,228 @@ pub fn import_keyboards(input: TokenStream) -> TokenStream {
     }
     .into()
 }
+
+// Path of the wire module in the firmware crate.
+fn wire_path() -> proc_macro2::TokenStream {
+    quote! { crate::split }
+}
+
+// Only cfg attributes are forwarded, so fields and variants that are compiled
+// out are also left out of the generated code.
+fn cfg_attributes(attributes: &[syn::Attribute]) -> Vec<&syn::Attribute> {
+    attributes.iter().filter(|attribute| attribute.path().is_ident("cfg")).collect()
+}
+
+fn validate_function(attributes: &[syn::Attribute]) -> syn::Result<Option<syn::Path>> {
+    let mut function = None;
+
+    for attribute in attributes.iter().filter(|attribute| attribute.path().is_ident("wire")) {
+        attribute.parse_nested_meta(|meta| match meta.path.is_ident("validate") {
+            true => {
+                let value: syn::LitStr = meta.value()?.parse()?;
+                function = Some(value.parse()?);
+                Ok(())
+            }
+            false => Err(meta.error("unknown wire attribute, expected `validate`")),
+        })?;
+    }
+
+    Ok(function)
+}
+
+// Size of the given fields as a constant expression.
+fn fields_size(fields: &syn::Fields) -> proc_macro2::TokenStream {
+    let wire = wire_path();
+    let sizes = fields.iter().map(|field| {
+        let cfgs = cfg_attributes(&field.attrs);
+        let field_type = &field.ty;
+        quote! { #(#cfgs)* let size = size + <#field_type as #wire::Wire>::SIZE; }
+    });
+
+    quote! {{
+        let size = 0;
+        #(#sizes)*
+        size
+    }}
+}
+
+// Names that the fields are bound to when encoding.
+fn field_bindings(fields: &syn::Fields) -> Vec<syn::Ident> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| match &field.ident {
+            Some(ident) => ident.clone(),
+            None => quote::format_ident!("field_{}", index),
+        })
+        .collect()
+}
+
+// Pattern that binds every field to the names returned by `field_bindings`.
+fn fields_pattern(fields: &syn::Fields) -> proc_macro2::TokenStream {
+    let bindings = field_bindings(fields);
+    let cfgs = fields.iter().map(|field| cfg_attributes(&field.attrs));
+
+    match fields {
+        syn::Fields::Named(..) => quote! { { #( #(#cfgs)* #bindings, )* } },
+        syn::Fields::Unnamed(..) => quote! { ( #(#bindings),* ) },
+        syn::Fields::Unit => quote! {},
+    }
+}
+
+fn encode_fields(fields: &syn::Fields) -> proc_macro2::TokenStream {
+    let wire = wire_path();
+    let bindings = field_bindings(fields);
+    let cfgs = fields.iter().map(|field| cfg_attributes(&field.attrs));
+
+    quote! { #( #(#cfgs)* #wire::Wire::encode(#bindings, writer)?; )* }
+}
+
+// Fields are decoded in the order they are declared in, which is also the order
+// in which they are evaluated.
+fn decode_fields(fields: &syn::Fields) -> proc_macro2::TokenStream {
+    let wire = wire_path();
+
+    match fields {
+        syn::Fields::Named(named) => {
+            let fields = named.named.iter().map(|field| {
+                let cfgs = cfg_attributes(&field.attrs);
+                let ident = &field.ident;
+                quote! { #(#cfgs)* #ident: #wire::Wire::decode(reader)? }
+            });
+            quote! { { #(#fields),* } }
+        }
+        syn::Fields::Unnamed(unnamed) => {
+            let fields = unnamed.unnamed.iter().map(|_| quote! { #wire::Wire::decode(reader)? });
+            quote! { ( #(#fields),* ) }
+        }
+        syn::Fields::Unit => quote! {},
+    }
+}
+
+fn derive_wire_inner(input: syn::DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let wire = wire_path();
+    let validate = validate_function(&input.attrs)?;
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    let (size, encode, decode) = match &input.data {
+        syn::Data::Struct(data) => {
+            let size = fields_size(&data.fields);
+            let pattern = fields_pattern(&data.fields);
+            let encode_fields = encode_fields(&data.fields);
+            let decode_fields = decode_fields(&data.fields);
+
+            let encode = quote! {
+                let Self #pattern = self;
+                #encode_fields
+                Ok(())
+            };
+            let decode = quote! { Self #decode_fields };
+
+            (size, encode, decode)
+        }
+        syn::Data::Enum(data) => {
+            if data.variants.len() > u8::MAX as usize + 1 {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "enums sent to the other half can have at most 256 variants",
+                ));
+            }
+
+            let mut sizes = Vec::new();
+            let mut encode_arms = Vec::new();
+            let mut decode_arms = Vec::new();
+
+            for (tag, variant) in data.variants.iter().enumerate() {
+                let tag = tag as u8;
+                let cfgs = cfg_attributes(&variant.attrs);
+                let ident = &variant.ident;
+                let size = fields_size(&variant.fields);
+                let pattern = fields_pattern(&variant.fields);
+                let encode_fields = encode_fields(&variant.fields);
+                let decode_fields = decode_fields(&variant.fields);
+
+                sizes.push(quote! {
+                    #(#cfgs)*
+                    let size = {
+                        let variant_size = #size;
+                        match variant_size > size {
+                            true => variant_size,
+                            false => size,
+                        }
+                    };
+                });
+                encode_arms.push(quote! {
+                    #(#cfgs)*
+                    Self::#ident #pattern => {
+                        #wire::Wire::encode(&#tag, writer)?;
+                        #encode_fields
+                    }
+                });
+                decode_arms.push(quote! {
+                    #(#cfgs)*
+                    #tag => Self::#ident #decode_fields,
+                });
+            }
+
+            let size = quote! {
+                1 + {
+                    let size = 0;
+                    #(#sizes)*
+                    size
+                }
+            };
+            let encode = quote! {
+                match self {
+                    #(#encode_arms)*
+                }
+                Ok(())
+            };
+            let decode = quote! {
+                match <u8 as #wire::Wire>::decode(reader)? {
+                    #(#decode_arms)*
+                    tag => return Err(#wire::WireError::UnknownVariant { tag }),
+                }
+            };
+
+            (size, encode, decode)
+        }
+        syn::Data::Union(..) => return Err(syn::Error::new_spanned(name, "unions can not be sent to the other half")),
+    };
+
+    let validate = validate.map(|function| {
+        quote! {
+            if !#function(&value) {
+                return Err(#wire::WireError::InvalidValue);
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics #wire::Wire for #name #type_generics #where_clause {
+            const SIZE: usize = #size;
+
+            #[allow(unused_variables)]
+            fn encode(&self, writer: &mut #wire::WireWriter<'_>) -> Result<(), #wire::WireError> {
+                #encode
+            }
+
+            #[allow(unused_variables)]
+            fn decode(reader: &mut #wire::WireReader<'_>) -> Result<Self, #wire::WireError> {
+                let value = #decode;
+                #validate
+                Ok(value)
+            }
+        }
+    })
+}
+
+/// Implement `Wire` for a struct or enum, so it can be sent to the other half.
+/// See the `Wire` trait for the layout.
+#[proc_macro_derive(Wire, attributes(wire))]
+pub fn derive_wire(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+
+    derive_wire_inner(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
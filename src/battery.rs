@@ -1,28 +1,63 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
 use embassy_nrf::saadc::{ChannelConfig, Config, Oversample, Saadc, VddhDiv5Input};
 use embassy_nrf::{bind_interrupts, saadc, Peripherals};
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::{Channel, Receiver};
+use embassy_sync::channel::{Channel, Receiver, Sender};
 use embassy_time::Timer;
 
 use crate::interface::Keyboard;
+use crate::split::Wire;
 
 bind_interrupts!(struct Irqs {
     SAADC => saadc::InterruptHandler;
 });
 
+#[derive(Clone, Copy, Debug, defmt::Format, Wire)]
+#[wire(validate = "BatteryLevel::is_valid")]
 pub struct BatteryLevel(pub u8);
+
+impl BatteryLevel {
+    fn is_valid(&self) -> bool {
+        self.0 <= 100
+    }
+}
+
 pub struct Voltage(pub f32);
 
 const BATTERY_CHANNEL_SIZE: usize = 2;
 
 static BATTERY_CHANNEL: Channel<ThreadModeRawMutex, BatteryLevel, BATTERY_CHANNEL_SIZE> = Channel::new();
+static SLAVE_BATTERY_CHANNEL: Channel<ThreadModeRawMutex, BatteryLevel, BATTERY_CHANNEL_SIZE> = Channel::new();
+
+// Last measured battery level of this half, or `u8::MAX` before the first
+// measurement.
+static LATEST_BATTERY_LEVEL: AtomicU8 = AtomicU8::new(u8::MAX);
 
+pub type BatteryLevelSender = Sender<'static, ThreadModeRawMutex, BatteryLevel, BATTERY_CHANNEL_SIZE>;
 pub type BatteryLevelReceiver = Receiver<'static, ThreadModeRawMutex, BatteryLevel, BATTERY_CHANNEL_SIZE>;
 
 pub fn battery_level_receiver() -> BatteryLevelReceiver {
     BATTERY_CHANNEL.receiver()
 }
 
+/// Battery levels the master received from the slave.
+pub fn slave_battery_level_sender() -> BatteryLevelSender {
+    SLAVE_BATTERY_CHANNEL.sender()
+}
+
+pub fn slave_battery_level_receiver() -> BatteryLevelReceiver {
+    SLAVE_BATTERY_CHANNEL.receiver()
+}
+
+/// Get the last measured battery level of this half, if it was measured yet.
+pub fn latest_battery_level() -> Option<BatteryLevel> {
+    match LATEST_BATTERY_LEVEL.load(Ordering::Relaxed) {
+        u8::MAX => None,
+        battery_level => Some(BatteryLevel(battery_level)),
+    }
+}
+
 #[embassy_executor::task]
 pub async fn battery_task() {
     // TODO: not steal but get from keyboard setup (?)
@@ -39,10 +74,13 @@ pub async fn battery_task() {
         saadc.calibrate().await;
         saadc.sample(&mut buffer).await;
 
-        let battery_percentage = calculate_battery_percentage(buffer[0]) as u8;
+        // The voltage can be slightly outside of the configured limits.
+        let battery_percentage = (calculate_battery_percentage(buffer[0]) as u8).min(100);
 
         defmt::info!("current battery percentage: {}", battery_percentage);
 
+        LATEST_BATTERY_LEVEL.store(battery_percentage, Ordering::Relaxed);
+
         BATTERY_CHANNEL.send(BatteryLevel(battery_percentage)).await;
 
         Timer::after(<crate::Used as Keyboard>::BATTERY_SAMPLE_FREQUENCY).await;
@@ -0,0 +1,24 @@
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+
+// Every signal only wakes a single waiter, so everything that reacts to
+// activity has its own.
+static CONNECTION_ACTIVITY: Signal<ThreadModeRawMutex, ()> = Signal::new();
+static ADVERTISING_ACTIVITY: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Let the connection parameters and advertising know that the keyboard is in
+/// use.
+pub fn register_activity() {
+    CONNECTION_ACTIVITY.signal(());
+    ADVERTISING_ACTIVITY.signal(());
+}
+
+pub(super) async fn connection_activity() {
+    CONNECTION_ACTIVITY.wait().await
+}
+
+/// Wait for the next key press. Activity from before the call is ignored.
+pub async fn advertising_activity() {
+    ADVERTISING_ACTIVITY.reset();
+    ADVERTISING_ACTIVITY.wait().await
+}
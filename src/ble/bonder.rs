@@ -1,52 +1,299 @@
+use core::cell::RefCell;
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
 use nrf_softdevice::ble::gatt_server::set_sys_attrs;
-use nrf_softdevice::ble::security::{IoCapabilities, SecurityHandler};
-use nrf_softdevice::ble::{gatt_server, Connection, EncryptionInfo, IdentityKey, MasterId};
+use nrf_softdevice::ble::security::{IoCapabilities, PasskeyReply, SecurityHandler};
+use nrf_softdevice::ble::{
+    gatt_client, gatt_server, Address, AddressType, Connection, EncryptionInfo, IdentityKey, IdentityResolutionKey, MasterId,
+};
+use nrf_softdevice::raw;
 
-use crate::flash::{get_settings, try_store_peer, try_store_system_attributes, BondSlot, FlashToken, Peer, SystemAttributes, NO_ADDRESS};
+use super::{host_bonded, pairing_slot, GenericAccessServiceClient};
+use crate::flash::{
+    get_settings, store_bond_metadata, try_remove_bond, try_store_bond_metadata, try_store_peer, try_store_system_attributes, BondMetadata,
+    BondSlot, FlashToken, Peer, SystemAttributes,
+};
+use crate::interface::Keyboard;
 use crate::side::Side;
 
+// Pairing fails on the host if the passkey is not entered within 30 seconds.
+// https://www.bluetooth.com/specifications/specs/core-specification/
+// Vol 3, Part H, Section 3.4 SMP Timeout
+const PASSKEY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Passkey request of a host that is currently pairing.
+struct PasskeyRequest {
+    reply: PasskeyReply,
+    // The host that is pairing, used to stop waiting for the passkey if it
+    // disconnects.
+    connection: Option<Connection>,
+    requested_at: Instant,
+}
+
 pub struct Bonder {
     flash_token: FlashToken,
+    // Host that most recently started pairing.
+    pairing_connection: RefCell<Option<Connection>>,
+    // Reply to the passkey request of a host that is currently pairing. The
+    // passkey is typed on the keyboard itself.
+    passkey_request: RefCell<Option<PasskeyRequest>>,
 }
 
 impl Bonder {
     pub fn new(flash_token: FlashToken) -> Self {
-        Self { flash_token }
+        Self {
+            flash_token,
+            pairing_connection: RefCell::new(None),
+            passkey_request: RefCell::new(None),
+        }
     }
+
+    /// Returns `true` while a host waits for the passkey to be typed. Requests
+    /// of hosts that disconnected or took too long are rejected.
+    pub fn is_entering_passkey(&self) -> bool {
+        let mut passkey_request = self.passkey_request.borrow_mut();
+
+        let aborted = passkey_request.as_ref().is_some_and(|request| {
+            let disconnected = request.connection.as_ref().is_some_and(|connection| connection.handle().is_none());
+            disconnected || request.requested_at.elapsed() > PASSKEY_TIMEOUT
+        });
+
+        if aborted {
+            defmt::warn!("Pairing was aborted, stopping passkey entry");
+
+            // Dropping the reply rejects the request.
+            *passkey_request = None;
+        }
+
+        passkey_request.is_some()
+    }
+
+    /// Reply to the pending passkey request. Passing `None` rejects the
+    /// pairing.
+    pub fn reply_passkey(&self, passkey: Option<&[u8; 6]>) {
+        let Some(request) = self.passkey_request.borrow_mut().take() else {
+            defmt::warn!("No passkey was requested");
+            return;
+        };
+
+        if let Err(error) = request.reply.reply(passkey) {
+            defmt::error!("Failed to reply with passkey: {}", error);
+        }
+    }
+}
+
+/// Only allow bonded hosts to connect when advertising with a filter policy.
+/// Returns the number of hosts on the whitelist.
+pub fn whitelist_bonded_hosts(flash_token: FlashToken) -> usize {
+    let addresses: Vec<raw::ble_gap_addr_t, { <crate::Used as Keyboard>::MAXIMUM_BONDS }> = get_settings(flash_token)
+        .bonds
+        .iter()
+        .filter(|bond| bond.is_used())
+        .map(|bond| bond.peer.peer_id.addr.into_raw())
+        .collect();
+    let pointers: Vec<*const raw::ble_gap_addr_t, { <crate::Used as Keyboard>::MAXIMUM_BONDS }> =
+        addresses.iter().map(|address| address as *const _).collect();
+
+    let result = unsafe { raw::sd_ble_gap_whitelist_set(pointers.as_ptr(), pointers.len() as u8) };
+
+    if result != raw::NRF_SUCCESS {
+        defmt::error!("Failed to set whitelist: {}", result);
+        return 0;
+    }
+
+    pointers.len()
+}
+
+/// Let the softdevice resolve the private addresses of bonded hosts, so they
+/// can be found on the whitelist and in directed advertising.
+pub fn resolve_bonded_hosts(flash_token: FlashToken) {
+    let identities: Vec<raw::ble_gap_id_key_t, { <crate::Used as Keyboard>::MAXIMUM_BONDS }> = get_settings(flash_token)
+        .bonds
+        .iter()
+        .filter(|bond| bond.is_used())
+        .map(|bond| raw::ble_gap_id_key_t {
+            id_info: *bond.peer.peer_id.irk.as_raw(),
+            id_addr_info: bond.peer.peer_id.addr.into_raw(),
+        })
+        .collect();
+    let pointers: Vec<*const raw::ble_gap_id_key_t, { <crate::Used as Keyboard>::MAXIMUM_BONDS }> =
+        identities.iter().map(|identity| identity as *const _).collect();
+
+    // Passing no local keys uses the key of our own identity for every host.
+    let result = unsafe { raw::sd_ble_gap_device_identities_set(pointers.as_ptr(), core::ptr::null(), pointers.len() as u8) };
+
+    if result != raw::NRF_SUCCESS {
+        defmt::error!("Failed to set device identities: {}", result);
+    }
+}
+
+// https://www.bluetooth.com/specifications/specs/core-specification/
+// Vol 3, Part H, Section 2.2.2 Random address hash function ah
+fn resolves_to(address: &Address, irk: &IdentityResolutionKey) -> bool {
+    if address.address_type() != AddressType::RandomPrivateResolvable {
+        return false;
+    }
+
+    // The key and the address are stored least significant byte first, while the
+    // encryption works on the most significant byte first. The lower three bytes
+    // of the address are the hash of the upper three.
+    let mut data = raw::nrf_ecb_hal_data_t {
+        key: irk.as_raw().irk,
+        cleartext: [0; 16],
+        ciphertext: [0; 16],
+    };
+    data.key.reverse();
+
+    for (index, byte) in address.bytes[3..].iter().enumerate() {
+        data.cleartext[15 - index] = *byte;
+    }
+
+    let result = unsafe { raw::sd_ecb_block_encrypt(&mut data) };
+
+    if result != raw::NRF_SUCCESS {
+        defmt::error!("Failed to resolve private address: {}", result);
+        return false;
+    }
+
+    address.bytes[..3]
+        .iter()
+        .enumerate()
+        .all(|(index, byte)| data.ciphertext[15 - index] == *byte)
+}
+
+/// Find the bond of the host with the given address. Hosts usually connect
+/// with a resolvable private address, which is resolved with the identity
+/// resolution key of every bond.
+pub fn find_bond(flash_token: FlashToken, address: Address) -> Option<BondSlot> {
+    let slot = get_settings(flash_token)
+        .bonds
+        .iter()
+        .position(|bond| bond.is_used() && (bond.peer.peer_id.addr == address || resolves_to(&address, &bond.peer.peer_id.irk)));
+
+    match slot {
+        Some(slot) => defmt::trace!("Found bond for peer with address {} in slot {}", address, slot),
+        None => defmt::trace!("No bond found for peer with address {}", address),
+    }
+
+    slot.map(BondSlot)
+}
+
+/// Update the metadata of the bond in the given slot when its host connects.
+/// The name of the host is read from its Generic Access service, if available.
+/// The flash is only written if the metadata changed.
+pub async fn update_bond_metadata(connection: &Connection, flash_token: FlashToken, bond_slot: BondSlot) {
+    let name = match gatt_client::discover::<GenericAccessServiceClient>(connection).await {
+        Ok(client) => client.device_name_read().await.ok(),
+        Err(error) => {
+            defmt::debug!("Failed to discover generic access service of host: {:?}", error);
+            None
+        }
+    };
+
+    let settings = get_settings(flash_token);
+    let previous = settings.bonds[bond_slot.0].metadata;
+    let mut metadata = previous;
+
+    // Keep the name we know from a previous connection if the host does not tell
+    // us.
+    if let Some(name) = name {
+        metadata.set_name(&name);
+    }
+
+    // The counter only orders the hosts, so it does not need to change if the host
+    // already connected last.
+    let connected_last = settings
+        .bonds
+        .iter()
+        .filter(|bond| bond.is_used())
+        .all(|bond| bond.metadata.last_connected <= previous.last_connected);
+
+    if !connected_last {
+        metadata.last_connected = settings.next_connection_counter();
+    }
+
+    if metadata.last_connected == previous.last_connected && metadata.name() == previous.name() {
+        defmt::trace!("Metadata of bond in slot {} is up to date", bond_slot);
+        return;
+    }
+
+    defmt::debug!("Updating metadata of bond in slot {}: {}", bond_slot, metadata);
+
+    store_bond_metadata(Side::Both, bond_slot, metadata).await;
 }
 
 impl SecurityHandler for Bonder {
+    // Passkeys are typed on the keyboard, which protects new bonds against man in
+    // the middle attacks.
     fn io_capabilities(&self) -> IoCapabilities {
-        IoCapabilities::None
+        IoCapabilities::KeyboardOnly
     }
 
-    fn can_bond(&self, _conn: &Connection) -> bool {
-        true
+    // Only allow new bonds while the keyboard is in pairing mode.
+    fn can_bond(&self, conn: &Connection) -> bool {
+        let can_bond = pairing_slot().is_some();
+
+        if can_bond {
+            *self.pairing_connection.borrow_mut() = Some(conn.clone());
+        }
+
+        can_bond
     }
 
     fn display_passkey(&self, passkey: &[u8; 6]) {
         defmt::info!("The passkey is \"{:a}\"", passkey)
     }
 
-    fn on_bonded(&self, _conn: &Connection, master_id: MasterId, key: EncryptionInfo, peer_id: IdentityKey) {
+    fn enter_passkey(&self, reply: PasskeyReply) {
+        defmt::info!("Type the passkey shown by the host and press enter");
+
+        // A previous request that was not answered is rejected when it is dropped.
+        *self.passkey_request.borrow_mut() = Some(PasskeyRequest {
+            reply,
+            connection: self.pairing_connection.borrow_mut().take(),
+            requested_at: Instant::now(),
+        });
+    }
+
+    fn on_bonded(&self, conn: &Connection, master_id: MasterId, key: EncryptionInfo, peer_id: IdentityKey) {
         defmt::debug!("Storing bond with key {} for master with id {}", key, master_id);
 
-        let free_slot = get_settings(self.flash_token)
+        *self.pairing_connection.borrow_mut() = None;
+
+        // New hosts can only bond in pairing mode, which always has a slot to store the
+        // bond in.
+        let Some(bond_slot) = pairing_slot() else {
+            defmt::warn!("Not storing bond outside of pairing mode");
+            return;
+        };
+
+        defmt::trace!("Pairing into slot {}", bond_slot);
+
+        // The host might have been bonded in another slot before, so we remove that
+        // bond.
+        get_settings(self.flash_token)
             .bonds
             .iter()
-            .position(|bond| bond.peer.peer_id.addr == NO_ADDRESS);
+            .enumerate()
+            .filter(|(slot, bond)| *slot != bond_slot.0 && bond.peer.peer_id.addr == peer_id.addr)
+            .for_each(|(slot, _)| try_remove_bond(Side::Both, BondSlot(slot)));
 
-        match free_slot {
-            Some(free_slot) => defmt::trace!("Found free slot at {}", free_slot),
-            None => defmt::trace!("No free slot found for bond"),
-        }
+        let peer = Peer { master_id, key, peer_id };
+
+        // The slot might have held the bond that was used least recently, so the
+        // metadata of that host is replaced as well.
+        let metadata = BondMetadata::new(get_settings(self.flash_token).next_connection_counter());
 
-        // FIX: Figure out how to choose another slot if all of them are full
-        if let Some(free_slot) = free_slot {
-            let bond_slot = BondSlot(free_slot);
-            let peer = Peer { master_id, key, peer_id };
+        try_store_peer(Side::Both, bond_slot, peer);
+        try_store_system_attributes(Side::Both, bond_slot, SystemAttributes::new());
+        try_store_bond_metadata(Side::Both, bond_slot, metadata);
 
-            try_store_peer(Side::Both, bond_slot, peer);
+        // Only now that the bond is stored, the host gets the slot and pairing mode
+        // ends.
+        match conn.handle() {
+            Some(handle) => host_bonded(handle, bond_slot),
+            None => defmt::warn!("Host disconnected while bonding"),
         }
     }
 
@@ -70,18 +317,7 @@ impl SecurityHandler for Bonder {
 
         defmt::debug!("Saving system attributes for peer with address {}", peer_address);
 
-        let slot = get_settings(self.flash_token)
-            .bonds
-            .iter()
-            .position(|bond| bond.peer.peer_id.addr == peer_address);
-
-        match slot {
-            Some(slot) => defmt::trace!("Found bond for peer with address {} in slot {}", peer_address, slot),
-            None => defmt::trace!("No bond found for peer with address {}", peer_address),
-        }
-
-        if let Some(slot) = slot {
-            let bond_slot = BondSlot(slot);
+        if let Some(bond_slot) = find_bond(self.flash_token, peer_address) {
             let mut system_attributes = SystemAttributes::new();
             let length = defmt::unwrap!(gatt_server::get_sys_attrs(conn, &mut system_attributes.data));
             system_attributes.length = length;
@@ -95,11 +331,10 @@ impl SecurityHandler for Bonder {
 
         defmt::debug!("Loading system attributes for peer with address {}", peer_address);
 
-        let attributes = get_settings(self.flash_token)
-            .bonds
-            .iter()
-            .find(|bond| bond.peer.peer_id.addr == peer_address)
-            .map(|bond| &bond.system_attributes.data[..bond.system_attributes.length])
+        let bonds = &get_settings(self.flash_token).bonds;
+        let attributes = find_bond(self.flash_token, peer_address)
+            .map(|bond_slot| &bonds[bond_slot.0].system_attributes)
+            .map(|system_attributes| &system_attributes.data[..system_attributes.length])
             .filter(|attributes| !attributes.is_empty());
 
         match attributes {
@@ -0,0 +1,88 @@
+use embassy_time::Timer;
+use futures::future::{pending, select, Either};
+use futures::pin_mut;
+use nrf_softdevice::ble::Connection;
+use nrf_softdevice::raw;
+
+use super::activity::connection_activity;
+use super::Hosts;
+use crate::interface::Keyboard;
+
+/// Connection parameters that are requested from hosts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ConnectionProfile {
+    /// Short connection interval without slave latency, used while typing.
+    LowLatency,
+    /// Long connection interval with slave latency, used while idle to save
+    /// battery.
+    Relaxed,
+}
+
+impl ConnectionProfile {
+    // Intervals are in units of 1.25 milliseconds and the supervision timeout is in
+    // units of 10 milliseconds. The supervision timeout needs to be longer than
+    // (1 + slave latency) * maximum interval * 2.
+    //
+    // Apple devices reject parameters that do not follow their accessory design
+    // guidelines. HID devices need a minimum interval of at least 11.25
+    // milliseconds and a maximum interval that is at least 15 milliseconds longer.
+    // https://developer.apple.com/accessories/Accessory-Design-Guidelines.pdf
+    pub const fn parameters(self) -> raw::ble_gap_conn_params_t {
+        match self {
+            Self::LowLatency => raw::ble_gap_conn_params_t {
+                min_conn_interval: 9,
+                max_conn_interval: 21,
+                slave_latency: 0,
+                conn_sup_timeout: 400,
+            },
+            Self::Relaxed => raw::ble_gap_conn_params_t {
+                min_conn_interval: 24,
+                max_conn_interval: 40,
+                slave_latency: 30,
+                conn_sup_timeout: 600,
+            },
+        }
+    }
+}
+
+/// Ask the host to use the parameters of the given profile. The host decides
+/// which parameters are actually used.
+pub fn request_connection_profile(connection: &Connection, profile: ConnectionProfile) {
+    if let Err(error) = connection.set_conn_params(profile.parameters()) {
+        defmt::warn!("Failed to request {} connection parameters: {:?}", profile, error);
+    }
+}
+
+/// Switch between connection profiles based on activity. Only the host that
+/// receives input reports is asked for low latency parameters.
+pub async fn manage_connection_parameters(hosts: &Hosts) -> ! {
+    let mut profile = ConnectionProfile::Relaxed;
+
+    loop {
+        let activity_future = connection_activity();
+        let connections_future = hosts.connections_changed();
+        let idle_future = async {
+            match profile {
+                ConnectionProfile::LowLatency => Timer::after(<crate::Used as Keyboard>::CONNECTION_IDLE_TIMEOUT).await,
+                ConnectionProfile::Relaxed => pending().await,
+            }
+        };
+
+        pin_mut!(activity_future);
+        pin_mut!(connections_future);
+        pin_mut!(idle_future);
+
+        match select(select(activity_future, connections_future), idle_future).await {
+            // Keep the current parameters as long as the keyboard is in use.
+            Either::Left((Either::Left(..), _)) if profile == ConnectionProfile::LowLatency => continue,
+            Either::Left((Either::Left(..), _)) => profile = ConnectionProfile::LowLatency,
+            // New hosts and profile changes need the parameters to be requested again.
+            Either::Left((Either::Right(..), _)) => {}
+            Either::Right(..) => profile = ConnectionProfile::Relaxed,
+        }
+
+        defmt::debug!("Requesting {} connection parameters", profile);
+
+        hosts.request_connection_profile(profile);
+    }
+}
@@ -0,0 +1,50 @@
+use heapless::Vec;
+
+use super::Server;
+use crate::interface::Keyboard;
+
+// Maximum length of the strings in the device information service. Longer
+// strings are truncated.
+pub const DEVICE_INFORMATION_SIZE: usize = 32;
+
+// https://www.bluetooth.com/specifications/specs/device-information-service-1-1/
+// Section 3.9 PnP ID
+pub const PNP_ID_SIZE: usize = 7;
+const VENDOR_ID_SOURCE_USB: u8 = 0x02;
+
+// The git hash is embedded by the build script.
+const FIRMWARE_REVISION: &str = concat!(env!("CARGO_PKG_VERSION"), "-", env!("GIT_HASH"));
+
+fn string_value(value: &[u8]) -> Vec<u8, DEVICE_INFORMATION_SIZE> {
+    defmt::unwrap!(Vec::from_slice(&value[..value.len().min(DEVICE_INFORMATION_SIZE)]))
+}
+
+const fn pnp_id() -> [u8; PNP_ID_SIZE] {
+    let vendor_id = <crate::Used as Keyboard>::VENDOR_ID.to_le_bytes();
+    let product_id = <crate::Used as Keyboard>::PRODUCT_ID.to_le_bytes();
+    let product_version = <crate::Used as Keyboard>::PRODUCT_VERSION.to_le_bytes();
+
+    [
+        VENDOR_ID_SOURCE_USB,
+        vendor_id[0],
+        vendor_id[1],
+        product_id[0],
+        product_id[1],
+        product_version[0],
+        product_version[1],
+    ]
+}
+
+/// Fill in the device information service, so hosts can see which keyboard
+/// and firmware they are connected to.
+pub fn initialize_device_information(server: &Server) {
+    let service = &server.device_information_service;
+
+    defmt::info!("Firmware revision is {}", FIRMWARE_REVISION);
+
+    defmt::unwrap!(service.manufacturer_name_set(&string_value(<crate::Used as Keyboard>::MANUFACTURER_NAME)));
+    defmt::unwrap!(service.model_number_set(&string_value(<crate::Used as Keyboard>::DEVICE_NAME)));
+    defmt::unwrap!(service.firmware_revision_set(&string_value(FIRMWARE_REVISION.as_bytes())));
+    defmt::unwrap!(service.hardware_revision_set(&string_value(<crate::Used as Keyboard>::HARDWARE_REVISION)));
+    defmt::unwrap!(service.pnp_id_set(&pnp_id()));
+}
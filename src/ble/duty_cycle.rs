@@ -0,0 +1,59 @@
+use embassy_time::Duration;
+use nrf_softdevice::ble::{central, peripheral};
+
+use crate::interface::Keyboard;
+
+/// How often the radio advertises or scans while looking for hosts or the
+/// other half. The radio starts with the fast duty cycle, so connections are
+/// made quickly, and drops to the slow duty cycle to save battery. Once that
+/// times out too, the radio stops until a key is pressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum DutyCycle {
+    Fast,
+    Slow,
+}
+
+impl DutyCycle {
+    /// Duty cycle that is used after this one timed out. `None` means that the
+    /// radio stops.
+    pub const fn next(self) -> Option<Self> {
+        match self {
+            Self::Fast => Some(Self::Slow),
+            Self::Slow => None,
+        }
+    }
+
+    /// Time to advertise to hosts before moving on to the next duty cycle.
+    pub fn advertising_timeout(self) -> Duration {
+        match self {
+            Self::Fast => <crate::Used as Keyboard>::FAST_ADVERTISING_TIMEOUT,
+            Self::Slow => <crate::Used as Keyboard>::SLOW_ADVERTISING_TIMEOUT,
+        }
+    }
+
+    /// Time to search for the other half before moving on to the next duty
+    /// cycle.
+    pub fn search_timeout(self) -> Duration {
+        match self {
+            Self::Fast => <crate::Used as Keyboard>::FAST_SEARCH_TIMEOUT,
+            Self::Slow => <crate::Used as Keyboard>::SLOW_SEARCH_TIMEOUT,
+        }
+    }
+
+    // Intervals and windows are in units of 0.625 milliseconds. The fast
+    // advertising interval of 30 milliseconds and the slow interval of 1022.5
+    // milliseconds are the ones recommended for accessories by Apple.
+    pub fn configure_advertising(self, config: &mut peripheral::Config) {
+        config.interval = match self {
+            Self::Fast => 48,
+            Self::Slow => 1636,
+        };
+    }
+
+    pub fn configure_scanning(self, config: &mut central::ScanConfig) {
+        (config.interval, config.window) = match self {
+            Self::Fast => (96, 48),
+            Self::Slow => (1600, 96),
+        };
+    }
+}
@@ -0,0 +1,345 @@
+use core::cell::{Cell, RefCell};
+
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use futures::future::{pending, select};
+use futures::pin_mut;
+use heapless::Vec;
+use nrf_softdevice::ble::{gatt_server, Connection};
+
+use super::{
+    clear_reports, request_connection_profile, update_bond_metadata, ConnectionProfile, HidServiceEvent, ProfileRequest, RawHidReport,
+    Server, ServerEvent,
+};
+use crate::flash::{BondSlot, FlashToken};
+
+// Number of hosts that can be connected at the same time. The softdevice needs
+// one more peripheral connection for the other half.
+pub const MAXIMUM_HOSTS: usize = 3;
+
+// https://www.bluetooth.com/specifications/specs/hid-service-1-0/
+// Section 2.2 Protocol Mode
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ProtocolMode {
+    Boot = 0,
+    Report = 1,
+}
+
+impl ProtocolMode {
+    pub const fn from_raw(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Boot),
+            1 => Some(Self::Report),
+            _ => None,
+        }
+    }
+}
+
+// https://www.bluetooth.com/specifications/specs/hid-service-1-0/
+// Section 2.7 HID Control Point
+const CONTROL_POINT_SUSPEND: u8 = 0;
+const CONTROL_POINT_EXIT_SUSPEND: u8 = 1;
+
+const RAW_HID_CHANNEL_SIZE: usize = 4;
+
+// State of a single host connection that is controlled by the host through the
+// HID service. It is reset for every connection, since hosts expect the report
+// protocol after connecting.
+struct HostSlot {
+    connection: RefCell<Option<Connection>>,
+    bond_slot: Cell<Option<BondSlot>>,
+    protocol_mode: Cell<ProtocolMode>,
+    suspended: Cell<bool>,
+    connected: Signal<NoopRawMutex, ()>,
+    // Signaled once the bond of the host is known.
+    bonded: Signal<NoopRawMutex, ()>,
+}
+
+impl HostSlot {
+    const fn new() -> Self {
+        Self {
+            connection: RefCell::new(None),
+            bond_slot: Cell::new(None),
+            protocol_mode: Cell::new(ProtocolMode::Report),
+            suspended: Cell::new(false),
+            connected: Signal::new(),
+            bonded: Signal::new(),
+        }
+    }
+
+    fn is_free(&self) -> bool {
+        self.connection.borrow().is_none()
+    }
+}
+
+/// All hosts that are connected to the master. Every host stays connected, but
+/// only the host of the selected profile receives input reports.
+pub struct Hosts {
+    slots: [HostSlot; MAXIMUM_HOSTS],
+    profile: Cell<ProfileRequest>,
+    profile_changed: Signal<NoopRawMutex, ()>,
+    slot_freed: Signal<NoopRawMutex, ()>,
+    connections_changed: Signal<NoopRawMutex, ()>,
+    suspended: Signal<NoopRawMutex, bool>,
+    raw_hid_requests: Channel<NoopRawMutex, (usize, RawHidReport), RAW_HID_CHANNEL_SIZE>,
+}
+
+impl Hosts {
+    pub const fn new(profile: ProfileRequest) -> Self {
+        const FREE_SLOT: HostSlot = HostSlot::new();
+
+        Self {
+            slots: [FREE_SLOT; MAXIMUM_HOSTS],
+            profile: Cell::new(profile),
+            profile_changed: Signal::new(),
+            slot_freed: Signal::new(),
+            connections_changed: Signal::new(),
+            suspended: Signal::new(),
+            raw_hid_requests: Channel::new(),
+        }
+    }
+
+    pub fn profile(&self) -> ProfileRequest {
+        self.profile.get()
+    }
+
+    /// Select the host that receives input reports. If the host is already
+    /// connected, this takes effect immediately.
+    pub fn set_profile(&self, profile: ProfileRequest) {
+        self.profile.set(profile);
+        self.profile_changed.signal(());
+        self.connections_changed.signal(());
+
+        // The keyboard should follow the suspend state of the new host.
+        let suspended = self.active_index().map(|index| self.slots[index].suspended.get());
+        self.suspended.signal(suspended.unwrap_or(false));
+    }
+
+    /// Wait until another profile is selected.
+    pub async fn profile_changed(&self) {
+        self.profile_changed.wait().await
+    }
+
+    pub fn active_index(&self) -> Option<usize> {
+        let (ProfileRequest::Select(bond_slot) | ProfileRequest::Pair(bond_slot)) = self.profile.get();
+        self.index_of(bond_slot)
+    }
+
+    fn index_of(&self, bond_slot: BondSlot) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| !slot.is_free() && slot.bond_slot.get() == Some(bond_slot))
+    }
+
+    pub fn is_connected(&self, bond_slot: BondSlot) -> bool {
+        self.index_of(bond_slot).is_some()
+    }
+
+    pub fn connection(&self, index: usize) -> Option<Connection> {
+        self.slots[index].connection.borrow().clone()
+    }
+
+    /// Get the connection and protocol mode of the host that receives input
+    /// reports.
+    pub fn active_connection(&self) -> Option<(Connection, ProtocolMode)> {
+        let slot = &self.slots[self.active_index()?];
+        let connection = slot.connection.borrow().clone()?;
+        Some((connection, slot.protocol_mode.get()))
+    }
+
+    pub fn connections(&self) -> Vec<Connection, MAXIMUM_HOSTS> {
+        self.slots.iter().filter_map(|slot| slot.connection.borrow().clone()).collect()
+    }
+
+    /// Wait until there is room for another host.
+    pub async fn wait_for_free_slot(&self) {
+        while !self.slots.iter().any(HostSlot::is_free) {
+            self.slot_freed.wait().await;
+        }
+    }
+
+    /// Add a new host connection. The connection is served by [`Self::run`].
+    pub fn add(&self, connection: Connection, bond_slot: Option<BondSlot>) {
+        let Some(index) = self.slots.iter().position(HostSlot::is_free) else {
+            defmt::warn!("No free slot for host connection");
+            return;
+        };
+
+        let slot = &self.slots[index];
+        slot.bond_slot.set(bond_slot);
+        slot.protocol_mode.set(ProtocolMode::Report);
+        slot.suspended.set(false);
+        *slot.connection.borrow_mut() = Some(connection);
+        slot.connected.signal(());
+
+        match bond_slot {
+            Some(..) => slot.bonded.signal(()),
+            None => slot.bonded.reset(),
+        }
+
+        self.connections_changed.signal(());
+
+        // Reports that were queued before the selected host connected are meaningless
+        // to it.
+        if self.active_index() == Some(index) {
+            clear_reports();
+        }
+    }
+
+    /// Assign a new bond to the connected host with the given connection
+    /// handle. Hosts that are not bonded yet when they connect only get their
+    /// slot once bonding succeeds.
+    pub fn assign_bond(&self, handle: u16, bond_slot: BondSlot) {
+        let Some(index) = self
+            .slots
+            .iter()
+            .position(|slot| slot.connection.borrow().as_ref().and_then(Connection::handle) == Some(handle))
+        else {
+            defmt::warn!("Bonded host is no longer connected");
+            return;
+        };
+
+        // The bond replaced the one of any other connected host in the same slot.
+        for slot in self.slots.iter().filter(|slot| slot.bond_slot.get() == Some(bond_slot)) {
+            slot.bond_slot.set(None);
+        }
+
+        let slot = &self.slots[index];
+        slot.bond_slot.set(Some(bond_slot));
+        slot.bonded.signal(());
+        self.connections_changed.signal(());
+
+        if self.active_index() == Some(index) {
+            clear_reports();
+        }
+    }
+
+    /// Wait until a host connects or another profile is selected.
+    pub async fn connections_changed(&self) {
+        self.connections_changed.wait().await
+    }
+
+    /// Request the given connection profile from the selected host and the
+    /// relaxed profile from every other host.
+    pub fn request_connection_profile(&self, profile: ConnectionProfile) {
+        let active_index = self.active_index();
+
+        for (index, slot) in self.slots.iter().enumerate() {
+            if let Some(connection) = slot.connection.borrow().as_ref() {
+                match active_index == Some(index) {
+                    true => request_connection_profile(connection, profile),
+                    false => request_connection_profile(connection, ConnectionProfile::Relaxed),
+                }
+            }
+        }
+    }
+
+    /// Run the GATT server for the host in the given slot and keep the metadata
+    /// of its bond up to date. Never returns.
+    pub async fn run(&self, index: usize, server: &Server, flash_token: FlashToken) -> ! {
+        let slot = &self.slots[index];
+
+        loop {
+            slot.connected.wait().await;
+
+            let Some(connection) = slot.connection.borrow().clone() else {
+                continue;
+            };
+
+            defmt::info!("Host with bond {} connected in slot {}", slot.bond_slot.get(), index);
+
+            // The slot starts in the report protocol, which hosts expect after connecting.
+            self.update_protocol_mode(server);
+
+            let server_future = gatt_server::run(&connection, server, |event| self.handle_event(index, server, event));
+            let metadata_future = async {
+                // Reading the name of the host takes a while, so it runs next to the server
+                // instead of delaying other connections.
+                slot.bonded.wait().await;
+
+                if let Some(bond_slot) = slot.bond_slot.get() {
+                    update_bond_metadata(&connection, flash_token, bond_slot).await;
+                }
+
+                pending::<()>().await
+            };
+
+            pin_mut!(server_future);
+            pin_mut!(metadata_future);
+
+            select(server_future, metadata_future).await;
+
+            defmt::info!("Host in slot {} disconnected", index);
+
+            *slot.connection.borrow_mut() = None;
+            slot.bond_slot.set(None);
+            self.slot_freed.signal(());
+        }
+    }
+
+    /// Show the protocol mode of the selected host in the Protocol Mode
+    /// characteristic. Every host selects its own protocol mode, but the
+    /// characteristic is shared by all connections.
+    pub fn update_protocol_mode(&self, server: &Server) {
+        let protocol_mode = self
+            .active_index()
+            .map_or(ProtocolMode::Report, |index| self.slots[index].protocol_mode.get());
+
+        defmt::unwrap!(server.hid_service.protocol_mode_set(&(protocol_mode as u8)));
+    }
+
+    /// Wait until the selected host enters or exits suspend.
+    pub async fn suspend_changed(&self) -> bool {
+        self.suspended.wait().await
+    }
+
+    /// Wait for the next raw HID request from any host. Returns the index of
+    /// the host together with the request.
+    pub async fn raw_hid_request(&self) -> (usize, RawHidReport) {
+        self.raw_hid_requests.recv().await
+    }
+
+    fn handle_event(&self, index: usize, server: &Server, event: ServerEvent) {
+        let slot = &self.slots[index];
+
+        match event {
+            ServerEvent::HidService(HidServiceEvent::ProtocolModeWrite(value)) => match ProtocolMode::from_raw(value) {
+                Some(protocol_mode) => {
+                    defmt::debug!("Host switched to {} protocol", protocol_mode);
+                    slot.protocol_mode.set(protocol_mode);
+
+                    // The write changed the shared characteristic, which should only follow the
+                    // selected host.
+                    self.update_protocol_mode(server);
+                }
+                None => defmt::warn!("Host requested unknown protocol mode {}", value),
+            },
+            ServerEvent::HidService(HidServiceEvent::ControlPointWrite(value)) => {
+                let suspended = match value {
+                    CONTROL_POINT_SUSPEND => true,
+                    CONTROL_POINT_EXIT_SUSPEND => false,
+                    _ => {
+                        defmt::warn!("Unknown control point value {}", value);
+                        return;
+                    }
+                };
+
+                defmt::debug!("Host in slot {} changed suspend to {}", index, suspended);
+                slot.suspended.set(suspended);
+
+                // Only the selected host decides if the keyboard is suspended.
+                if self.active_index() == Some(index) {
+                    self.suspended.signal(suspended);
+                }
+            }
+            ServerEvent::HidService(HidServiceEvent::RawOutputReportWrite(report)) => {
+                if self.raw_hid_requests.try_send((index, report)).is_err() {
+                    defmt::warn!("Raw HID channel is full, dropping request");
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use nrf_softdevice::ble::{set_address, Address, AddressType};
+use nrf_softdevice::{raw, Softdevice};
+
+use super::IDENTITY_SIZE;
+
+// Resolvable private addresses are renewed after this many seconds.
+const PRIVATE_ADDRESS_CYCLE: u16 = 900;
+
+/// Identity that the keyboard presents to hosts. Hosts only see resolvable
+/// private addresses, which they can resolve to the identity address with the
+/// key they receive while bonding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Identity {
+    pub address: Address,
+    pub key: [u8; 16],
+}
+
+impl Identity {
+    /// Get the identity of this half. Both the address and the key are unique
+    /// for every chip, since they are derived from the factory information
+    /// configuration registers.
+    pub fn from_ficr() -> Self {
+        let ficr = unsafe { &*embassy_nrf::pac::FICR::ptr() };
+
+        let mut key = [0; 16];
+        for (chunk, register) in key.chunks_exact_mut(4).zip(ficr.ir.iter()) {
+            chunk.copy_from_slice(&register.read().bits().to_le_bytes());
+        }
+
+        Self {
+            address: device_address(),
+            key,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; IDENTITY_SIZE] {
+        let mut bytes = [0; IDENTITY_SIZE];
+        bytes[..6].copy_from_slice(&self.address.bytes);
+        bytes[6..].copy_from_slice(&self.key);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; IDENTITY_SIZE]) -> Self {
+        let mut address = [0; 6];
+        let mut key = [0; 16];
+        address.copy_from_slice(&bytes[..6]);
+        key.copy_from_slice(&bytes[6..]);
+
+        Self {
+            address: Address::new(AddressType::RandomStatic, address),
+            key,
+        }
+    }
+}
+
+/// Get the static random address of this half. It is derived from the device
+/// address in the factory information configuration registers, so every chip
+/// has a different address.
+pub fn device_address() -> Address {
+    let ficr = unsafe { &*embassy_nrf::pac::FICR::ptr() };
+
+    let low = ficr.deviceaddr[0].read().bits().to_le_bytes();
+    let high = ficr.deviceaddr[1].read().bits().to_le_bytes();
+    let mut bytes = [low[0], low[1], low[2], low[3], high[0], high[1]];
+
+    // The two most significant bits of a static random address must be set.
+    bytes[5] |= 0b1100_0000;
+
+    Address::new(AddressType::RandomStatic, bytes)
+}
+
+/// Check if the address is a valid static random address, which both halves
+/// use as their identity. Erased flash, which reads as all ones, and cleared
+/// flash never hold one.
+pub fn is_static_address(address: &Address) -> bool {
+    // https://www.bluetooth.com/specifications/specs/core-specification/
+    // Vol 6, Part B, Section 1.3.2.1 Static device address
+    let is_random_static = address.flags >> 1 == raw::BLE_GAP_ADDR_TYPE_RANDOM_STATIC as u8;
+    let top_bits_set = address.bytes[5] & 0b1100_0000 == 0b1100_0000;
+
+    // The random part of the address may not be all zeros or all ones.
+    let mut random_part = address.bytes;
+    random_part[5] &= 0b0011_1111;
+    let is_all_zeros = random_part == [0; 6];
+    let is_all_ones = random_part == [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0b0011_1111];
+
+    is_random_static && top_bits_set && !is_all_zeros && !is_all_ones
+}
+
+/// Use the static address of this half without privacy. Used while the halves
+/// find each other.
+pub fn use_device_address(softdevice: &Softdevice) {
+    set_privacy(None);
+    set_address(softdevice, &device_address());
+}
+
+/// Present the given identity to hosts, advertising with resolvable private
+/// addresses.
+pub fn use_identity(softdevice: &Softdevice, identity: &Identity) {
+    set_privacy(None);
+    set_address(softdevice, &identity.address);
+    set_privacy(Some(&identity.key));
+}
+
+fn set_privacy(key: Option<&[u8; 16]>) {
+    let mut device_key = raw::ble_gap_irk_t {
+        irk: key.copied().unwrap_or_default(),
+    };
+
+    let parameters = raw::ble_gap_privacy_params_t {
+        privacy_mode: match key.is_some() {
+            true => raw::BLE_GAP_PRIVACY_MODE_DEVICE_PRIVACY as u8,
+            false => raw::BLE_GAP_PRIVACY_MODE_OFF as u8,
+        },
+        private_addr_type: raw::BLE_GAP_ADDR_TYPE_RANDOM_PRIVATE_RESOLVABLE as u8,
+        private_addr_cycle_s: PRIVATE_ADDRESS_CYCLE,
+        p_device_irk: &mut device_key,
+    };
+
+    let result = unsafe { raw::sd_ble_gap_privacy_set(&parameters) };
+
+    if result != raw::NRF_SUCCESS {
+        defmt::error!("Failed to set privacy: {}", result);
+    }
+}
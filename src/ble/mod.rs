@@ -1,67 +1,127 @@
-mod advertising;
+mod activity;
 mod bonder;
+mod connection_parameters;
+mod device_information;
+mod duty_cycle;
+mod host;
+mod identity;
+mod passkey;
+mod profile;
+mod raw_hid;
+mod report;
 
-pub use self::advertising::{AdvertisingData, KEYBOARD_ICON};
-pub use self::bonder::Bonder;
+pub use logic::ble::{
+    advertises_service, AdvertisingData, CONSUMER_INPUT_REPORT_SIZE, CONSUMER_REPORT_ID, IDENTITY_SIZE, KEYBOARD_ICON,
+    KEYBOARD_INPUT_REPORT_SIZE, KEYBOARD_OUTPUT_REPORT_SIZE, KEYBOARD_REPORT_ID, MOUSE_INPUT_REPORT_SIZE, MOUSE_REPORT_ID,
+    RAW_HID_REPORT_ID, RAW_HID_REPORT_SIZE,
+};
+use logic::ble::{ReportType, BOOT_INPUT_REPORT_SIZE, MAP_DATA};
+
+pub use self::activity::{advertising_activity, register_activity};
+pub use self::bonder::{find_bond, resolve_bonded_hosts, update_bond_metadata, whitelist_bonded_hosts, Bonder};
+pub use self::connection_parameters::{manage_connection_parameters, request_connection_profile, ConnectionProfile};
+pub use self::device_information::{initialize_device_information, DEVICE_INFORMATION_SIZE, PNP_ID_SIZE};
+pub use self::duty_cycle::DutyCycle;
+pub use self::host::{Hosts, ProtocolMode, MAXIMUM_HOSTS};
+pub use self::identity::{device_address, is_static_address, use_device_address, use_identity, Identity};
+pub use self::passkey::PasskeyInput;
+pub use self::profile::{
+    bonded_host, enter_pairing_mode, host_bonded, pair_profile, pairing_slot, profile_request, select_profile, set_pairing_slot,
+    stored_profile, ProfileRequest,
+};
+pub use self::raw_hid::{FromRawHid, HostRequest, RawHidReport, RawHidResponse, RawHidStatus};
+pub use self::report::{
+    clear_reports, queue_report, release_all_keys, report_statistics, run_report_queue, KeyboardReport, ReportStatistics,
+};
+use crate::split::{message_size, WireMessage};
+
+// https://www.bluetooth.com/specifications/specs/battery-service/
+// Every half has its own battery service. Hosts can tell them apart by the
+// description in the characteristic presentation format.
+const LEFT_BATTERY_FORMAT: [u8; 7] = battery_format(BATTERY_DESCRIPTION_LEFT);
+const RIGHT_BATTERY_FORMAT: [u8; 7] = battery_format(BATTERY_DESCRIPTION_RIGHT);
+
+// https://www.bluetooth.com/specifications/assigned-numbers/
+// Section 2.4 Characteristic Presentation Format and section 2.4.2.1 Bluetooth
+// SIG GATT Namespace Descriptors
+const FORMAT_UINT8: u8 = 0x04;
+const UNIT_PERCENTAGE: u16 = 0x27AD;
+const NAMESPACE_BLUETOOTH_SIG: u8 = 0x01;
+const BATTERY_DESCRIPTION_LEFT: u16 = 0x010D;
+const BATTERY_DESCRIPTION_RIGHT: u16 = 0x010E;
+
+const fn battery_format(description: u16) -> [u8; 7] {
+    let unit = UNIT_PERCENTAGE.to_le_bytes();
+    let description = description.to_le_bytes();
+    [
+        FORMAT_UINT8,
+        0,
+        unit[0],
+        unit[1],
+        NAMESPACE_BLUETOOTH_SIG,
+        description[0],
+        description[1],
+    ]
+}
 
 #[nrf_softdevice::gatt_service(uuid = "180f")]
-pub struct BatteryService {
-    #[characteristic(uuid = "2a19", security = "justworks", read, notify)]
+pub struct LeftBatteryService {
+    #[characteristic(
+        uuid = "2a19",
+        security = "mitm",
+        read,
+        notify,
+        descriptor(uuid = "2904", security = "mitm", value = "LEFT_BATTERY_FORMAT")
+    )]
+    pub battery_level: u8,
+}
+
+#[nrf_softdevice::gatt_service(uuid = "180f")]
+pub struct RightBatteryService {
+    #[characteristic(
+        uuid = "2a19",
+        security = "mitm",
+        read,
+        notify,
+        descriptor(uuid = "2904", security = "mitm", value = "RIGHT_BATTERY_FORMAT")
+    )]
     pub battery_level: u8,
 }
 
+// https://www.bluetooth.com/specifications/specs/device-information-service-1-1/
 #[nrf_softdevice::gatt_service(uuid = "180A")]
-pub struct DeviceInformationService {}
-
-const MAP_DATA: &[u8] = &[
-    0x05, 0x01, // Usage Page (Generic Desktop)
-    0x09, 0x06, // Usage (Keyboard)
-    0xA1, 0x01, // Collection (Application)
-    0x05, 0x07, // Usage Page (Key Codes)
-    0x19, 0xe0, // Usage Minimum (224)
-    0x29, 0xe7, // Usage Maximum (231)
-    0x15, 0x00, // Logical Minimum (0)
-    0x25, 0x01, // Logical Maximum (1)
-    0x75, 0x01, // Report Size (1)
-    0x95, 0x08, // Report Count (8)
-    0x81, 0x02, // Input (Data, Variable, Absolute)
-    0x95, 0x01, // Report Count (1)
-    0x75, 0x08, // Report Size (8)
-    0x81, 0x01, // Input (Constant) reserved byte(1)
-    0x95, 0x05, // Report Count (5)
-    0x75, 0x01, // Report Size (1)
-    0x05, 0x08, // Usage Page (Page# for LEDs)
-    0x19, 0x01, // Usage Minimum (1)
-    0x29, 0x05, // Usage Maximum (5)
-    0x91, 0x02, // Output (Data, Variable, Absolute), Led report
-    0x95, 0x01, // Report Count (1)
-    0x75, 0x03, // Report Size (3)
-    0x91, 0x01, // Output (Data, Variable, Absolute), Led report padding
-    0x95, 0x06, // Report Count (6)
-    0x75, 0x08, // Report Size (8)
-    0x15, 0x00, // Logical Minimum (0)
-    0x25, 0x65, // Logical Maximum (101)
-    0x05, 0x07, // Usage Page (Key codes)
-    0x19, 0x00, // Usage Minimum (0)
-    0x29, 0x65, // Usage Maximum (101)
-    0x81, 0x00, // Input (Data, Array) Key array(6 bytes)
-    0x09, 0x05, // Usage (Vendor Defined)
-    0x15, 0x00, // Logical Minimum (0)
-    0x26, 0xFF, 0x00, // Logical Maximum (255)
-    0x75, 0x08, // Report Size (8 bit)
-    0x95, 0x02, // Report Count (2)
-    0xB1, 0x02, // Feature (Data, Variable, Absolute)
-    0xC0, // End Collection (Application)
-];
+pub struct DeviceInformationService {
+    #[characteristic(uuid = "2A29", read)]
+    pub manufacturer_name: heapless::Vec<u8, DEVICE_INFORMATION_SIZE>,
+    #[characteristic(uuid = "2A24", read)]
+    pub model_number: heapless::Vec<u8, DEVICE_INFORMATION_SIZE>,
+    #[characteristic(uuid = "2A26", read)]
+    pub firmware_revision: heapless::Vec<u8, DEVICE_INFORMATION_SIZE>,
+    #[characteristic(uuid = "2A27", read)]
+    pub hardware_revision: heapless::Vec<u8, DEVICE_INFORMATION_SIZE>,
+    #[characteristic(uuid = "2A50", read)]
+    pub pnp_id: [u8; PNP_ID_SIZE],
+}
+
+// Generic Access service of the host. Used to read the name of the host.
+#[nrf_softdevice::gatt_client(uuid = "1800")]
+pub struct GenericAccessServiceClient {
+    #[characteristic(uuid = "2a00", read)]
+    pub device_name: heapless::Vec<u8, 256>,
+}
 
 const NO_DATA: &[u8] = &[];
-const INPUT_VALUE: [u8; 2] = [0, 1];
-const OUTPUT_VALUE: [u8; 2] = [0, 2];
-const FEATURE_VALUE: [u8; 2] = [0, 3];
-const BOOT_INPUT_REPORT_VALUE: [u8; 8] = [0; 8];
+const KEYBOARD_INPUT_VALUE: [u8; 2] = [KEYBOARD_REPORT_ID, ReportType::Input as u8];
+const KEYBOARD_OUTPUT_VALUE: [u8; 2] = [KEYBOARD_REPORT_ID, ReportType::Output as u8];
+const CONSUMER_INPUT_VALUE: [u8; 2] = [CONSUMER_REPORT_ID, ReportType::Input as u8];
+const MOUSE_INPUT_VALUE: [u8; 2] = [MOUSE_REPORT_ID, ReportType::Input as u8];
+const RAW_HID_INPUT_VALUE: [u8; 2] = [RAW_HID_REPORT_ID, ReportType::Input as u8];
+const RAW_HID_OUTPUT_VALUE: [u8; 2] = [RAW_HID_REPORT_ID, ReportType::Output as u8];
+const BOOT_INPUT_REPORT_VALUE: [u8; BOOT_INPUT_REPORT_SIZE] = [0; BOOT_INPUT_REPORT_SIZE];
 const BOOT_OUTPUT_REPORT_VALUE: [u8; 1] = [0; 1];
 const HID_INFORMATION_VALUE: [u8; 4] = [USB_HID_SPEC_VERSION as u8, (USB_HID_SPEC_VERSION >> 8) as u8, COUNTRY_CODE, FLAGS];
 const CONTROL_POINT_VALUE: [u8; 1] = [0; 1];
+const PROTOCOL_MODE_VALUE: [u8; 1] = [ProtocolMode::Report as u8];
 
 const COUNTRY_CODE: u8 = 0;
 const HID_INFO_FLAG_REMOTE_WAKE_MSK: u8 = 0x1;
@@ -74,84 +134,124 @@ pub struct HidService {
     #[characteristic(
         uuid = "2A4D",
         initial_value = "NO_DATA",
-        security = "justworks",
+        security = "mitm",
         read,
         write,
         notify,
-        descriptor(uuid = "2908", security = "justworks", value = "INPUT_VALUE")
+        descriptor(uuid = "2908", security = "mitm", value = "KEYBOARD_INPUT_VALUE")
     )]
-    pub input_report: [u8; 8],
+    pub input_report: [u8; KEYBOARD_INPUT_REPORT_SIZE],
     #[characteristic(
         uuid = "2A4D",
         initial_value = "NO_DATA",
-        security = "justworks",
+        security = "mitm",
         read,
         write,
         write_without_response,
-        descriptor(uuid = "2908", security = "justworks", value = "OUTPUT_VALUE")
+        descriptor(uuid = "2908", security = "mitm", value = "KEYBOARD_OUTPUT_VALUE")
     )]
-    pub output_report: [u8; 1],
+    pub output_report: [u8; KEYBOARD_OUTPUT_REPORT_SIZE],
     #[characteristic(
         uuid = "2A4D",
         initial_value = "NO_DATA",
-        security = "justworks",
+        security = "mitm",
         read,
         write,
-        descriptor(uuid = "2908", security = "justworks", value = "FEATURE_VALUE")
+        notify,
+        descriptor(uuid = "2908", security = "mitm", value = "CONSUMER_INPUT_VALUE")
     )]
-    pub feature_report: [u8; 1],
-    #[characteristic(uuid = "2A4B", initial_value = "MAP_DATA", security = "justworks", read)]
-    pub report_map: [u8; MAP_DATA.len()],
+    pub consumer_input_report: [u8; CONSUMER_INPUT_REPORT_SIZE],
+    #[characteristic(
+        uuid = "2A4D",
+        initial_value = "NO_DATA",
+        security = "mitm",
+        read,
+        write,
+        notify,
+        descriptor(uuid = "2908", security = "mitm", value = "MOUSE_INPUT_VALUE")
+    )]
+    pub mouse_input_report: [u8; MOUSE_INPUT_REPORT_SIZE],
+    #[characteristic(
+        uuid = "2A4D",
+        initial_value = "NO_DATA",
+        security = "mitm",
+        read,
+        notify,
+        descriptor(uuid = "2908", security = "mitm", value = "RAW_HID_INPUT_VALUE")
+    )]
+    pub raw_input_report: RawHidReport,
     #[characteristic(
-        uuid = "2A22",
-        initial_value = "BOOT_INPUT_REPORT_VALUE",
-        security = "justworks",
+        uuid = "2A4D",
+        initial_value = "NO_DATA",
+        security = "mitm",
         read,
         write,
-        notify
+        write_without_response,
+        descriptor(uuid = "2908", security = "mitm", value = "RAW_HID_OUTPUT_VALUE")
     )]
-    pub boot_input_report: [u8; 8],
+    pub raw_output_report: RawHidReport,
+    #[characteristic(uuid = "2A4B", initial_value = "MAP_DATA", security = "mitm", read)]
+    pub report_map: [u8; MAP_DATA.len()],
+    #[characteristic(uuid = "2A22", initial_value = "BOOT_INPUT_REPORT_VALUE", security = "mitm", read, write, notify)]
+    pub boot_input_report: [u8; BOOT_INPUT_REPORT_SIZE],
     #[characteristic(
         uuid = "2A32",
         initial_value = "BOOT_OUTPUT_REPORT_VALUE",
-        security = "justworks",
+        security = "mitm",
         read,
         write,
         write_without_response
     )]
     pub boot_output_report: [u8; 1],
-    #[characteristic(uuid = "2A4A", initial_value = "HID_INFORMATION_VALUE", security = "justworks", read)]
+    #[characteristic(uuid = "2A4A", initial_value = "HID_INFORMATION_VALUE", security = "mitm", read)]
     pub hid_information: [u8; 4],
+    #[characteristic(uuid = "2A4C", initial_value = "CONTROL_POINT_VALUE", security = "mitm", write_without_response)]
+    pub control_point: u8,
     #[characteristic(
-        uuid = "2A4C",
-        initial_value = "CONTROL_POINT_VALUE",
-        security = "justworks",
+        uuid = "2A4E",
+        initial_value = "PROTOCOL_MODE_VALUE",
+        security = "mitm",
+        read,
         write_without_response
     )]
-    pub control_point: u8,
+    pub protocol_mode: u8,
 }
 
 #[nrf_softdevice::gatt_server]
 pub struct Server {
-    pub battery_service: BatteryService,
+    pub left_battery_service: LeftBatteryService,
+    pub right_battery_service: RightBatteryService,
     pub device_information_service: DeviceInformationService,
     pub hid_service: HidService,
 }
 
+// UUID of the master service in little endian. The halves advertise it to find
+// each other.
+pub const MASTER_SERVICE_UUID: [u8; 16] = [
+    0x02, 0x00, 0x12, 0xac, 0x42, 0x02, 0xea, 0xb5, 0xed, 0x11, 0x9e, 0xde, 0xbc, 0xf8, 0x7e, 0x5a,
+];
+
+// Size of the candidate the right half sends while the halves elect the master.
+pub const CANDIDATE_MESSAGE_SIZE: usize = message_size::<crate::split::Candidate>();
+
 #[nrf_softdevice::gatt_service(uuid = "5a7ef8bc-de9e-11ed-b5ea-0242ac120002")]
 pub struct MasterService {
-    #[characteristic(uuid = "66762370-de9e-11ed-b5ea-0242ac120002", read, write)]
-    pub other_random_number: u32,
-    #[characteristic(uuid = "734e5e64-de9e-11ed-b5ea-0242ac120002", read)]
+    #[characteristic(uuid = "66762370-de9e-11ed-b5ea-0242ac120002", security = "justworks", read, write)]
+    pub other_candidate: WireMessage<CANDIDATE_MESSAGE_SIZE>,
+    #[characteristic(uuid = "734e5e64-de9e-11ed-b5ea-0242ac120002", security = "justworks", read)]
     pub is_master: bool,
+    #[characteristic(uuid = "8f4b2a3e-de9e-11ed-b5ea-0242ac120002", security = "justworks", read)]
+    pub identity: [u8; IDENTITY_SIZE],
 }
 
 #[nrf_softdevice::gatt_client(uuid = "5a7ef8bc-de9e-11ed-b5ea-0242ac120002")]
 pub struct MasterServiceClient {
     #[characteristic(uuid = "66762370-de9e-11ed-b5ea-0242ac120002", read, write)]
-    pub other_random_number: u32,
+    pub other_candidate: WireMessage<CANDIDATE_MESSAGE_SIZE>,
     #[characteristic(uuid = "734e5e64-de9e-11ed-b5ea-0242ac120002", read)]
     pub is_master: bool,
+    #[characteristic(uuid = "8f4b2a3e-de9e-11ed-b5ea-0242ac120002", read)]
+    pub identity: [u8; IDENTITY_SIZE],
 }
 
 #[nrf_softdevice::gatt_server]
@@ -159,74 +259,24 @@ pub struct MasterServer {
     pub master_service: MasterService,
 }
 
+// Everything the halves send each other is multiplexed over a single
+// characteristic. Frames are encoded with the wire format, so halves with
+// mismatched firmware can not misinterpret each other.
+pub const SPLIT_FRAME_SIZE: usize = message_size::<crate::split::Frame>();
+
 #[nrf_softdevice::gatt_service(uuid = "c78c4d70-e02d-11ed-b5ea-0242ac120002")]
-pub struct KeyStateService {
-    #[characteristic(uuid = "d8004dfa-e02d-11ed-b5ea-0242ac120002", write)]
-    pub key_state: u64,
+pub struct TransportService {
+    #[characteristic(uuid = "d8004dfa-e02d-11ed-b5ea-0242ac120002", security = "justworks", write)]
+    pub frame: WireMessage<SPLIT_FRAME_SIZE>,
 }
 
 #[nrf_softdevice::gatt_client(uuid = "c78c4d70-e02d-11ed-b5ea-0242ac120002")]
-pub struct KeyStateServiceClient {
+pub struct TransportServiceClient {
     #[characteristic(uuid = "d8004dfa-e02d-11ed-b5ea-0242ac120002", write)]
-    pub key_state: u64,
-}
-
-#[nrf_softdevice::gatt_service(uuid = "fe027f36-e7e0-11ed-a05b-0242ac120003")]
-pub struct FlashService {
-    #[characteristic(uuid = "0b257fe2-e7e1-11ed-a05b-0242ac120003", write)]
-    pub flash_operation: crate::flash::FlashOperation,
-}
-
-#[nrf_softdevice::gatt_client(uuid = "fe027f36-e7e0-11ed-a05b-0242ac120003")]
-pub struct FlashServiceClient {
-    #[characteristic(uuid = "0b257fe2-e7e1-11ed-a05b-0242ac120003", write)]
-    pub flash_operation: crate::flash::FlashOperation,
-}
-
-#[nrf_softdevice::gatt_service(uuid = "fd05a94e-ff89-11ed-be56-0242ac120002")]
-pub struct PowerService {
-    #[characteristic(uuid = "080986a8-ff8a-11ed-be56-0242ac120002", write)]
-    pub power_operation: crate::power::PowerOperation,
-}
-
-#[nrf_softdevice::gatt_client(uuid = "fd05a94e-ff89-11ed-be56-0242ac120002")]
-pub struct PowerServiceClient {
-    #[characteristic(uuid = "080986a8-ff8a-11ed-be56-0242ac120002", write)]
-    pub power_operation: crate::power::PowerOperation,
-}
-
-#[cfg(feature = "lighting")]
-#[nrf_softdevice::gatt_service(uuid = "01c5abb0-ee81-11ed-a05b-0242ac120003")]
-pub struct LightingService {
-    #[characteristic(uuid = "0bad19c4-ee81-11ed-a05b-0242ac120003", write)]
-    pub lighting_operation: crate::led::LightingOperation,
-}
-
-#[cfg(feature = "lighting")]
-#[nrf_softdevice::gatt_client(uuid = "01c5abb0-ee81-11ed-a05b-0242ac120003")]
-pub struct LightingServiceClient {
-    #[characteristic(uuid = "0bad19c4-ee81-11ed-a05b-0242ac120003", write)]
-    pub lighting_operation: crate::led::LightingOperation,
-}
-
-#[nrf_softdevice::gatt_service(uuid = "465b52c4-f0c6-11ed-a05b-0242ac120003")]
-pub struct EventService {
-    #[characteristic(uuid = "579bce56-f0c6-11ed-a05b-0242ac120003", write)]
-    pub event: crate::split::UsedEvent,
-}
-
-#[nrf_softdevice::gatt_client(uuid = "465b52c4-f0c6-11ed-a05b-0242ac120003")]
-pub struct EventServiceClient {
-    #[characteristic(uuid = "579bce56-f0c6-11ed-a05b-0242ac120003", write)]
-    pub event: crate::split::UsedEvent,
+    pub frame: WireMessage<SPLIT_FRAME_SIZE>,
 }
 
 #[nrf_softdevice::gatt_server]
 pub struct CommunicationServer {
-    pub key_state_service: KeyStateService,
-    pub flash_service: FlashService,
-    pub power_service: PowerService,
-    #[cfg(feature = "lighting")]
-    pub lighting_service: LightingService,
-    pub event_service: EventService,
+    pub transport_service: TransportService,
 }
@@ -0,0 +1,76 @@
+use heapless::Vec;
+
+use super::{Bonder, KeyboardReport, KEYBOARD_INPUT_REPORT_SIZE};
+use crate::keys::{typed_digit, Modifiers, BACKSPACE, ENTER, ESC, KPBACKSPACE, KPENTER};
+
+// Passkeys always have six digits.
+const PASSKEY_LENGTH: usize = 6;
+
+const MODIFIER_POSITION: usize = 0;
+const SCAN_CODE_POSITION: usize = 2;
+
+/// Collects the passkey that is typed on the keyboard while a host pairs with
+/// passkey entry. Digits are entered in the active layout, backspace removes
+/// the last digit, enter submits the passkey and escape rejects the pairing.
+pub struct PasskeyInput {
+    digits: Vec<u8, PASSKEY_LENGTH>,
+    previous_report: KeyboardReport,
+}
+
+impl PasskeyInput {
+    pub const fn new() -> Self {
+        Self {
+            digits: Vec::new(),
+            previous_report: [0; KEYBOARD_INPUT_REPORT_SIZE],
+        }
+    }
+
+    /// Handle the keys pressed since the last report and reply to the host
+    /// once the passkey is submitted or rejected.
+    pub fn update(&mut self, bonder: &Bonder, report: &KeyboardReport) {
+        let modifiers = Modifiers::from_bits_truncate(report[MODIFIER_POSITION]);
+        let previous_keycodes = &self.previous_report[SCAN_CODE_POSITION..];
+
+        for keycode in report[SCAN_CODE_POSITION..]
+            .iter()
+            .copied()
+            .filter(|keycode| *keycode != 0 && !previous_keycodes.contains(keycode))
+        {
+            match keycode {
+                _ if keycode == ESC.get_value() => {
+                    defmt::info!("Passkey entry cancelled");
+                    self.digits.clear();
+                    bonder.reply_passkey(None);
+                }
+                _ if keycode == BACKSPACE.get_value() || keycode == KPBACKSPACE.get_value() => {
+                    let _ = self.digits.pop();
+                }
+                _ if keycode == ENTER.get_value() || keycode == KPENTER.get_value() => {
+                    match <[u8; PASSKEY_LENGTH]>::try_from(self.digits.as_slice()) {
+                        Ok(passkey) => {
+                            defmt::debug!("Submitting passkey");
+                            self.digits.clear();
+                            bonder.reply_passkey(Some(&passkey));
+                        }
+                        Err(..) => defmt::warn!(
+                            "Passkey needs {} digits, but only {} were entered",
+                            PASSKEY_LENGTH,
+                            self.digits.len()
+                        ),
+                    }
+                }
+                _ => match typed_digit(keycode, modifiers) {
+                    // The softdevice expects the passkey as ASCII digits.
+                    Some(digit) => {
+                        if self.digits.push(b'0' + digit).is_err() {
+                            defmt::warn!("Passkey already has {} digits", PASSKEY_LENGTH);
+                        }
+                    }
+                    None => defmt::trace!("Ignoring keycode {} during passkey entry", keycode),
+                },
+            }
+        }
+
+        self.previous_report = *report;
+    }
+}
@@ -0,0 +1,122 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use futures::future::{select, Either};
+use futures::pin_mut;
+
+use crate::flash::{get_settings, BondSlot, FlashToken};
+use crate::interface::Keyboard;
+
+// Every bond slot acts as a host profile. Only the host of the selected profile
+// receives input reports. Pairing a new host selects its profile once it
+// bonds.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub enum ProfileRequest {
+    Select(BondSlot),
+    Pair(BondSlot),
+}
+
+static PROFILE_REQUESTS: Signal<ThreadModeRawMutex, ProfileRequest> = Signal::new();
+static PAIRING_MODE_REQUESTS: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+// Slot that new bonds are stored in. If this is `None`, the keyboard is not in
+// pairing mode and new bonds are refused.
+static PAIRING_SLOT: Mutex<ThreadModeRawMutex, Cell<Option<BondSlot>>> = Mutex::new(Cell::new(None));
+
+// Connection handle of the host that bonded in pairing mode and the slot its
+// bond was stored in.
+static BONDED_HOSTS: Signal<ThreadModeRawMutex, (u16, BondSlot)> = Signal::new();
+
+fn is_valid_slot(slot: BondSlot) -> bool {
+    slot.0 < <crate::Used as Keyboard>::MAXIMUM_BONDS
+}
+
+fn request_profile(request: ProfileRequest) {
+    let (ProfileRequest::Select(slot) | ProfileRequest::Pair(slot)) = request;
+
+    if !is_valid_slot(slot) {
+        defmt::warn!("Ignoring profile request for invalid slot {}", slot);
+        return;
+    }
+
+    PROFILE_REQUESTS.signal(request);
+}
+
+/// Only connect to the host bonded in the given slot.
+pub fn select_profile(slot: BondSlot) {
+    request_profile(ProfileRequest::Select(slot));
+}
+
+/// Pair a new host and store the bond in the given slot, replacing the
+/// previous bond.
+pub fn pair_profile(slot: BondSlot) {
+    request_profile(ProfileRequest::Pair(slot));
+}
+
+/// Enter pairing mode. The new host is stored in a free slot or replaces the
+/// bond that was not used for the longest time.
+pub fn enter_pairing_mode() {
+    PAIRING_MODE_REQUESTS.signal(());
+}
+
+pub async fn profile_request(flash_token: FlashToken) -> ProfileRequest {
+    let profile_future = PROFILE_REQUESTS.wait();
+    let pairing_mode_future = PAIRING_MODE_REQUESTS.wait();
+
+    pin_mut!(profile_future);
+    pin_mut!(pairing_mode_future);
+
+    match select(profile_future, pairing_mode_future).await {
+        Either::Left((request, _)) => request,
+        Either::Right(..) => {
+            let settings = get_settings(flash_token);
+            let slot = settings
+                .bonds
+                .iter()
+                .position(|bond| !bond.is_used())
+                .map(BondSlot)
+                .unwrap_or_else(|| {
+                    let slot = settings.least_recently_used_bond();
+                    defmt::warn!("No free slot for a new bond, replacing bond in slot {}", slot);
+                    slot
+                });
+
+            ProfileRequest::Pair(slot)
+        }
+    }
+}
+
+/// Get the profile that was last selected.
+pub fn stored_profile(flash_token: FlashToken) -> BondSlot {
+    let slot = get_settings(flash_token).active_profile;
+
+    // The settings might not have been written before.
+    match is_valid_slot(slot) {
+        true => slot,
+        false => BondSlot(0),
+    }
+}
+
+/// Get the slot that a new host is paired into. Returns `None` if the keyboard
+/// is not in pairing mode.
+pub fn pairing_slot() -> Option<BondSlot> {
+    PAIRING_SLOT.lock(|pairing_slot| pairing_slot.get())
+}
+
+pub fn set_pairing_slot(slot: Option<BondSlot>) {
+    PAIRING_SLOT.lock(|pairing_slot| pairing_slot.set(slot));
+}
+
+/// Report that the host with the given connection handle bonded in the given
+/// slot, which ends pairing mode.
+pub fn host_bonded(handle: u16, slot: BondSlot) {
+    BONDED_HOSTS.signal((handle, slot));
+}
+
+/// Wait until a host bonds. Returns the connection handle of the host and the
+/// slot of the bond.
+pub async fn bonded_host() -> (u16, BondSlot) {
+    BONDED_HOSTS.wait().await
+}
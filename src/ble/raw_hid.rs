@@ -0,0 +1,153 @@
+use super::RAW_HID_REPORT_SIZE;
+use crate::flash::BondSlot;
+use crate::interface::Keyboard;
+#[cfg(feature = "lighting")]
+use crate::led::{Led, LedIndex};
+
+pub type RawHidReport = [u8; RAW_HID_REPORT_SIZE];
+
+// Requests from the host are framed as [command, length, payload..] and
+// responses as [command, status, length, payload..].
+const REQUEST_HEADER_SIZE: usize = 2;
+const RESPONSE_HEADER_SIZE: usize = 3;
+
+// Commands from this value upwards are passed on to the keyboard.
+const BOARD_COMMAND_OFFSET: u8 = 0x80;
+
+const GET_LAYER: u8 = 0x01;
+const GET_BATTERY: u8 = 0x02;
+const TRIGGER_CALLBACK: u8 = 0x03;
+#[cfg(feature = "lighting")]
+const SET_LED: u8 = 0x04;
+const GET_BOND: u8 = 0x05;
+const GET_CONNECTION: u8 = 0x06;
+const GET_REPORT_STATISTICS: u8 = 0x07;
+
+/// Implemented by types that can be decoded from the payload of a raw HID
+/// request.
+pub trait FromRawHid: Sized {
+    fn from_raw_hid(payload: &[u8]) -> Option<Self>;
+}
+
+impl FromRawHid for ! {
+    fn from_raw_hid(_payload: &[u8]) -> Option<Self> {
+        None
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum RawHidStatus {
+    Ok = 0,
+    UnknownCommand = 1,
+    InvalidPayload = 2,
+    ResponseTooLong = 3,
+    NotConnected = 4,
+}
+
+pub enum HostRequest {
+    GetLayer,
+    GetBattery,
+    TriggerCallback(<crate::Used as Keyboard>::Callbacks),
+    #[cfg(feature = "lighting")]
+    SetLed {
+        index: LedIndex,
+        color: Led,
+    },
+    GetBond(BondSlot),
+    GetConnection,
+    GetReportStatistics,
+    Board(<crate::Used as Keyboard>::HostCommands),
+}
+
+impl HostRequest {
+    /// Returns the command of the request and the decoded request.
+    pub fn parse(report: &RawHidReport) -> (u8, Result<Self, RawHidStatus>) {
+        let command = report[0];
+        let length = report[1] as usize;
+
+        let Some(payload) = report.get(REQUEST_HEADER_SIZE..REQUEST_HEADER_SIZE + length) else {
+            return (command, Err(RawHidStatus::InvalidPayload));
+        };
+
+        let request = match command {
+            GET_LAYER => Ok(Self::GetLayer),
+            GET_BATTERY => Ok(Self::GetBattery),
+            TRIGGER_CALLBACK => FromRawHid::from_raw_hid(payload)
+                .map(Self::TriggerCallback)
+                .ok_or(RawHidStatus::InvalidPayload),
+            #[cfg(feature = "lighting")]
+            SET_LED => match payload {
+                [index, red, green, blue] => FromRawHid::from_raw_hid(&[*index])
+                    .map(|index| Self::SetLed {
+                        index,
+                        color: Led::rgb(*red as f32 / 255.0, *green as f32 / 255.0, *blue as f32 / 255.0),
+                    })
+                    .ok_or(RawHidStatus::InvalidPayload),
+                _ => Err(RawHidStatus::InvalidPayload),
+            },
+            GET_BOND => match payload {
+                [slot] if (*slot as usize) < <crate::Used as Keyboard>::MAXIMUM_BONDS => Ok(Self::GetBond(BondSlot(*slot as usize))),
+                _ => Err(RawHidStatus::InvalidPayload),
+            },
+            GET_CONNECTION => Ok(Self::GetConnection),
+            GET_REPORT_STATISTICS => Ok(Self::GetReportStatistics),
+            BOARD_COMMAND_OFFSET..=u8::MAX => {
+                // Pass the board command (without the offset) to the keyboard, followed by the
+                // payload.
+                let mut board_payload = heapless::Vec::<u8, RAW_HID_REPORT_SIZE>::new();
+                let _ = board_payload.push(command - BOARD_COMMAND_OFFSET);
+                let _ = board_payload.extend_from_slice(payload);
+
+                FromRawHid::from_raw_hid(&board_payload)
+                    .map(Self::Board)
+                    .ok_or(RawHidStatus::InvalidPayload)
+            }
+            _ => Err(RawHidStatus::UnknownCommand),
+        };
+
+        (command, request)
+    }
+}
+
+/// Response to a raw HID request.
+pub struct RawHidResponse {
+    data: RawHidReport,
+    length: usize,
+    status: RawHidStatus,
+}
+
+impl RawHidResponse {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; RAW_HID_REPORT_SIZE],
+            length: 0,
+            status: RawHidStatus::Ok,
+        }
+    }
+
+    /// Append data to the response. If the data does not fit, the status of
+    /// the response is set to [`RawHidStatus::ResponseTooLong`].
+    pub fn push(&mut self, data: &[u8]) {
+        let start = RESPONSE_HEADER_SIZE + self.length;
+
+        match self.data.get_mut(start..start + data.len()) {
+            Some(slice) => {
+                slice.copy_from_slice(data);
+                self.length += data.len();
+            }
+            None => self.status = RawHidStatus::ResponseTooLong,
+        }
+    }
+
+    pub fn set_status(&mut self, status: RawHidStatus) {
+        self.status = status;
+    }
+
+    pub fn finish(mut self, command: u8) -> RawHidReport {
+        self.data[0] = command;
+        self.data[1] = self.status as u8;
+        self.data[2] = self.length as u8;
+        self.data
+    }
+}
@@ -0,0 +1,281 @@
+use core::cell::{Cell, RefCell};
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Deque;
+use nrf_softdevice::ble::gatt_server::NotifyValueError;
+use nrf_softdevice::ble::Connection;
+use nrf_softdevice::RawError;
+
+use super::{Hosts, ProtocolMode, Server, KEYBOARD_INPUT_REPORT_SIZE};
+
+pub type KeyboardReport = [u8; KEYBOARD_INPUT_REPORT_SIZE];
+
+const MODIFIER_POSITION: usize = 0;
+const SCAN_CODE_POSITION: usize = 2;
+const REPORT_QUEUE_SIZE: usize = 16;
+
+// Time to wait before trying again if the notification queue of the softdevice
+// is full.
+const RETRY_DELAY: Duration = Duration::from_millis(5);
+
+// Time to wait before sending a release report again after it failed for a
+// reason other than a full notification queue, and how often to try.
+const RELEASE_RETRY_DELAY: Duration = Duration::from_millis(50);
+const RELEASE_RETRIES: u32 = 20;
+
+// Reports that take longer than this to be sent are counted as delayed.
+const DELAY_THRESHOLD: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct ReportStatistics {
+    /// Number of reports sent to the host.
+    pub sent: u32,
+    /// Number of reports that were merged into another report before being
+    /// sent.
+    pub coalesced: u32,
+    /// Number of reports that could not be sent on the first try or took longer
+    /// than expected to be sent.
+    pub delayed: u32,
+    /// Number of reports that were dropped.
+    pub lost: u32,
+}
+
+#[derive(Clone, Copy)]
+struct QueuedReport {
+    report: KeyboardReport,
+    // Whether this report releases any key or modifier compared to the report
+    // queued before it.
+    is_release: bool,
+    queued_at: Instant,
+}
+
+struct ReportQueue {
+    reports: Mutex<ThreadModeRawMutex, RefCell<Deque<QueuedReport, REPORT_QUEUE_SIZE>>>,
+    // The last report that was queued. Used to detect redundant reports and
+    // releases.
+    last_report: Mutex<ThreadModeRawMutex, Cell<KeyboardReport>>,
+    statistics: Mutex<ThreadModeRawMutex, Cell<ReportStatistics>>,
+    report_queued: Signal<ThreadModeRawMutex, ()>,
+    report_sent: Signal<ThreadModeRawMutex, ()>,
+}
+
+static REPORT_QUEUE: ReportQueue = ReportQueue {
+    reports: Mutex::new(RefCell::new(Deque::new())),
+    last_report: Mutex::new(Cell::new([0; KEYBOARD_INPUT_REPORT_SIZE])),
+    statistics: Mutex::new(Cell::new(ReportStatistics {
+        sent: 0,
+        coalesced: 0,
+        delayed: 0,
+        lost: 0,
+    })),
+    report_queued: Signal::new(),
+    report_sent: Signal::new(),
+};
+
+// Check if every key and modifier that is pressed in `previous` is still
+// pressed in `next`.
+fn is_superset(next: &KeyboardReport, previous: &KeyboardReport) -> bool {
+    let modifiers_held = next[MODIFIER_POSITION] & previous[MODIFIER_POSITION] == previous[MODIFIER_POSITION];
+    let keys_held = previous[SCAN_CODE_POSITION..]
+        .iter()
+        .filter(|keycode| **keycode != 0)
+        .all(|keycode| next[SCAN_CODE_POSITION..].contains(keycode));
+
+    modifiers_held && keys_held
+}
+
+fn update_statistics(update: impl FnOnce(&mut ReportStatistics)) -> ReportStatistics {
+    REPORT_QUEUE.statistics.lock(|statistics| {
+        let mut updated = statistics.get();
+        update(&mut updated);
+        statistics.set(updated);
+        updated
+    })
+}
+
+pub fn report_statistics() -> ReportStatistics {
+    REPORT_QUEUE.statistics.lock(|statistics| statistics.get())
+}
+
+/// Queue a keyboard report to be sent to the host. Reports that only press
+/// additional keys may be merged with the previous report, but reports that
+/// release keys are never dropped.
+pub async fn queue_report(report: KeyboardReport) {
+    enum QueueResult {
+        Queued,
+        Coalesced,
+        Full,
+    }
+
+    loop {
+        let last_report = REPORT_QUEUE.last_report.lock(|last_report| last_report.get());
+
+        // The host already has (or will have) the same state, so there is nothing to
+        // do.
+        if report == last_report {
+            return;
+        }
+
+        let is_release = !is_superset(&report, &last_report);
+
+        let result = REPORT_QUEUE.reports.lock(|reports| {
+            let mut reports = reports.borrow_mut();
+
+            // If the last queued report only pressed keys and this report does so as well,
+            // we can safely replace the queued report, since the host will still see every
+            // key being pressed.
+            if let Some(queued) = reports.back_mut() {
+                if !queued.is_release && !is_release {
+                    queued.report = report;
+                    return QueueResult::Coalesced;
+                }
+            }
+
+            match reports.push_back(QueuedReport {
+                report,
+                is_release,
+                queued_at: Instant::now(),
+            }) {
+                Ok(()) => QueueResult::Queued,
+                Err(..) => QueueResult::Full,
+            }
+        });
+
+        match result {
+            QueueResult::Queued => {}
+            QueueResult::Coalesced => {
+                update_statistics(|statistics| statistics.coalesced += 1);
+            }
+            QueueResult::Full => {
+                // Wait for the queue to drain instead of dropping the report, since it
+                // might be a release.
+                defmt::warn!("Report queue is full, waiting for reports to be sent");
+                REPORT_QUEUE.report_sent.wait().await;
+                continue;
+            }
+        }
+
+        REPORT_QUEUE.last_report.lock(|last_report| last_report.set(report));
+        REPORT_QUEUE.report_queued.signal(());
+        return;
+    }
+}
+
+/// Drop all pending reports. This should be called when a new host connects,
+/// since it does not know about any previous state.
+pub fn clear_reports() {
+    let dropped = REPORT_QUEUE.reports.lock(|reports| {
+        let mut reports = reports.borrow_mut();
+        let dropped = reports.len();
+        reports.clear();
+        dropped
+    });
+
+    REPORT_QUEUE
+        .last_report
+        .lock(|last_report| last_report.set([0; KEYBOARD_INPUT_REPORT_SIZE]));
+    REPORT_QUEUE.report_queued.reset();
+
+    if dropped > 0 {
+        let statistics = update_statistics(|statistics| statistics.lost += dropped as u32);
+        defmt::warn!("Dropped {} pending reports. Report statistics: {}", dropped, statistics);
+    }
+}
+
+fn notify_report(
+    server: &Server,
+    connection: &Connection,
+    protocol_mode: ProtocolMode,
+    report: &KeyboardReport,
+) -> Result<(), NotifyValueError> {
+    // The boot keyboard input report has the same layout as our input report, so
+    // we only need to pick the right characteristic.
+    match protocol_mode {
+        ProtocolMode::Boot => server.hid_service.boot_input_report_notify(connection, report),
+        ProtocolMode::Report => server.hid_service.input_report_notify(connection, report),
+    }
+}
+
+/// Release all keys on a host that no longer receives input reports, so no
+/// keys are stuck after switching to another host.
+pub fn release_all_keys(server: &Server, connection: &Connection, protocol_mode: ProtocolMode) {
+    if let Err(error) = notify_report(server, connection, protocol_mode, &[0; KEYBOARD_INPUT_REPORT_SIZE]) {
+        defmt::warn!("Failed to release keys on previous host: {:?}", error);
+    }
+}
+
+/// Send queued reports to the selected host. Never returns.
+pub async fn run_report_queue(server: &Server, hosts: &Hosts) -> ! {
+    loop {
+        // Take the report out of the queue before sending it, so it can not be
+        // coalesced with newer reports while we are waiting for the softdevice.
+        let next = REPORT_QUEUE.reports.lock(|reports| reports.borrow_mut().pop_front());
+
+        let Some(queued) = next else {
+            REPORT_QUEUE.report_queued.wait().await;
+            continue;
+        };
+
+        // If the selected host is not connected, there is nobody to send the report
+        // to.
+        let Some((connection, protocol_mode)) = hosts.active_connection() else {
+            REPORT_QUEUE.report_sent.signal(());
+            continue;
+        };
+
+        defmt::info!("Sending input report with value {:?}", queued.report);
+
+        let mut retried = false;
+        let mut release_retries = 0;
+
+        let sent = loop {
+            match notify_report(server, &connection, protocol_mode, &queued.report) {
+                Ok(()) => break true,
+                Err(NotifyValueError::Raw(RawError::Resources)) => {
+                    // The notification queue of the softdevice is full, so we wait for it to
+                    // drain and try again.
+                    retried = true;
+                    Timer::after(RETRY_DELAY).await;
+                }
+                Err(error) => {
+                    // Every report carries the full key state, so a newer report releases the
+                    // keys as well. Without one, dropping a release would leave keys stuck on
+                    // the host, so we try again for a while as long as it is connected.
+                    let superseded = REPORT_QUEUE.reports.lock(|reports| !reports.borrow().is_empty());
+                    let connected = !matches!(error, NotifyValueError::Disconnected);
+
+                    if queued.is_release && !superseded && connected && release_retries < RELEASE_RETRIES {
+                        defmt::warn!("Failed to send release report: {:?}, trying again", error);
+                        release_retries += 1;
+                        retried = true;
+                        Timer::after(RELEASE_RETRY_DELAY).await;
+                        continue;
+                    }
+
+                    let statistics = update_statistics(|statistics| statistics.lost += 1);
+                    defmt::error!("Failed to send input report: {:?}. Report statistics: {}", error, statistics);
+                    break false;
+                }
+            }
+        };
+
+        REPORT_QUEUE.report_sent.signal(());
+
+        if !sent {
+            continue;
+        }
+
+        let delayed = retried || queued.queued_at.elapsed() > DELAY_THRESHOLD;
+        let statistics = update_statistics(|statistics| {
+            statistics.sent += 1;
+            statistics.delayed += delayed as u32;
+        });
+
+        if delayed {
+            defmt::warn!("Input report was delayed. Report statistics: {}", statistics);
+        }
+    }
+}
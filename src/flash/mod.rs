@@ -2,12 +2,14 @@ mod operation;
 mod settings;
 
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::{Channel, Receiver, Sender};
+use embassy_sync::channel::{Channel, Sender};
 use nrf_softdevice::ble::{Address, EncryptionInfo, IdentityKey, MasterId};
 
 pub use self::operation::*;
 pub use self::settings::{flash_task, get_settings, initialize_flash, FlashToken};
+use crate::ble::{is_static_address, IDENTITY_SIZE};
 use crate::interface::Keyboard;
+use crate::split::Wire;
 
 // The Bluetooth address 00:00:00:00:00:00 is technically valid but rarely used
 // because it is known to cause problems with most operating systems. So we
@@ -16,26 +18,31 @@ pub const NO_ADDRESS: Address = Address { flags: 0, bytes: [0; 6] };
 
 const FLASH_CHANNEL_SIZE: usize = 10;
 
+// Maximum number of bytes of the host name that are stored for every bond.
+pub const BOND_NAME_SIZE: usize = 20;
+
 static FLASH_OPERATIONS: Channel<ThreadModeRawMutex, FlashOperation, FLASH_CHANNEL_SIZE> = Channel::new();
-static SLAVE_FLASH_OPERATIONS: Channel<ThreadModeRawMutex, FlashOperation, FLASH_CHANNEL_SIZE> = Channel::new();
 
 pub type FlashSender = Sender<'static, ThreadModeRawMutex, FlashOperation, FLASH_CHANNEL_SIZE>;
-pub type OtherFlashReceiver = Receiver<'static, ThreadModeRawMutex, FlashOperation, FLASH_CHANNEL_SIZE>;
 
 pub fn flash_sender() -> FlashSender {
     FLASH_OPERATIONS.sender()
 }
 
-pub fn other_flash_receiver() -> OtherFlashReceiver {
-    SLAVE_FLASH_OPERATIONS.receiver()
-}
-
 #[repr(C)]
-#[derive(Clone, Copy, Debug, defmt::Format)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format, Wire)]
+#[wire(validate = "BondSlot::is_valid")]
 pub struct BondSlot(pub usize);
 
+impl BondSlot {
+    fn is_valid(&self) -> bool {
+        self.0 < <crate::Used as Keyboard>::MAXIMUM_BONDS
+    }
+}
+
 #[repr(C)]
-#[derive(Clone, Copy, Debug, defmt::Format)]
+#[derive(Clone, Copy, Debug, defmt::Format, Wire)]
+#[wire(validate = "SystemAttributes::is_valid")]
 pub struct SystemAttributes {
     pub length: usize,
     pub data: [u8; 64],
@@ -45,26 +52,115 @@ impl SystemAttributes {
     pub const fn new() -> Self {
         Self { length: 0, data: [0; 64] }
     }
+
+    fn is_valid(&self) -> bool {
+        self.length <= self.data.len()
+    }
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy, defmt::Format)]
+#[derive(Debug, Clone, Copy, defmt::Format, Wire)]
 pub struct Peer {
     pub master_id: MasterId,
     pub key: EncryptionInfo,
     pub peer_id: IdentityKey,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, defmt::Format, Wire)]
+#[wire(validate = "BondMetadata::is_valid")]
+pub struct BondMetadata {
+    // Value of the connection counter when the host last connected. There is no
+    // real time clock, so this only tells us the order in which the hosts
+    // connected.
+    pub last_connected: u32,
+    pub name_length: usize,
+    pub name: [u8; BOND_NAME_SIZE],
+}
+
+impl BondMetadata {
+    pub const fn new(last_connected: u32) -> Self {
+        Self {
+            last_connected,
+            name_length: 0,
+            name: [0; BOND_NAME_SIZE],
+        }
+    }
+
+    /// Set the name of the host. Names that are too long are truncated.
+    pub fn set_name(&mut self, name: &[u8]) {
+        self.name_length = name.len().min(BOND_NAME_SIZE);
+        self.name[..self.name_length].copy_from_slice(&name[..self.name_length]);
+    }
+
+    fn is_valid(&self) -> bool {
+        self.name_length <= BOND_NAME_SIZE
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_length.min(BOND_NAME_SIZE)]
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, defmt::Format)]
 pub struct Bond {
     pub peer: Peer,
     pub system_attributes: SystemAttributes,
+    pub metadata: BondMetadata,
+}
+
+impl Bond {
+    pub fn is_used(&self) -> bool {
+        self.peer.peer_id.addr != NO_ADDRESS
+    }
 }
 
 #[repr(C)]
 #[derive(Clone, defmt::Format)]
 pub struct Settings {
     pub bonds: [Bond; <crate::Used as Keyboard>::MAXIMUM_BONDS],
+    pub active_profile: BondSlot,
+    // The other half of the keyboard. Halves only connect to their partner once
+    // they are paired.
+    pub partner: Peer,
+    // Number of times this half was elected master. The halves compare it to
+    // take turns being the master.
+    pub master_elections: u32,
+    // Identity that the keyboard presents to hosts, as received from the left
+    // half. Lets the right half serve hosts on its own. All zeros if unknown.
+    pub identity: [u8; IDENTITY_SIZE],
     pub board_flash: <crate::Used as Keyboard>::BoardFlash,
 }
+
+impl Settings {
+    /// Get the other half if the halves are paired. Flash that was never
+    /// written does not hold a valid address, so the halves count as
+    /// unpaired.
+    pub fn partner(&self) -> Option<&Peer> {
+        is_static_address(&self.partner.peer_id.addr).then_some(&self.partner)
+    }
+
+    /// Get the value of the connection counter for the next host that
+    /// connects.
+    pub fn next_connection_counter(&self) -> u32 {
+        self.bonds
+            .iter()
+            .filter(|bond| bond.is_used())
+            .map(|bond| bond.metadata.last_connected)
+            .max()
+            .map_or(0, |counter| counter.wrapping_add(1))
+    }
+
+    /// Get the slot of the bond that was not used for the longest time.
+    pub fn least_recently_used_bond(&self) -> BondSlot {
+        let slot = self
+            .bonds
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, bond)| bond.metadata.last_connected)
+            .map_or(0, |(slot, _)| slot);
+
+        BondSlot(slot)
+    }
+}
@@ -1,11 +1,10 @@
-use nrf_softdevice::ble::FixedGattValue;
-
-use super::{BondSlot, Peer, SystemAttributes, FLASH_OPERATIONS, SLAVE_FLASH_OPERATIONS};
+use super::{BondMetadata, BondSlot, Peer, SystemAttributes, FLASH_OPERATIONS};
+use crate::ble::IDENTITY_SIZE;
 use crate::interface::Keyboard;
 use crate::side::Side;
+use crate::split::{send_to_other_half, try_send_to_other_half, SplitMessage, Wire};
 
-#[repr(C)]
-#[derive(Clone, defmt::Format)]
+#[derive(Clone, defmt::Format, Wire)]
 pub enum FlashOperation {
     StorePeer {
         slot: BondSlot,
@@ -15,32 +14,27 @@ pub enum FlashOperation {
         slot: BondSlot,
         system_attributes: SystemAttributes,
     },
+    StoreBondMetadata {
+        slot: BondSlot,
+        metadata: BondMetadata,
+    },
     RemoveBond(BondSlot),
+    ClearBonds,
+    StoreActiveProfile(BondSlot),
+    StorePartner(Peer),
+    StoreMasterElections(u32),
+    StoreIdentity([u8; IDENTITY_SIZE]),
     StoreBoardFlash(<crate::Used as Keyboard>::BoardFlash),
     ResetPersistentData,
 }
 
-impl FixedGattValue for FlashOperation {
-    const SIZE: usize = core::mem::size_of::<FlashOperation>();
-
-    fn from_gatt(data: &[u8]) -> Self {
-        let mut buffer = [0; Self::SIZE];
-        buffer.copy_from_slice(data);
-        unsafe { core::mem::transmute::<&[u8; Self::SIZE], &FlashOperation>(&buffer).clone() }
-    }
-
-    fn to_gatt(&self) -> &[u8] {
-        unsafe { core::mem::transmute::<&FlashOperation, &[u8; Self::SIZE]>(self) }
-    }
-}
-
 async fn queue_inner(side: Side, operation: FlashOperation) {
     if side.includes_this() {
         FLASH_OPERATIONS.send(operation.clone()).await;
     }
 
     if side.includes_other() {
-        SLAVE_FLASH_OPERATIONS.send(operation).await;
+        send_to_other_half(SplitMessage::Flash(operation)).await;
     }
 }
 
@@ -48,6 +42,26 @@ pub async fn remove_bond(side: Side, slot: BondSlot) {
     queue_inner(side, FlashOperation::RemoveBond(slot)).await;
 }
 
+pub async fn clear_bonds(side: Side) {
+    queue_inner(side, FlashOperation::ClearBonds).await;
+}
+
+pub async fn store_bond_metadata(side: Side, slot: BondSlot, metadata: BondMetadata) {
+    queue_inner(side, FlashOperation::StoreBondMetadata { slot, metadata }).await;
+}
+
+pub async fn store_active_profile(side: Side, slot: BondSlot) {
+    queue_inner(side, FlashOperation::StoreActiveProfile(slot)).await;
+}
+
+pub async fn store_master_elections(side: Side, master_elections: u32) {
+    queue_inner(side, FlashOperation::StoreMasterElections(master_elections)).await;
+}
+
+pub async fn store_identity(side: Side, identity: [u8; IDENTITY_SIZE]) {
+    queue_inner(side, FlashOperation::StoreIdentity(identity)).await;
+}
+
 pub async fn store_board_flash(side: Side, board_flash: <crate::Used as Keyboard>::BoardFlash) {
     queue_inner(side, FlashOperation::StoreBoardFlash(board_flash)).await;
 }
@@ -63,8 +77,8 @@ fn try_queue_inner(side: Side, operation: FlashOperation) {
         defmt::error!("Failed to send flash operation to flash task");
     }
 
-    if side.includes_other() && SLAVE_FLASH_OPERATIONS.try_send(operation).is_err() {
-        defmt::error!("Failed to send flash operation to slave");
+    if side.includes_other() && !try_send_to_other_half(SplitMessage::Flash(operation)) {
+        defmt::error!("Failed to send flash operation to the other half");
     }
 }
 
@@ -72,6 +86,18 @@ pub fn try_store_peer(side: Side, slot: BondSlot, peer: Peer) {
     try_queue_inner(side, FlashOperation::StorePeer { slot, peer });
 }
 
+pub fn try_store_partner(side: Side, peer: Peer) {
+    try_queue_inner(side, FlashOperation::StorePartner(peer));
+}
+
+pub fn try_remove_bond(side: Side, slot: BondSlot) {
+    try_queue_inner(side, FlashOperation::RemoveBond(slot));
+}
+
+pub fn try_store_bond_metadata(side: Side, slot: BondSlot, metadata: BondMetadata) {
+    try_queue_inner(side, FlashOperation::StoreBondMetadata { slot, metadata });
+}
+
 pub fn try_store_system_attributes(side: Side, slot: BondSlot, system_attributes: SystemAttributes) {
     try_queue_inner(side, FlashOperation::StoreSystemAttributes { slot, system_attributes });
 }
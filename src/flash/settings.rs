@@ -21,11 +21,17 @@ struct ReservedFlash {
     _data: [u8; nvmc::PAGE_SIZE * <crate::Used as Keyboard>::SETTINGS_PAGES],
 }
 
+// Version of the layout of the settings. Needs to be increased whenever the
+// layout changes, so settings of an older firmware are not misread. Erased
+// flash never matches, since it reads as all ones.
+const SETTINGS_VERSION: u32 = 1;
+
 // The flash can only write full words, so we need to pad the settings with up
 // to 3 bytes.
 #[repr(C)]
 #[derive(Clone, defmt::Format)]
 struct AlignedSettings {
+    pub version: u32,
     pub settings: Settings,
     pub padding: [u8; 3 - ((core::mem::size_of::<Settings>() - 1) % 4)],
 }
@@ -56,9 +62,30 @@ pub async fn initialize_flash(flash: &mut Flash) -> FlashToken {
     let mut buffer = [0u8; core::mem::size_of::<AlignedSettings>()];
     defmt::unwrap!(flash.read(address, &mut buffer).await);
 
-    // Save to static variable so that other tasks can read from it.
     let settings = unsafe { core::mem::transmute::<&[u8; core::mem::size_of::<AlignedSettings>()], &AlignedSettings>(&buffer) };
-    unsafe { SETTINGS.write(settings.clone()) };
+    let mut settings = settings.clone();
+
+    // Settings that were written with another layout can not be read, so we start
+    // over with the default settings.
+    if settings.version != SETTINGS_VERSION {
+        defmt::warn!(
+            "Settings have version {} instead of {}, resetting them",
+            settings.version,
+            SETTINGS_VERSION
+        );
+
+        settings = unsafe { MaybeUninit::zeroed().assume_init() };
+        settings.version = SETTINGS_VERSION;
+
+        let bytes = unsafe { core::mem::transmute::<&AlignedSettings, &[u8; core::mem::size_of::<AlignedSettings>()]>(&settings) };
+        let erase_end_address = address + core::mem::size_of::<ReservedFlash>() as u32;
+
+        defmt::unwrap!(flash.erase(address, erase_end_address).await);
+        defmt::unwrap!(flash.write(address, bytes).await);
+    }
+
+    // Save to static variable so that other tasks can read from it.
+    unsafe { SETTINGS.write(settings) };
 
     // Return a FlashToken that can be used to access the settings.
     FlashToken { address }
@@ -118,6 +145,13 @@ pub async fn flash_task(flash: Flash, token: FlashToken) {
                         // need to erase the section before writing.
                         apply_flags |= ApplyFlags::ERASE_AND_WRITE;
                     }
+                    FlashOperation::StoreBondMetadata { slot, metadata } => {
+                        aligned.settings.bonds[slot.0].metadata = metadata;
+
+                        // Since we are potentially trying to set bits to 1 that are currently 0, we
+                        // need to erase the section before writing.
+                        apply_flags |= ApplyFlags::ERASE_AND_WRITE;
+                    }
                     FlashOperation::RemoveBond(slot) => {
                         if aligned.settings.bonds[slot.0].peer.peer_id.addr != NO_ADDRESS {
                             aligned.settings.bonds[slot.0] = unsafe { MaybeUninit::zeroed().assume_init() };
@@ -127,6 +161,45 @@ pub async fn flash_task(flash: Flash, token: FlashToken) {
                             apply_flags |= ApplyFlags::WRITE;
                         }
                     }
+                    FlashOperation::ClearBonds => {
+                        aligned.settings.bonds = unsafe { MaybeUninit::zeroed().assume_init() };
+
+                        // Since all we are doing is setting the bits of all bonds to 0, we can write
+                        // without erasing first.
+                        apply_flags |= ApplyFlags::WRITE;
+                    }
+                    // Every profile selection ends up here, even if it selects the profile that is
+                    // already active, so only touch the flash if the slot actually changed. Quick
+                    // successive selections are written once after the apply time.
+                    FlashOperation::StoreActiveProfile(slot) if aligned.settings.active_profile != slot => {
+                        aligned.settings.active_profile = slot;
+
+                        // Since we are potentially trying to set bits to 1 that are currently 0, we
+                        // need to erase the section before writing.
+                        apply_flags |= ApplyFlags::ERASE_AND_WRITE;
+                    }
+                    FlashOperation::StoreActiveProfile(..) => {}
+                    FlashOperation::StorePartner(peer) => {
+                        aligned.settings.partner = peer;
+
+                        // Since we are potentially trying to set bits to 1 that are currently 0, we
+                        // need to erase the section before writing.
+                        apply_flags |= ApplyFlags::ERASE_AND_WRITE;
+                    }
+                    FlashOperation::StoreMasterElections(master_elections) => {
+                        aligned.settings.master_elections = master_elections;
+
+                        // Since we are potentially trying to set bits to 1 that are currently 0, we
+                        // need to erase the section before writing.
+                        apply_flags |= ApplyFlags::ERASE_AND_WRITE;
+                    }
+                    FlashOperation::StoreIdentity(identity) => {
+                        aligned.settings.identity = identity;
+
+                        // Since we are potentially trying to set bits to 1 that are currently 0, we
+                        // need to erase the section before writing.
+                        apply_flags |= ApplyFlags::ERASE_AND_WRITE;
+                    }
                     FlashOperation::StoreBoardFlash(board_flash) => {
                         aligned.settings.board_flash = board_flash;
 
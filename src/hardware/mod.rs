@@ -1,6 +1,7 @@
 use embassy_cortex_m::interrupt::Interrupt;
 use embassy_nrf::gpio::{AnyPin, Input, Level, Output, OutputDrive, Pull};
 use embassy_nrf::interrupt;
+use embassy_time::{Duration, Timer};
 
 use crate::interface::UnwrapInfelliable;
 use crate::keys::Modifiers;
@@ -74,6 +75,30 @@ pub struct MatrixPins<'a, const C: usize, const R: usize> {
     pub rows: [Input<'a, AnyPin>; R],
 }
 
+// Interval at which the matrix is checked for key presses while the radio is
+// stopped.
+const KEY_PRESS_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+impl<'a, const C: usize, const R: usize> MatrixPins<'a, C, R> {
+    /// Wait until any key on this half is pressed.
+    pub async fn wait_for_key_press(&mut self) {
+        loop {
+            // All columns are driven at once, since it does not matter which key is
+            // pressed. They are released before waiting, so the regular scan is not
+            // disturbed if this future is dropped.
+            self.columns.iter_mut().for_each(|column| column.set_high());
+            let pressed = self.rows.iter().any(|row| row.is_high());
+            self.columns.iter_mut().for_each(|column| column.set_low());
+
+            if pressed {
+                return;
+            }
+
+            Timer::after(KEY_PRESS_POLL_INTERVAL).await;
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ActiveLayer {
     pub layer_index: usize,
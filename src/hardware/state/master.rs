@@ -1,13 +1,13 @@
-use embassy_time::driver::now;
-
 use super::KeyState;
-use crate::flash::{remove_bond, reset_persistent_data};
+use crate::ble::{enter_pairing_mode, pair_profile, select_profile};
+use crate::flash::{clear_bonds, remove_bond, reset_persistent_data};
 use crate::hardware::{ActiveLayer, ActiveModifier, BitOperations, DebouncedKey};
 use crate::interface::{Keyboard, KeyboardExtension, Scannable};
 use crate::keys::{Mapping, Modifiers};
 #[cfg(feature = "lighting")]
 use crate::led::set_animation;
 use crate::power::set_power_state;
+use crate::split::{is_standalone, request_handover};
 
 // TODO: make fileds private?
 pub struct MasterState {
@@ -19,6 +19,7 @@ pub struct MasterState {
     pub slave_raw_state: u64,
     pub state_mask: u64,
     pub lock_mask: u64,
+    pub last_event_time: u64,
 }
 
 impl KeyState for MasterState {
@@ -47,20 +48,50 @@ impl MasterState {
             slave_raw_state: 0,
             state_mask: !0,
             lock_mask: 0,
+            last_event_time: 0,
         }
     }
 
+    /// Get the raw state of the keys on both sides.
+    pub fn raw_key_state(&self) -> u64 {
+        #[cfg(feature = "left")]
+        return self.slave_raw_state | (self.master_raw_state << <crate::Used as KeyboardExtension>::KEYS_PER_SIDE);
+
+        #[cfg(feature = "right")]
+        return (self.slave_raw_state << <crate::Used as KeyboardExtension>::KEYS_PER_SIDE) | self.master_raw_state;
+    }
+
     pub fn current_layer_index(&self) -> usize {
-        self.active_layers.last().map(|layer| layer.layer_index).unwrap_or(0)
+        self.active_layers
+            .last()
+            .map_or_else(Self::base_layer_index, |layer| layer.layer_index)
+    }
+
+    // Layer that is active if no other layer is held. While the master runs on its
+    // own, the fallback layer of the keyboard replaces the base layer.
+    fn base_layer_index() -> usize {
+        match is_standalone() {
+            true => <crate::Used as Keyboard>::STANDALONE_LAYER,
+            false => 0,
+        }
     }
 
+    /// Apply a change of the key state. The time is when the change happened in
+    /// ticks, so hold-taps are resolved by when keys were actually pressed and
+    /// released, not when the change arrived. Changes of the slave that arrive
+    /// after a later change of the master are applied at the time of the
+    /// latest change, so time never goes backwards.
     pub async fn apply(
         &mut self,
         keyboard: &mut crate::Used,
         mut key_state: u64,
+        event_time: u64,
     ) -> Option<(heapless::Vec<ActiveModifier, 8>, usize, u64, u64)> {
         let mut injected_keys = 0;
 
+        let event_time = event_time.max(self.last_event_time);
+        self.last_event_time = event_time;
+
         // TODO: make key_state immutable and copy to modify instead.
         let saved_state = key_state;
 
@@ -76,7 +107,8 @@ impl MasterState {
                 false => {
                     // Check if we want to execute the tap action for this layer (if
                     // present).
-                    if matches!(active_layer.tap_timer, Some(time) if now() - time < <crate::Used as Keyboard>::TAP_TIME) {
+                    if matches!(active_layer.tap_timer.and_then(|time| event_time.checked_sub(time)), Some(elapsed) if elapsed < <crate::Used as Keyboard>::TAP_TIME)
+                    {
                         injected_keys.set_bit(key_index);
                     }
 
@@ -106,7 +138,8 @@ impl MasterState {
             if !key_state.test_bit(key_index) {
                 // Check if we want to execute the tap action for this key (if
                 // present).
-                if matches!(self.active_modifiers[index].tap_timer, Some(time) if now() - time < <crate::Used as Keyboard>::TAP_TIME) {
+                if matches!(self.active_modifiers[index].tap_timer.and_then(|time| event_time.checked_sub(time)), Some(elapsed) if elapsed < <crate::Used as Keyboard>::TAP_TIME)
+                {
                     injected_keys.set_bit(key_index);
                 }
 
@@ -148,9 +181,24 @@ impl MasterState {
                                     crate::keys::SpecialAction::RemoveBond { side, bond_slot } => {
                                         remove_bond(*side, *bond_slot).await;
                                     }
+                                    crate::keys::SpecialAction::ClearBonds { side } => {
+                                        clear_bonds(*side).await;
+                                    }
                                     crate::keys::SpecialAction::ResetPersistentData { side } => {
                                         reset_persistent_data(*side).await;
                                     }
+                                    crate::keys::SpecialAction::SelectProfile(bond_slot) => {
+                                        select_profile(*bond_slot);
+                                    }
+                                    crate::keys::SpecialAction::PairProfile(bond_slot) => {
+                                        pair_profile(*bond_slot);
+                                    }
+                                    crate::keys::SpecialAction::PairingMode => {
+                                        enter_pairing_mode();
+                                    }
+                                    crate::keys::SpecialAction::Handover => {
+                                        request_handover();
+                                    }
                                     #[cfg(feature = "lighting")]
                                     crate::keys::SpecialAction::SetAnimation { side, index, animation } => {
                                         set_animation(*side, index.clone(), animation.clone()).await;
@@ -183,11 +231,11 @@ impl MasterState {
                     Mapping::HoldTap(hold_action, _) => match hold_action {
                         crate::keys::HoldAction::Layer(layer_index) => StackAction::Layer {
                             index: *layer_index,
-                            time: Some(now()),
+                            time: Some(event_time),
                         },
                         crate::keys::HoldAction::Modifier(modifier) => StackAction::Modifier {
                             value: *modifier,
-                            time: Some(now()),
+                            time: Some(event_time),
                         },
                     },
                 };
@@ -1,13 +1,14 @@
 use embassy_nrf::Peripherals;
 use embassy_time::Duration;
-use nrf_softdevice::ble::{Address, AddressType};
 
 use crate::battery::Voltage;
+use crate::ble::{FromRawHid, RawHidResponse};
 use crate::flash::FlashToken;
 use crate::hardware::PeripheralConfig;
 use crate::keys::Mapping;
 #[cfg(feature = "lighting")]
 use crate::led::{Animation, Led, LedCollection, LedProvider, Speed};
+use crate::split::Wire;
 
 pub trait Scannable {
     const COLUMNS: usize;
@@ -26,18 +27,24 @@ where
     /// Name presented to the connecting device.
     const DEVICE_NAME: &'static [u8];
 
-    /// Key mappings.
-    const LAYER_LOOKUP: &'static [&'static [Mapping; Self::COLUMNS * Self::ROWS * 2]];
+    /// Manufacturer name reported in the device information service.
+    const MANUFACTURER_NAME: &'static [u8] = b"Butterware";
 
-    /// Bluetooth address of the left side before establishing a master.
-    const LEFT_ADDRESS: Address = Address::new(AddressType::Public, [6, 2, 3, 4, 5, 9]);
+    /// Revision of the board reported in the device information service.
+    const HARDWARE_REVISION: &'static [u8] = b"1.0";
 
-    /// Bluetooth address of the right side before establishing a master.
-    const RIGHT_ADDRESS: Address = Address::new(AddressType::Public, [7, 2, 3, 4, 5, 9]);
+    /// USB vendor id reported to hosts in the PnP ID. Defaults to the vendor id
+    /// of pid.codes, which is shared between open source projects.
+    const VENDOR_ID: u16 = 0x1209;
 
-    /// Bluetooth address that the master will present itself with to other
-    /// devices.
-    const ADDRESS: Address = Address::new(AddressType::Public, [8, 2, 3, 4, 5, 9]);
+    /// USB product id reported to hosts in the PnP ID.
+    const PRODUCT_ID: u16 = 0x0001;
+
+    /// Version of the product reported to hosts in the PnP ID.
+    const PRODUCT_VERSION: u16 = 0x0100;
+
+    /// Key mappings.
+    const LAYER_LOOKUP: &'static [&'static [Mapping; Self::COLUMNS * Self::ROWS * 2]];
 
     /// 32768 Ticks per second on the nice!nano. 100 Ticks is around 3
     /// milliseconds.
@@ -55,6 +62,15 @@ where
     /// Maximum number of bonds that can be stored on the keyboard.
     const MAXIMUM_BONDS: usize = 10;
 
+    /// Time after which the keyboard leaves pairing mode if no new host
+    /// connected.
+    const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Indices of the keys that enter pairing mode if they are held while the
+    /// keyboard starts. Pairing mode can only be entered with a special action
+    /// if this is empty.
+    const PAIRING_KEYS: &'static [usize] = &[];
+
     #[cfg(feature = "lighting")]
     const STATUS_LEDS: <<Self::Leds as LedProvider>::Collection as LedCollection>::Index;
 
@@ -78,6 +94,15 @@ where
         color: Led::rgb(0.0, 0.0, 0.0),
     };
 
+    /// Animation played on the master side while the keyboard is in pairing
+    /// mode.
+    #[cfg(feature = "lighting")]
+    const PAIRING_ANIMATION: Animation = Animation::Pulsate {
+        color: Led::rgb(0.0, 0.0, 1.0),
+        speed: Speed(2.0),
+        offset: 0.0,
+    };
+
     /// Animation played when the halves disconnected and the auto-reset feature
     /// is not enabled.
     #[cfg(feature = "lighting")]
@@ -85,6 +110,35 @@ where
         color: Led::rgb(1.0, 0.0, 0.0),
     };
 
+    /// Time without any key changes after which hosts are asked for connection
+    /// parameters that save power.
+    const CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Time that hosts are advertised to with a short interval, before
+    /// switching to a long interval.
+    const FAST_ADVERTISING_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Time that hosts are advertised to with a long interval, before
+    /// advertising stops until a key is pressed.
+    const SLOW_ADVERTISING_TIMEOUT: Duration = Duration::from_secs(300);
+
+    /// Time that the halves search for each other with a short interval, before
+    /// switching to a long interval.
+    const FAST_SEARCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Time that the halves search for each other with a long interval, before
+    /// the search stops until a key on the half is pressed.
+    const SLOW_SEARCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+    /// Time after starting that a half searches for the other half, before it
+    /// becomes the master on its own. The other half can still join later.
+    const STANDALONE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Layer that replaces the base layer while the master runs on its own.
+    /// It can map important keys of the missing half onto the available one.
+    /// Defaults to the base layer.
+    const STANDALONE_LAYER: usize = 0;
+
     // Read battery level every 5 minutes.
     const BATTERY_SAMPLE_FREQUENCY: Duration = Duration::from_secs(300);
 
@@ -94,17 +148,25 @@ where
     // Maximum voltage of the battery.
     const BATTERY_MAXIMUM_VOLTAGE: Voltage = Voltage(4.2);
 
+    /// Battery level in percent below which the master hands its role over to
+    /// the slave, if the slave has noticeably more charge.
+    const HANDOVER_BATTERY_LEVEL: u8 = 20;
+
     /// Timeout after which data is written to the flash.
     const FLASH_APPLY_TIME: Duration = Duration::from_secs(3);
 
     /// Persistent data that is stored in the flash.
-    type BoardFlash: Clone + defmt::Format = ();
+    type BoardFlash: Clone + defmt::Format + Wire = ();
 
     /// Custom callbacks defined by the keyboard.
-    type Callbacks: Clone = !;
+    type Callbacks: Clone + FromRawHid = !;
+
+    /// Custom commands that host applications can send to the keyboard over
+    /// the raw HID channel.
+    type HostCommands: FromRawHid = !;
 
     /// Custom events defined by the keyboard.
-    type Events: Clone = !;
+    type Events: Clone + Wire = !;
 
     /// Led configuration.
     #[cfg(feature = "lighting")]
@@ -139,6 +201,12 @@ where
 
     async fn sides_disconnected(&mut self) {}
 
+    /// Function that gets called when the host enters or exits suspend. This
+    /// is a good place to turn off power hungry peripherals like lighting.
+    async fn host_suspended(&mut self, suspended: bool) {
+        let _ = suspended;
+    }
+
     /// Key press callback handler.
     async fn callback(&mut self, callback: Self::Callbacks) {
         let _ = callback;
@@ -150,6 +218,13 @@ where
         let _ = event;
         defmt::warn!("Event handler not defined");
     }
+
+    /// Host command handler. Data pushed to the response is sent back to the
+    /// host application.
+    async fn host_command(&mut self, command: Self::HostCommands, response: &mut RawHidResponse) {
+        let _ = (command, response);
+        defmt::warn!("Host command handler not defined");
+    }
 }
 
 pub trait KeyboardExtension {
@@ -30,32 +30,23 @@ macro_rules! register_callbacks {
                 Mapping::Tap(self.into_tap_action())
             }
         }
+
+        impl crate::ble::FromRawHid for $callbacks {
+            fn from_raw_hid(payload: &[u8]) -> Option<Self> {
+                const VARIANTS: &[$callbacks] = &[$($callbacks::$names),*];
+                VARIANTS.get(*payload.first()? as usize).copied()
+            }
+        }
     };
 }
 
 #[allow(unused_macros)]
 macro_rules! register_events {
     ($board:ident, $events:ident, [$($names:ident),* $(,)?]) => {
-        #[repr(C)]
-        #[derive(Clone, Copy, Debug, defmt::Format, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[derive(Clone, Copy, Debug, defmt::Format, PartialEq, Eq, PartialOrd, Ord, Hash, crate::split::Wire)]
         pub enum $events {
             $($names),*
         }
-
-        impl nrf_softdevice::ble::FixedGattValue for $events {
-            const SIZE: usize = core::mem::size_of::<$events>();
-
-            fn from_gatt(data: &[u8]) -> Self {
-                let mut buffer = [0; Self::SIZE];
-                buffer.copy_from_slice(data);
-                unsafe { core::mem::transmute::<&[u8; Self::SIZE], &$events>(&buffer).clone() }
-            }
-
-            fn to_gatt(&self) -> &[u8] {
-                unsafe { core::mem::transmute::<&$events, &[u8; Self::SIZE]>(self) }
-            }
-        }
-
     };
 }
 
@@ -64,7 +55,7 @@ macro_rules! register_leds {
     ($board:ident, $leds:ident, [$($names:ident: $types:ty,)* $(,)?]) => {
 
         #[repr(C)]
-        #[derive(Clone, Copy, Debug, defmt::Format, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[derive(Clone, Copy, Debug, defmt::Format, PartialEq, Eq, PartialOrd, Ord, Hash, crate::split::Wire)]
         pub enum $leds {
             $($names),*
         }
@@ -78,17 +69,24 @@ macro_rules! register_leds {
             type Collection = GeneratedLedStorage;
         }
 
+        impl crate::ble::FromRawHid for $leds {
+            fn from_raw_hid(payload: &[u8]) -> Option<Self> {
+                const VARIANTS: &[$leds] = &[$($leds::$names),*];
+                VARIANTS.get(*payload.first()? as usize).copied()
+            }
+        }
+
         impl crate::led::LedCollection for GeneratedLedStorage {
             type Index = $leds;
 
-            fn set_animation(&mut self, index: Self::Index, animtaion: Animation) {
+            fn set_animation(&mut self, index: Self::Index, animtaion: Animation, animation_time: f64) {
                 match index {
-                    $(Self::Index::$names => crate::led::LedDriver::set_animation(&mut self.$names, animtaion)),*
+                    $(Self::Index::$names => crate::led::LedDriver::set_animation(&mut self.$names, animtaion, animation_time)),*
                 }
             }
 
-            async fn update(&mut self, elapsed_time: f32) {
-                $(crate::led::LedDriver::update(&mut self.$names, elapsed_time).await;)*
+            async fn update(&mut self, elapsed_time: f32, animation_time: f64) {
+                $(crate::led::LedDriver::update(&mut self.$names, elapsed_time, animation_time).await;)*
             }
         }
 
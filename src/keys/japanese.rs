@@ -0,0 +1,79 @@
+use super::*;
+
+pub const JP_ZKHK: Key = GRAVE; // Zenkaku ↔ Hankaku ↔ Kanji (半角 ↔ 全角 ↔ 漢字)
+pub const JP_1: Key = N1; // 1
+pub const JP_2: Key = N2; // 2
+pub const JP_3: Key = N3; // 3
+pub const JP_4: Key = N4; // 4
+pub const JP_5: Key = N5; // 5
+pub const JP_6: Key = N6; // 6
+pub const JP_7: Key = N7; // 7
+pub const JP_8: Key = N8; // 8
+pub const JP_9: Key = N9; // 9
+pub const JP_0: Key = N0; // 0
+pub const JP_MINS: Key = MINUS; // -
+pub const JP_CIRC: Key = EQUAL; // ^
+pub const JP_YEN: Key = INTERNATIONAL3; // ¥
+pub const JP_Q: Key = Q; // Q
+pub const JP_W: Key = W; // W
+pub const JP_E: Key = E; // E
+pub const JP_R: Key = R; // R
+pub const JP_T: Key = T; // T
+pub const JP_Y: Key = Y; // Y
+pub const JP_U: Key = U; // U
+pub const JP_I: Key = I; // I
+pub const JP_O: Key = O; // O
+pub const JP_P: Key = P; // P
+pub const JP_AT: Key = LEFTBRACE; // @
+pub const JP_LBRC: Key = RIGHTBRACE; // [
+pub const JP_EISU: Key = CAPSLOCK; // Eisū (英数)
+pub const JP_A: Key = A; // A
+pub const JP_S: Key = S; // S
+pub const JP_D: Key = D; // D
+pub const JP_F: Key = F; // F
+pub const JP_G: Key = G; // G
+pub const JP_H: Key = H; // H
+pub const JP_J: Key = J; // J
+pub const JP_K: Key = K; // K
+pub const JP_L: Key = L; // L
+pub const JP_SCLN: Key = SEMICOLON; // ;
+pub const JP_COLN: Key = APOSTROPHE; // :
+pub const JP_RBRC: Key = HASHTILDE; // ]
+pub const JP_Z: Key = Z; // Z
+pub const JP_X: Key = X; // X
+pub const JP_C: Key = C; // C
+pub const JP_V: Key = V; // V
+pub const JP_B: Key = B; // B
+pub const JP_N: Key = N; // N
+pub const JP_M: Key = M; // M
+pub const JP_COMM: Key = COMMA; // ,
+pub const JP_DOT: Key = DOT; // .
+pub const JP_SLSH: Key = SLASH; // /
+pub const JP_BSLS: Key = INTERNATIONAL1; // (backslash)
+pub const JP_MHEN: Key = INTERNATIONAL5; // Muhenkan (無変換)
+pub const JP_HENK: Key = INTERNATIONAL4; // Henkan (変換)
+pub const JP_KANA: Key = INTERNATIONAL2; // Katakana ↔ Hiragana ↔ Rōmaji (カタカナ ↔ ひらがな ↔ ローマ字)
+pub const JP_KANA_MAC: Key = LANG1; // Kana (かな) on Apple keyboards
+pub const JP_EISU_MAC: Key = LANG2; // Eisū (英数) on Apple keyboards
+pub const JP_EXLM: Key = JP_1.shift(); // !
+pub const JP_DQUO: Key = JP_2.shift(); // "
+pub const JP_HASH: Key = JP_3.shift(); // #
+pub const JP_DLR: Key = JP_4.shift(); // $
+pub const JP_PERC: Key = JP_5.shift(); // %
+pub const JP_AMPR: Key = JP_6.shift(); // &
+pub const JP_QUOT: Key = JP_7.shift(); // '
+pub const JP_LPRN: Key = JP_8.shift(); // (
+pub const JP_RPRN: Key = JP_9.shift(); // )
+pub const JP_EQL: Key = JP_MINS.shift(); // =
+pub const JP_TILD: Key = JP_CIRC.shift(); // ~
+pub const JP_PIPE: Key = JP_YEN.shift(); // |
+pub const JP_GRV: Key = JP_AT.shift(); // `
+pub const JP_LCBR: Key = JP_LBRC.shift(); // {
+pub const JP_CAPS: Key = JP_EISU.shift(); // Caps Lock
+pub const JP_PLUS: Key = JP_SCLN.shift(); // +
+pub const JP_ASTR: Key = JP_COLN.shift(); // *
+pub const JP_RCBR: Key = JP_RBRC.shift(); // }
+pub const JP_LABK: Key = JP_COMM.shift(); // <
+pub const JP_RABK: Key = JP_DOT.shift(); // >
+pub const JP_QUES: Key = JP_SLSH.shift(); // ?
+pub const JP_UNDS: Key = JP_BSLS.shift(); // _
@@ -6,6 +6,7 @@ use crate::power::PowerState;
 use crate::side::Side;
 
 pub mod german;
+pub mod japanese;
 
 bitflags::bitflags! {
     #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -54,9 +55,16 @@ pub enum SpecialAction {
         side: Side,
         bond_slot: BondSlot,
     },
+    ClearBonds {
+        side: Side,
+    },
     ResetPersistentData {
         side: Side,
     },
+    SelectProfile(BondSlot),
+    PairProfile(BondSlot),
+    PairingMode,
+    Handover,
     SetPower {
         side: Side,
         state: PowerState,
@@ -171,6 +179,25 @@ pub const fn hold_tap(hold: impl ~const IntoHoldAction, tap: impl ~const IntoTap
     Mapping::HoldTap(hold.into_hold_action(), tap.into_tap_action())
 }
 
+/// Get the digit that is typed by a keycode, taking the number row and the
+/// keypad into account. Shifted number keys type symbols in every supported
+/// layout, so they are not digits.
+pub const fn typed_digit(keycode: u8, modifiers: Modifiers) -> Option<u8> {
+    if modifiers.intersects(Modifiers::LSHIFT.union(Modifiers::RSHIFT)) {
+        return None;
+    }
+
+    if keycode >= N1.get_value() && keycode <= N9.get_value() {
+        Some(keycode - N1.get_value() + 1)
+    } else if keycode == N0.get_value() || keycode == KP0.get_value() {
+        Some(0)
+    } else if keycode >= KP1.get_value() && keycode <= KP9.get_value() {
+        Some(keycode - KP1.get_value() + 1)
+    } else {
+        None
+    }
+}
+
 pub const MOD_LCTRL: Modifiers = Modifiers::LCTRL;
 pub const MOD_LSHIFT: Modifiers = Modifiers::LSHIFT;
 pub const MOD_LALT: Modifiers = Modifiers::LALT;
@@ -190,8 +217,8 @@ pub const MOD_RMETA: Modifiers = Modifiers::RMETA;
 
 pub const NONE: Key = Key::from_keycode(0x00); // No key pressed
 pub const ERR_OVF: Key = Key::from_keycode(0x01); //  Keyboard Error Roll Over - used for all slots if too many keys are pressed ("Phantom key")
-// 0x02 //  Keycodeboard POST Fail
-// 0x03 //  Keycodeboard Error Undefined
+pub const ERR_POSTFAIL: Key = Key::from_keycode(0x02); // Keyboard POST Fail
+pub const ERR_UNDEFINED: Key = Key::from_keycode(0x03); // Keyboard Error Undefined
 pub const A: Key = Key::from_keycode(0x04); // Keyboard a and A
 pub const B: Key = Key::from_keycode(0x05); // Keyboard b and B
 pub const C: Key = Key::from_keycode(0x06); // Keyboard c and C
@@ -293,7 +320,7 @@ pub const KP8: Key = Key::from_keycode(0x60); // Keypad 8 and Up Arrow
 pub const KP9: Key = Key::from_keycode(0x61); // Keypad 9 and Page Up
 pub const KP0: Key = Key::from_keycode(0x62); // Keypad 0 and Insert
 pub const KPDOT: Key = Key::from_keycode(0x63); // Keypad . and Delete
-pub const NONUS_BACKSLASH: Key = Key::from_keycode(0x64);
+pub const NONUS_BACKSLASH: Key = Key::from_keycode(0x64); // Keyboard Non-US \ and |
 
 pub const COMPOSE: Key = Key::from_keycode(0x65); // Keyboard Application
 pub const POWER: Key = Key::from_keycode(0x66); // Keyboard Power
@@ -326,88 +353,100 @@ pub const FIND: Key = Key::from_keycode(0x7e); // Keyboard Find
 pub const MUTE: Key = Key::from_keycode(0x7f); // Keyboard Mute
 pub const VOLUMEUP: Key = Key::from_keycode(0x80); // Keyboard Volume Up
 pub const VOLUMEDOWN: Key = Key::from_keycode(0x81); // Keyboard Volume Down
-// 0x82  Keycodeboard Locking Caps Lock
-// 0x83  Keycodeboard Locking Num Lock
-// 0x84  Keycodeboard Locking Scroll Lock
+pub const LOCKING_CAPSLOCK: Key = Key::from_keycode(0x82); // Keyboard Locking Caps Lock
+pub const LOCKING_NUMLOCK: Key = Key::from_keycode(0x83); // Keyboard Locking Num Lock
+pub const LOCKING_SCROLLLOCK: Key = Key::from_keycode(0x84); // Keyboard Locking Scroll Lock
 pub const KPCOMMA: Key = Key::from_keycode(0x85); // Keypad Comma
-// 0x86  Keycodepad Equal Sign
+pub const KPEQUAL_AS400: Key = Key::from_keycode(0x86); // Keypad Equal Sign
 pub const RO: Key = Key::from_keycode(0x87); // Keyboard International1
 pub const KATAKANAHIRAGANA: Key = Key::from_keycode(0x88); // Keyboard International2
 pub const YEN: Key = Key::from_keycode(0x89); // Keyboard International3
 pub const HENKAN: Key = Key::from_keycode(0x8a); // Keyboard International4
 pub const MUHENKAN: Key = Key::from_keycode(0x8b); // Keyboard International5
 pub const KPJPCOMMA: Key = Key::from_keycode(0x8c); // Keyboard International6
-// 0x8d  Keycodeboard International7
-// 0x8e  Keycodeboard International8
-// 0x8f  Keycodeboard International9
+pub const INTERNATIONAL7: Key = Key::from_keycode(0x8d); // Keyboard International7
+pub const INTERNATIONAL8: Key = Key::from_keycode(0x8e); // Keyboard International8
+pub const INTERNATIONAL9: Key = Key::from_keycode(0x8f); // Keyboard International9
 pub const HANGEUL: Key = Key::from_keycode(0x90); // Keyboard LANG1
 pub const HANJA: Key = Key::from_keycode(0x91); // Keyboard LANG2
 pub const KATAKANA: Key = Key::from_keycode(0x92); // Keyboard LANG3
 pub const HIRAGANA: Key = Key::from_keycode(0x93); // Keyboard LANG4
 pub const ZENKAKUHANKAKU: Key = Key::from_keycode(0x94); // Keyboard LANG5
-// 0x95  Keycodeboard LANG6
-// 0x96  Keycodeboard LANG7
-// 0x97  Keycodeboard LANG8
-// 0x98  Keycodeboard LANG9
-// 0x99  Keycodeboard Alternate Erase
-// 0x9a  Keycodeboard SysReq/Attention
-// 0x9b  Keycodeboard Cancel
-// 0x9c  Keycodeboard Clear
-// 0x9d  Keycodeboard Prior
-// 0x9e  Keycodeboard Return
-// 0x9f  Keycodeboard Separator
-// 0xa0  Keycodeboard Out
-// 0xa1  Keycodeboard Oper
-// 0xa2  Keycodeboard Clear/Again
-// 0xa3  Keycodeboard CrSel/Props
-// 0xa4  Keycodeboard ExSel
-
-// 0xb0  Keycodepad 00
-// 0xb1  Keycodepad 000
-// 0xb2  Thousands Separator
-// 0xb3  Decimal Separator
-// 0xb4  Currency Unit
-// 0xb5  Currency Sub-unit
+pub const LANG6: Key = Key::from_keycode(0x95); // Keyboard LANG6
+pub const LANG7: Key = Key::from_keycode(0x96); // Keyboard LANG7
+pub const LANG8: Key = Key::from_keycode(0x97); // Keyboard LANG8
+pub const LANG9: Key = Key::from_keycode(0x98); // Keyboard LANG9
+pub const ALTERASE: Key = Key::from_keycode(0x99); // Keyboard Alternate Erase
+pub const SYSREQ: Key = Key::from_keycode(0x9a); // Keyboard SysReq/Attention
+pub const CANCEL: Key = Key::from_keycode(0x9b); // Keyboard Cancel
+pub const CLEAR: Key = Key::from_keycode(0x9c); // Keyboard Clear
+pub const PRIOR: Key = Key::from_keycode(0x9d); // Keyboard Prior
+pub const RETURN: Key = Key::from_keycode(0x9e); // Keyboard Return
+pub const SEPARATOR: Key = Key::from_keycode(0x9f); // Keyboard Separator
+pub const OUT: Key = Key::from_keycode(0xa0); // Keyboard Out
+pub const OPER: Key = Key::from_keycode(0xa1); // Keyboard Oper
+pub const CLEAR_AGAIN: Key = Key::from_keycode(0xa2); // Keyboard Clear/Again
+pub const CRSEL: Key = Key::from_keycode(0xa3); // Keyboard CrSel/Props
+pub const EXSEL: Key = Key::from_keycode(0xa4); // Keyboard ExSel
+
+pub const KP00: Key = Key::from_keycode(0xb0); // Keypad 00
+pub const KP000: Key = Key::from_keycode(0xb1); // Keypad 000
+pub const THOUSANDS_SEPARATOR: Key = Key::from_keycode(0xb2); // Thousands Separator
+pub const DECIMAL_SEPARATOR: Key = Key::from_keycode(0xb3); // Decimal Separator
+pub const CURRENCY_UNIT: Key = Key::from_keycode(0xb4); // Currency Unit
+pub const CURRENCY_SUBUNIT: Key = Key::from_keycode(0xb5); // Currency Sub-unit
 pub const KPLEFTPAREN: Key = Key::from_keycode(0xb6); // Keypad (
 pub const KPRIGHTPAREN: Key = Key::from_keycode(0xb7); // Keypad )
-// 0xb8  Keycodepad {
-// 0xb9  Keycodepad }
-// 0xba  Keycodepad Tab
-// 0xbb  Keycodepad Backspace
-// 0xbc  Keycodepad A
-// 0xbd  Keycodepad B
-// 0xbe  Keycodepad C
-// 0xbf  Keycodepad D
-// 0xc0  Keycodepad E
-// 0xc1  Keycodepad F
-// 0xc2  Keycodepad XOR
-// 0xc3  Keycodepad ^
-// 0xc4  Keycodepad %
-// 0xc5  Keycodepad <
-// 0xc6  Keycodepad >
-// 0xc7  Keycodepad &
-// 0xc8  Keycodepad &&
-// 0xc9  Keycodepad |
-// 0xca  Keycodepad ||
-// 0xcb  Keycodepad :
-// 0xcc  Keycodepad #
-// 0xcd  Keycodepad Space
-// 0xce  Keycodepad @
-// 0xcf  Keycodepad !
-// 0xd0  Keycodepad Memory Store
-// 0xd1  Keycodepad Memory Recall
-// 0xd2  Keycodepad Memory Clear
-// 0xd3  Keycodepad Memory Add
-// 0xd4  Keycodepad Memory Subtract
-// 0xd5  Keycodepad Memory Multiply
-// 0xd6  Keycodepad Memory Divide
-// 0xd7  Keycodepad +/-
-// 0xd8  Keycodepad Clear
-// 0xd9  Keycodepad Clear Entry
-// 0xda  Keycodepad Binary
-// 0xdb  Keycodepad Octal
-// 0xdc  Keycodepad Decimal
-// 0xdd  Keycodepad Hexadecimal
+pub const KPLEFTBRACE: Key = Key::from_keycode(0xb8); // Keypad {
+pub const KPRIGHTBRACE: Key = Key::from_keycode(0xb9); // Keypad }
+pub const KPTAB: Key = Key::from_keycode(0xba); // Keypad Tab
+pub const KPBACKSPACE: Key = Key::from_keycode(0xbb); // Keypad Backspace
+pub const KPA: Key = Key::from_keycode(0xbc); // Keypad A
+pub const KPB: Key = Key::from_keycode(0xbd); // Keypad B
+pub const KPC: Key = Key::from_keycode(0xbe); // Keypad C
+pub const KPD: Key = Key::from_keycode(0xbf); // Keypad D
+pub const KPE: Key = Key::from_keycode(0xc0); // Keypad E
+pub const KPF: Key = Key::from_keycode(0xc1); // Keypad F
+pub const KPXOR: Key = Key::from_keycode(0xc2); // Keypad XOR
+pub const KPCARET: Key = Key::from_keycode(0xc3); // Keypad ^
+pub const KPPERCENT: Key = Key::from_keycode(0xc4); // Keypad %
+pub const KPLESS: Key = Key::from_keycode(0xc5); // Keypad <
+pub const KPGREATER: Key = Key::from_keycode(0xc6); // Keypad >
+pub const KPAMPERSAND: Key = Key::from_keycode(0xc7); // Keypad &
+pub const KPDOUBLEAMPERSAND: Key = Key::from_keycode(0xc8); // Keypad &&
+pub const KPPIPE: Key = Key::from_keycode(0xc9); // Keypad |
+pub const KPDOUBLEPIPE: Key = Key::from_keycode(0xca); // Keypad ||
+pub const KPCOLON: Key = Key::from_keycode(0xcb); // Keypad :
+pub const KPHASH: Key = Key::from_keycode(0xcc); // Keypad #
+pub const KPSPACE: Key = Key::from_keycode(0xcd); // Keypad Space
+pub const KPAT: Key = Key::from_keycode(0xce); // Keypad @
+pub const KPEXCLAMATION: Key = Key::from_keycode(0xcf); // Keypad !
+pub const KPMEMSTORE: Key = Key::from_keycode(0xd0); // Keypad Memory Store
+pub const KPMEMRECALL: Key = Key::from_keycode(0xd1); // Keypad Memory Recall
+pub const KPMEMCLEAR: Key = Key::from_keycode(0xd2); // Keypad Memory Clear
+pub const KPMEMADD: Key = Key::from_keycode(0xd3); // Keypad Memory Add
+pub const KPMEMSUBTRACT: Key = Key::from_keycode(0xd4); // Keypad Memory Subtract
+pub const KPMEMMULTIPLY: Key = Key::from_keycode(0xd5); // Keypad Memory Multiply
+pub const KPMEMDIVIDE: Key = Key::from_keycode(0xd6); // Keypad Memory Divide
+pub const KPPLUSMINUS: Key = Key::from_keycode(0xd7); // Keypad +/-
+pub const KPCLEAR: Key = Key::from_keycode(0xd8); // Keypad Clear
+pub const KPCLEARENTRY: Key = Key::from_keycode(0xd9); // Keypad Clear Entry
+pub const KPBINARY: Key = Key::from_keycode(0xda); // Keypad Binary
+pub const KPOCTAL: Key = Key::from_keycode(0xdb); // Keypad Octal
+pub const KPDECIMAL: Key = Key::from_keycode(0xdc); // Keypad Decimal
+pub const KPHEXADECIMAL: Key = Key::from_keycode(0xdd); // Keypad Hexadecimal
+
+pub const INTERNATIONAL1: Key = RO; // Keyboard International1
+pub const INTERNATIONAL2: Key = KATAKANAHIRAGANA; // Keyboard International2
+pub const INTERNATIONAL3: Key = YEN; // Keyboard International3
+pub const INTERNATIONAL4: Key = HENKAN; // Keyboard International4
+pub const INTERNATIONAL5: Key = MUHENKAN; // Keyboard International5
+pub const INTERNATIONAL6: Key = KPJPCOMMA; // Keyboard International6
+pub const LANG1: Key = HANGEUL; // Keyboard LANG1
+pub const LANG2: Key = HANJA; // Keyboard LANG2
+pub const LANG3: Key = KATAKANA; // Keyboard LANG3
+pub const LANG4: Key = HIRAGANA; // Keyboard LANG4
+pub const LANG5: Key = ZENKAKUHANKAKU; // Keyboard LANG5
 
 pub const LEFTCTRL: Key = Key::from_keycode(0xe0); // Keyboard Left Control
 pub const LEFTSHIFT: Key = Key::from_keycode(0xe1); // Keyboard Left Shift
@@ -2,36 +2,22 @@ use embassy_nrf::gpio::AnyPin;
 use embassy_nrf::spim::Spim;
 use embassy_nrf::{peripherals, spim};
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::{Channel, Receiver, Sender};
+use embassy_sync::channel::{Channel, Sender};
 use embassy_time::{Duration, Timer};
 use futures::future::{select, Either};
 use futures::pin_mut;
-use nrf_softdevice::ble::FixedGattValue;
 use palette::FromColor;
 
+use crate::ble::FromRawHid;
 use crate::interface::{Keyboard, UnwrapInfelliable};
 use crate::side::Side;
+use crate::split::{send_to_other_half, shared_time, SplitMessage, Wire};
 
-#[repr(C)]
-#[derive(Clone, defmt::Format)]
+#[derive(Clone, defmt::Format, Wire)]
 pub enum LightingOperation {
     SetAnimation { index: LedIndex, animation: Animation },
 }
 
-impl FixedGattValue for LightingOperation {
-    const SIZE: usize = core::mem::size_of::<LightingOperation>();
-
-    fn from_gatt(data: &[u8]) -> Self {
-        let mut buffer = [0; Self::SIZE];
-        buffer.copy_from_slice(data);
-        unsafe { core::mem::transmute::<&[u8; Self::SIZE], &LightingOperation>(&buffer).clone() }
-    }
-
-    fn to_gatt(&self) -> &[u8] {
-        unsafe { core::mem::transmute::<&LightingOperation, &[u8; Self::SIZE]>(self) }
-    }
-}
-
 pub async fn set_animation(side: Side, index: LedIndex, animation: Animation) {
     let lighting_operation = LightingOperation::SetAnimation { index, animation };
 
@@ -40,12 +26,12 @@ pub async fn set_animation(side: Side, index: LedIndex, animation: Animation) {
     }
 
     if side.includes_other() {
-        OTHER_LIGHTING_OPERATIONS.send(lighting_operation).await;
+        send_to_other_half(SplitMessage::Lighting(lighting_operation)).await;
     }
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug, defmt::Format, PartialEq)]
+#[derive(Clone, Copy, Debug, defmt::Format, PartialEq, Wire)]
 pub struct Led {
     red: f32,
     green: f32,
@@ -98,6 +84,14 @@ where
         }
     }
 
+    // The last barrier fades into the current animation, which keeps running
+    // while fading, so it does not jump once the barrier completes.
+    pub fn set_uniform_target(&mut self, led: Led) {
+        if let Some((leds, _)) = self.barriers.last_mut() {
+            *leds = [led; N];
+        }
+    }
+
     pub fn update_barrier(&mut self, elapsed_time: f32) -> bool {
         let complete = if let Some((_leds, timer)) = self.barriers.first_mut() {
             *timer += 3.0 * elapsed_time;
@@ -116,11 +110,11 @@ where
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug, defmt::Format, PartialEq)]
+#[derive(Clone, Copy, Debug, defmt::Format, PartialEq, Wire)]
 pub struct Speed(pub f32);
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug, defmt::Format, PartialEq)]
+#[derive(Clone, Copy, Debug, defmt::Format, PartialEq, Wire)]
 pub enum Animation {
     // This first animation is the set after flashing.
     Static { color: Led },
@@ -128,24 +122,55 @@ pub enum Animation {
     Rainbow { hue: f32, speed: Speed },
 }
 
+impl Animation {
+    /// Color of the animation at the given time of the shared clock in seconds.
+    /// Both halves derive the phase from the same clock, so their animations
+    /// stay in lockstep.
+    fn color_at(&self, time: f64) -> Led {
+        match *self {
+            Animation::Static { color } => color,
+            Animation::Pulsate { color, speed, offset } => {
+                // Wrap the phase in double precision so it stays accurate after long uptimes.
+                let phase = libm::fmod(offset as f64 + speed.0 as f64 * time, core::f64::consts::TAU);
+                // Between 0 and 1
+                let brightness = 0.5 + (libm::sin(phase) * 0.5);
+
+                Led::rgb(
+                    color.red * brightness as f32,
+                    color.green * brightness as f32,
+                    color.blue * brightness as f32,
+                )
+            }
+            Animation::Rainbow { hue, speed } => {
+                let hue = libm::fmod(hue as f64 + speed.0 as f64 * time, 360.0) as f32;
+
+                let color = palette::Hsl::<palette::encoding::Srgb, f32>::new(hue, 1.0, 0.5);
+                let color = palette::rgb::Rgb::from_color(color);
+                let (red, green, blue) = color.into_linear().into_components();
+
+                Led::rgb(red, green, blue)
+            }
+        }
+    }
+}
+
+// Time of the shared clock in seconds.
+fn animation_time() -> f64 {
+    shared_time() as f64 / embassy_time::TICK_HZ as f64
+}
+
 const LIGHTING_CHANNEL_SIZE: usize = 10;
 
 pub type UsedLeds = <<crate::Used as Keyboard>::Leds as LedProvider>::Collection;
 pub type LedIndex = <UsedLeds as LedCollection>::Index;
 pub type LightingSender = Sender<'static, ThreadModeRawMutex, LightingOperation, LIGHTING_CHANNEL_SIZE>;
-pub type OtherLightingReceiver = Receiver<'static, ThreadModeRawMutex, LightingOperation, LIGHTING_CHANNEL_SIZE>;
 
 static LIGHTING_OPERATIONS: Channel<ThreadModeRawMutex, LightingOperation, LIGHTING_CHANNEL_SIZE> = Channel::new();
-static OTHER_LIGHTING_OPERATIONS: Channel<ThreadModeRawMutex, LightingOperation, LIGHTING_CHANNEL_SIZE> = Channel::new();
 
 pub fn lighting_sender() -> LightingSender {
     LIGHTING_OPERATIONS.sender()
 }
 
-pub fn other_lighting_receiver() -> OtherLightingReceiver {
-    OTHER_LIGHTING_OPERATIONS.receiver()
-}
-
 #[embassy_executor::task]
 pub async fn lighting_task(mut leds: UsedLeds) -> ! {
     let mut previous_time = embassy_time::Instant::now();
@@ -162,7 +187,7 @@ pub async fn lighting_task(mut leds: UsedLeds) -> ! {
             match select(receive_future, timer_future).await {
                 Either::Left((lighting_operation, _)) => {
                     match lighting_operation {
-                        LightingOperation::SetAnimation { index, animation } => leds.set_animation(index, animation),
+                        LightingOperation::SetAnimation { index, animation } => leds.set_animation(index, animation, animation_time()),
                     }
                     break;
                 }
@@ -171,7 +196,7 @@ pub async fn lighting_task(mut leds: UsedLeds) -> ! {
                     let elapsed_time = (current_time - previous_time).as_millis() as f32 / 1000.0;
                     previous_time = current_time;
 
-                    leds.update(elapsed_time).await;
+                    leds.update(elapsed_time, animation_time()).await;
                     receive_future = saved_receive_future;
                 }
             }
@@ -184,17 +209,17 @@ pub trait LedProvider {
 }
 
 pub trait LedCollection {
-    type Index: Clone;
+    type Index: Clone + FromRawHid + Wire;
 
-    fn set_animation(&mut self, index: Self::Index, animation: Animation);
+    fn set_animation(&mut self, index: Self::Index, animation: Animation, animation_time: f64);
 
-    async fn update(&mut self, elapsed_time: f32);
+    async fn update(&mut self, elapsed_time: f32, animation_time: f64);
 }
 
 pub trait LedDriver {
-    fn set_animation(&mut self, animation: Animation);
+    fn set_animation(&mut self, animation: Animation, animation_time: f64);
 
-    async fn update(&mut self, elapsed_time: f32);
+    async fn update(&mut self, elapsed_time: f32, animation_time: f64);
 }
 
 embassy_nrf::bind_interrupts!(pub struct Irqs {
@@ -301,63 +326,19 @@ where
     [(); C]:,
     [(); C * 9 * 3]:,
 {
-    fn set_animation(&mut self, animation: Animation) {
-        match animation {
-            Animation::Static { color } => {
-                self.strip.insert_uniform_barrier(color);
-            }
-            Animation::Pulsate { color, offset, .. } => {
-                // Between 0 and 1
-                let brightness = 0.5 + (libm::sin(offset as f64) * 0.5);
-                let led = Led::rgb(
-                    color.red * brightness as f32,
-                    color.green * brightness as f32,
-                    color.blue * brightness as f32,
-                );
-
-                self.strip.insert_uniform_barrier(led);
-            }
-            Animation::Rainbow { hue, .. } => {
-                let color = palette::Hsl::<palette::encoding::Srgb, f32>::new(hue, 1.0, 0.5);
-                let color = palette::rgb::Rgb::from_color(color);
-                let (red, green, blue) = color.into_linear().into_components();
-                let led = Led::rgb(red, green, blue);
-
-                self.strip.insert_uniform_barrier(led);
-            }
-        }
-
+    fn set_animation(&mut self, animation: Animation, animation_time: f64) {
+        self.strip.insert_uniform_barrier(animation.color_at(animation_time));
         self.current_animation = animation;
     }
 
-    async fn update(&mut self, elapsed_time: f32) {
+    async fn update(&mut self, elapsed_time: f32, animation_time: f64) {
+        self.strip.set_uniform_target(self.current_animation.color_at(animation_time));
+
         if self.strip.update_barrier(elapsed_time) {
             let _ = self.spi.write(&self.get_barrier_led_data()).await;
         } else {
-            match &mut self.current_animation {
-                Animation::Static { .. } => {}
-                Animation::Pulsate { offset, color, speed } => {
-                    *offset += speed.0 * elapsed_time;
-                    // Between 0 and 1
-                    let brightness = 0.5 + (libm::sin(*offset as f64) * 0.5);
-                    let led = Led::rgb(
-                        color.red * brightness as f32,
-                        color.green * brightness as f32,
-                        color.blue * brightness as f32,
-                    );
-
-                    self.strip.set_uniform_color(led);
-                }
-                Animation::Rainbow { hue, speed } => {
-                    *hue += speed.0 * elapsed_time;
-
-                    let color = palette::Hsl::<palette::encoding::Srgb, f32>::new(*hue, 1.0, 0.5);
-                    let color = palette::rgb::Rgb::from_color(color);
-                    let (red, green, blue) = color.into_linear().into_components();
-                    let led = Led::rgb(red, green, blue);
-
-                    self.strip.set_uniform_color(led);
-                }
+            if !matches!(self.current_animation, Animation::Static { .. }) {
+                self.strip.set_uniform_color(self.current_animation.color_at(animation_time));
             }
 
             let _ = self.spi.write(&self.get_led_data()).await;
@@ -464,63 +445,19 @@ where
     [(); C]:,
     [(); C * 5 * 3]:,
 {
-    fn set_animation(&mut self, animation: Animation) {
-        match animation {
-            Animation::Static { color } => {
-                self.strip.insert_uniform_barrier(color);
-            }
-            Animation::Pulsate { color, offset, .. } => {
-                // Between 0 and 1
-                let brightness = 0.5 + (libm::sin(offset as f64) * 0.5);
-                let led = Led::rgb(
-                    color.red * brightness as f32,
-                    color.green * brightness as f32,
-                    color.blue * brightness as f32,
-                );
-
-                self.strip.insert_uniform_barrier(led);
-            }
-            Animation::Rainbow { hue, .. } => {
-                let color = palette::Hsl::<palette::encoding::Srgb, f32>::new(hue, 1.0, 0.5);
-                let color = palette::rgb::Rgb::from_color(color);
-                let (red, green, blue) = color.into_linear().into_components();
-                let led = Led::rgb(red, green, blue);
-
-                self.strip.insert_uniform_barrier(led);
-            }
-        }
-
+    fn set_animation(&mut self, animation: Animation, animation_time: f64) {
+        self.strip.insert_uniform_barrier(animation.color_at(animation_time));
         self.current_animation = animation;
     }
 
-    async fn update(&mut self, elapsed_time: f32) {
+    async fn update(&mut self, elapsed_time: f32, animation_time: f64) {
+        self.strip.set_uniform_target(self.current_animation.color_at(animation_time));
+
         if self.strip.update_barrier(elapsed_time) {
             let _ = self.spi.write(&self.get_barrier_led_data()).await;
         } else {
-            match &mut self.current_animation {
-                Animation::Static { .. } => {}
-                Animation::Pulsate { offset, color, speed } => {
-                    *offset += speed.0 * elapsed_time;
-                    // Between 0 and 1
-                    let brightness = 0.5 + (libm::sin(*offset as f64) * 0.5);
-                    let led = Led::rgb(
-                        color.red * brightness as f32,
-                        color.green * brightness as f32,
-                        color.blue * brightness as f32,
-                    );
-
-                    self.strip.set_uniform_color(led);
-                }
-                Animation::Rainbow { hue, speed } => {
-                    *hue += speed.0 * elapsed_time;
-
-                    let color = palette::Hsl::<palette::encoding::Srgb, f32>::new(*hue, 1.0, 0.5);
-                    let color = palette::rgb::Rgb::from_color(color);
-                    let (red, green, blue) = color.into_linear().into_components();
-                    let led = Led::rgb(red, green, blue);
-
-                    self.strip.set_uniform_color(led);
-                }
+            if !matches!(self.current_animation, Animation::Static { .. }) {
+                self.strip.set_uniform_color(self.current_animation.color_at(animation_time));
             }
 
             let _ = self.spi.write(&self.get_led_data()).await;
@@ -18,7 +18,6 @@ use embassy_executor::Spawner;
 use embassy_nrf as _; // time driver
 use embassy_nrf::config::{HfclkSource, LfclkSource};
 use embassy_nrf::interrupt;
-use nrf_softdevice::ble::{set_address, Address};
 use nrf_softdevice::raw::ble_common_cfg_vs_uuid_t;
 use nrf_softdevice::{raw, Flash, Softdevice};
 use procedural::{alias_keyboard, import_keyboards};
@@ -49,12 +48,13 @@ alias_keyboard!(as Used);
 
 use ble::Server;
 
-use crate::ble::{AdvertisingData, Bonder, KEYBOARD_ICON};
+use crate::ble::{use_device_address, AdvertisingData, Bonder, KEYBOARD_ICON, MAXIMUM_HOSTS};
 use crate::hardware::ConfiguredPeripherals;
 use crate::interface::Keyboard;
 #[cfg(feature = "lighting")]
 use crate::led::set_animation;
 use crate::side::Side;
+use crate::split::PartnerBonder;
 
 #[cfg(all(feature = "left", feature = "right"))]
 compile_error!("Only one side can be built for at a time. Try disabling either the left or right feature");
@@ -94,7 +94,8 @@ async fn main(spawner: Spawner) -> ! {
         gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t { attr_tab_size: 32768 }),
         gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
             adv_set_count: 1,
-            periph_role_count: 4,
+            // One peripheral connection for every host and one for the other half.
+            periph_role_count: MAXIMUM_HOSTS as u8 + 1,
             central_role_count: 4,
             central_sec_count: 0,
             _bitfield_1: raw::ble_gap_cfg_role_count_t::new_bitfield_1(0),
@@ -118,6 +119,7 @@ async fn main(spawner: Spawner) -> ! {
 
     // Register BLE services.
     let server = defmt::unwrap!(Server::new(softdevice));
+    ble::initialize_device_information(&server);
     let communication_server = defmt::unwrap!(ble::CommunicationServer::new(softdevice));
     #[cfg(feature = "left")]
     let master_server = defmt::unwrap!(ble::MasterServer::new(softdevice));
@@ -151,35 +153,46 @@ async fn main(spawner: Spawner) -> ! {
     defmt::unwrap!(spawner.spawn(power::power_task(power_pin)));
 
     // Bluetooth setup
-    const SCAN_DATA: &[u8] = &[0x03, 0x03, 0x09, 0x18];
     const ADVERTISING_DATA: AdvertisingData = AdvertisingData::new()
         .add_flags(raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8)
         .add_services(&[0x09, 0x18])
-        .add_name(Used::DEVICE_NAME)
-        .add_appearance(KEYBOARD_ICON);
+        .add_appearance(KEYBOARD_ICON)
+        .add_name(Used::DEVICE_NAME);
 
     static BONDER: StaticCell<Bonder> = StaticCell::new();
     let bonder = BONDER.init(Bonder::new(flash_token));
 
+    static PARTNER_BONDER: StaticCell<PartnerBonder> = StaticCell::new();
+    let partner_bonder = PARTNER_BONDER.init(PartnerBonder::new(flash_token));
+
+    // Roles of the halves after a handover, so they do not need to be determined
+    // again.
+    let mut handed_over: Option<split::Determined> = None;
+
     loop {
-        // Set a well-defined address that the other half can connect to.
-        #[cfg(feature = "left")]
-        set_address(softdevice, &Used::LEFT_ADDRESS);
-        #[cfg(feature = "right")]
-        set_address(softdevice, &Used::RIGHT_ADDRESS);
+        // Use the address of this half while the halves find each other.
+        use_device_address(softdevice);
+
+        let determined = match handed_over.take() {
+            Some(determined) => determined,
+            None => {
+                #[cfg(feature = "lighting")]
+                set_animation(Side::This, Used::STATUS_LEDS, Used::SEARCH_ANIMATION).await;
+
+                // Both sides will connect, initially with the left side as the server and the
+                // right as peripheral. Once they are connected, they elect which will be the
+                // master and drop the connection again.
+                #[cfg(feature = "left")]
+                // FIX: This call makes the per-key leds not work for some reason
+                let determined =
+                    split::advertise_determine_master(softdevice, &master_server, partner_bonder, flash_token, &mut matrix_pins).await;
+                #[cfg(feature = "right")]
+                let determined = split::connect_determine_master(softdevice, partner_bonder, flash_token, &mut matrix_pins).await;
 
-        #[cfg(feature = "lighting")]
-        set_animation(Side::This, Used::STATUS_LEDS, Used::SEARCH_ANIMATION).await;
-
-        // Both sides will connect, initially with the left side as the server and the
-        // right as peripheral. Once they are connected, they will generate a random
-        // number to determine which will be the master and drop the
-        // connection again.
-        #[cfg(feature = "left")]
-        // FIX: This call makes the per-key leds not work for some reason
-        let is_master = split::advertise_determine_master(softdevice, &master_server, ADVERTISING_DATA.get_slice(), SCAN_DATA).await;
-        #[cfg(feature = "right")]
-        let is_master = split::connect_determine_master(softdevice, &Used::LEFT_ADDRESS).await;
+                determined
+            }
+        };
+        let is_master = determined.is_master;
 
         #[cfg(feature = "lighting")]
         match is_master {
@@ -189,7 +202,7 @@ async fn main(spawner: Spawner) -> ! {
 
         defmt::debug!("Is master: {}", is_master);
 
-        let _ = match is_master {
+        let result = match is_master {
             true => {
                 split::do_master(
                     softdevice,
@@ -197,33 +210,52 @@ async fn main(spawner: Spawner) -> ! {
                     &server,
                     &communication_server,
                     bonder,
+                    partner_bonder,
+                    flash_token,
+                    &determined.identity,
                     ADVERTISING_DATA.get_slice(),
-                    SCAN_DATA,
+                    ADVERTISING_DATA.get_scan_response_slice(),
+                    determined.standalone,
                     &mut matrix_pins,
                 )
                 .await
             }
             false => {
-                #[cfg(feature = "left")]
-                const MASTER_ADDRESS: Address = Used::RIGHT_ADDRESS;
-                #[cfg(feature = "right")]
-                const MASTER_ADDRESS: Address = Used::LEFT_ADDRESS;
-
                 split::do_slave(
                     softdevice,
                     &mut keyboard,
                     &communication_server,
                     &mut matrix_pins,
-                    &MASTER_ADDRESS,
+                    partner_bonder,
+                    &determined.identity,
+                    determined.master_connection,
                 )
                 .await
             }
         };
 
-        defmt::error!("Halves disconnected");
-
         keyboard.sides_disconnected().await;
 
+        if let Ok(handover) = result {
+            handed_over = Some(match handover {
+                split::Handover::Swapped => {
+                    defmt::info!("Halves swapped roles");
+
+                    split::Determined {
+                        is_master: !is_master,
+                        identity: determined.identity,
+                        standalone: false,
+                        master_connection: None,
+                    }
+                }
+                // The other half is the master now, so the roles are already known.
+                split::Handover::Joined(joined) => joined,
+            });
+            continue;
+        }
+
+        defmt::error!("Halves disconnected");
+
         #[cfg(all(feature = "lighting", not(feature = "auto-reset")))]
         set_animation(Side::This, Used::STATUS_LEDS, Used::DISCONNECTED_ANIMATION).await;
 
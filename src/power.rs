@@ -1,12 +1,12 @@
 use embassy_nrf::gpio::{AnyPin, Output};
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::{Channel, Receiver, Sender};
-use nrf_softdevice::ble::FixedGattValue;
+use embassy_sync::channel::{Channel, Sender};
 
 use crate::side::Side;
+use crate::split::{send_to_other_half, SplitMessage, Wire};
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, defmt::Format)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, defmt::Format, Wire)]
 pub enum PowerState {
     Off,
     On,
@@ -23,25 +23,11 @@ impl core::ops::Not for PowerState {
     }
 }
 
-#[repr(C)]
-#[derive(Clone, defmt::Format)]
+#[derive(Clone, defmt::Format, Wire)]
 pub enum PowerOperation {
     SetPower { state: PowerState },
 }
 
-impl FixedGattValue for PowerOperation {
-    const SIZE: usize = core::mem::size_of::<PowerOperation>();
-
-    fn from_gatt(data: &[u8]) -> Self {
-        let mut buffer = [0; Self::SIZE];
-        buffer.copy_from_slice(data);
-        unsafe { core::mem::transmute::<&[u8; Self::SIZE], &PowerOperation>(&buffer).clone() }
-    }
-
-    fn to_gatt(&self) -> &[u8] {
-        unsafe { core::mem::transmute::<&PowerOperation, &[u8; Self::SIZE]>(self) }
-    }
-}
 pub async fn set_power_state(side: Side, state: PowerState) {
     let power_operation = PowerOperation::SetPower { state };
 
@@ -50,26 +36,20 @@ pub async fn set_power_state(side: Side, state: PowerState) {
     }
 
     if side.includes_other() {
-        OTHER_POWER_OPERATIONS.send(power_operation).await;
+        send_to_other_half(SplitMessage::Power(power_operation)).await;
     }
 }
 
 const POWER_CHANNEL_SIZE: usize = 10;
 
 pub type PowerSender = Sender<'static, ThreadModeRawMutex, PowerOperation, POWER_CHANNEL_SIZE>;
-pub type OtherPowerReceiver = Receiver<'static, ThreadModeRawMutex, PowerOperation, POWER_CHANNEL_SIZE>;
 
 static POWER_OPERATIONS: Channel<ThreadModeRawMutex, PowerOperation, POWER_CHANNEL_SIZE> = Channel::new();
-static OTHER_POWER_OPERATIONS: Channel<ThreadModeRawMutex, PowerOperation, POWER_CHANNEL_SIZE> = Channel::new();
 
 pub fn power_sender() -> PowerSender {
     POWER_OPERATIONS.sender()
 }
 
-pub fn other_power_receiver() -> OtherPowerReceiver {
-    OTHER_POWER_OPERATIONS.receiver()
-}
-
 #[embassy_executor::task]
 pub async fn power_task(mut power_pin: Option<Output<'static, AnyPin>>) -> ! {
     let receiver = POWER_OPERATIONS.receiver();
@@ -0,0 +1,124 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::driver::now;
+use embassy_time::{Duration, Timer};
+
+use super::transport::{send_to_other_half, try_send_to_other_half, SplitMessage};
+
+// The clocks of the halves drift apart, so the slave synchronizes regularly.
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+// Number of times the slave asks for the time of the master per
+// synchronization. Only the sample with the shortest round trip is used, since
+// it was delayed the least on the way.
+const CLOCK_SAMPLES: usize = 4;
+
+// Time between two requests. Long enough for the master to answer.
+const CLOCK_SAMPLE_SPACING: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy)]
+struct ClockSample {
+    offset: i64,
+    round_trip: u64,
+}
+
+// Difference between the clock of the master and the clock of this half in
+// ticks. Zero on the master and only known on the slave once the clocks are
+// synchronized.
+static CLOCK_OFFSET: Mutex<ThreadModeRawMutex, Cell<Option<i64>>> = Mutex::new(Cell::new(None));
+
+// Sample with the shortest round trip of the current synchronization.
+static BEST_SAMPLE: Mutex<ThreadModeRawMutex, Cell<Option<ClockSample>>> = Mutex::new(Cell::new(None));
+
+/// Forget the offset to the clock of a previous master.
+pub(super) fn reset_clock() {
+    CLOCK_OFFSET.lock(|offset| offset.set(None));
+    BEST_SAMPLE.lock(|sample| sample.set(None));
+}
+
+/// Use the clock of this half as the shared clock. Called when becoming master.
+pub(super) fn use_own_clock() {
+    CLOCK_OFFSET.lock(|offset| offset.set(Some(0)));
+}
+
+/// Current time of the master in ticks, if the clocks are synchronized.
+pub(super) fn master_time() -> Option<u64> {
+    CLOCK_OFFSET
+        .lock(|offset| offset.get())
+        .map(|offset| now().wrapping_add_signed(offset))
+}
+
+/// Time in ticks that both halves agree on while they are connected. Falls
+/// back to the clock of this half until the clocks are synchronized.
+#[cfg(feature = "lighting")]
+pub fn shared_time() -> u64 {
+    master_time().unwrap_or_else(now)
+}
+
+// Clock messages carry the time when they are written to the link, not when
+// they were queued, so waiting in the queue does not count as round trip.
+pub(super) fn stamp_clock_message(message: &mut SplitMessage) {
+    match message {
+        SplitMessage::ClockRequest { slave_time } => *slave_time = now(),
+        SplitMessage::ClockResponse { master_sent, .. } => *master_sent = now(),
+        _ => {}
+    }
+}
+
+// Called on the master when the slave asks for its time. Messages are
+// dispatched as soon as they are received, so the current time is when the
+// request arrived.
+pub(super) fn answer_clock_request(slave_time: u64) {
+    let message = SplitMessage::ClockResponse {
+        slave_time,
+        master_received: now(),
+        master_sent: 0,
+    };
+
+    if !try_send_to_other_half(message) {
+        defmt::warn!("Failed to answer clock request of the slave");
+    }
+}
+
+// Called on the slave when the master answered. The time the master took to
+// answer is not part of the round trip, and the way there is assumed to take as
+// long as the way back.
+pub(super) fn update_clock_offset(slave_time: u64, master_received: u64, master_sent: u64) {
+    let received_at = now();
+    let round_trip = received_at
+        .wrapping_sub(slave_time)
+        .saturating_sub(master_sent.wrapping_sub(master_received));
+    let offset = (master_received.wrapping_sub(slave_time) as i64 + master_sent.wrapping_sub(received_at) as i64) / 2;
+
+    defmt::debug!(
+        "Clock offset to the master is {} ticks (round trip {} ticks)",
+        offset,
+        round_trip
+    );
+
+    BEST_SAMPLE.lock(|best_sample| {
+        if !matches!(best_sample.get(), Some(best_sample) if best_sample.round_trip <= round_trip) {
+            best_sample.set(Some(ClockSample { offset, round_trip }));
+        }
+    });
+}
+
+/// Keep the clock of the slave synchronized with the master.
+pub(super) async fn synchronize_clock() -> ! {
+    loop {
+        for _ in 0..CLOCK_SAMPLES {
+            // The time is set when the request is written.
+            send_to_other_half(SplitMessage::ClockRequest { slave_time: 0 }).await;
+            Timer::after(CLOCK_SAMPLE_SPACING).await;
+        }
+
+        if let Some(sample) = BEST_SAMPLE.lock(|best_sample| best_sample.take()) {
+            defmt::debug!("Using clock offset {} (round trip {} ticks)", sample.offset, sample.round_trip);
+            CLOCK_OFFSET.lock(|offset| offset.set(Some(sample.offset)));
+        }
+
+        Timer::after(CLOCK_SYNC_INTERVAL).await;
+    }
+}
@@ -1,66 +1,228 @@
-use nrf_softdevice::ble::{central, gatt_server, peripheral, Address};
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::with_timeout;
+use logic::ble::is_known_identity;
+use nrf_softdevice::ble::{central, gatt_server, Connection};
 use nrf_softdevice::Softdevice;
 
-use crate::ble::{MasterServer, MasterServerEvent, MasterServiceClient, MasterServiceEvent};
-use crate::hardware::generate_random_u32;
+use super::partner::{advertise_to_partner, connect_to_partner, join_running_master, PartnerBonder};
+use super::{decode_message, encode_message, Candidate, WireMessage};
+use crate::battery::latest_battery_level;
+use crate::ble::{Identity, MasterServer, MasterServerEvent, MasterServiceClient, MasterServiceEvent, CANDIDATE_MESSAGE_SIZE};
+use crate::flash::{get_settings, store_identity, store_master_elections, FlashToken};
+use crate::hardware::{generate_random_u32, MatrixPins};
+use crate::interface::{Keyboard, Scannable};
+use crate::side::Side;
+
+// Number of times this half was elected master since booting, if it was elected
+// at all. The halves run the election again on every reconnect, so only the
+// first win after booting is written to the flash. That is enough to take turns
+// across restarts without erasing the flash on every election.
+static MASTER_ELECTIONS: Mutex<ThreadModeRawMutex, Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+/// Outcome of connecting the halves.
+pub struct Determined {
+    pub is_master: bool,
+    /// Identity presented to hosts. Both halves use the identity of the left
+    /// half, so hosts see the same keyboard no matter which half is the master,
+    /// even if it runs on its own.
+    pub identity: Identity,
+    /// Whether the other half was not found, so the master runs on its own
+    /// until the other half joins.
+    pub standalone: bool,
+    /// Link to a master that was already running on its own when this half
+    /// started. This half joins it as the slave.
+    pub master_connection: Option<Connection>,
+}
 
-#[allow(dead_code)]
-pub async fn advertise_determine_master(softdevice: &Softdevice, server: &MasterServer, adv_data: &[u8], scan_data: &[u8]) -> bool {
-    let config = peripheral::Config::default();
-    let adv = peripheral::ConnectableAdvertisement::ScannableUndirected { adv_data, scan_data };
+impl Determined {
+    fn elected(is_master: bool, identity: Identity) -> Self {
+        Self {
+            is_master,
+            identity,
+            standalone: false,
+            master_connection: None,
+        }
+    }
+
+    fn standalone(identity: Identity) -> Self {
+        Self {
+            is_master: true,
+            identity,
+            standalone: true,
+            master_connection: None,
+        }
+    }
+}
 
-    defmt::debug!("Start advertising");
+// What this half brings to the election of the master.
+async fn own_candidate(softdevice: &Softdevice, flash_token: FlashToken) -> Candidate {
+    Candidate {
+        battery_level: latest_battery_level().map(|battery_level| battery_level.0),
+        master_elections: MASTER_ELECTIONS
+            .lock(|master_elections| master_elections.get())
+            .unwrap_or(get_settings(flash_token).master_elections),
+        random_number: generate_random_u32(softdevice).await,
+    }
+}
 
-    let connection = defmt::unwrap!(peripheral::advertise_connectable(softdevice, adv, &config).await);
+// Remember that this half was elected, so the other half gets its turn next
+// time.
+async fn record_election(candidate: &Candidate, is_master: bool) {
+    if !is_master {
+        return;
+    }
 
-    defmt::debug!("Connected to other half");
+    let master_elections = candidate.master_elections.wrapping_add(1);
+    let first_win = MASTER_ELECTIONS.lock(|elections| elections.replace(Some(master_elections)).is_none());
 
-    let random_number = generate_random_u32(softdevice).await;
+    if first_win {
+        store_master_elections(Side::This, master_elections).await;
+    }
+}
 
-    defmt::debug!("Random number is {}", random_number);
+#[allow(dead_code)]
+pub async fn advertise_determine_master(
+    softdevice: &Softdevice,
+    server: &MasterServer,
+    bonder: &'static PartnerBonder,
+    flash_token: FlashToken,
+    matrix_pins: &mut MatrixPins<'_, { <crate::Used as Scannable>::COLUMNS }, { <crate::Used as Scannable>::ROWS }>,
+) -> Determined {
+    let identity = Identity::from_ficr();
+    defmt::unwrap!(server.master_service.identity_set(&identity.to_bytes()));
+
+    let connection = match with_timeout(
+        <crate::Used as Keyboard>::STANDALONE_TIMEOUT,
+        advertise_to_partner(softdevice, bonder, matrix_pins),
+    )
+    .await
+    {
+        Ok(connection) => connection,
+        Err(..) => {
+            // The right half joins the left half once both run on their own, so there is
+            // no need to wait for it here.
+            defmt::info!("Other half not found, becoming master on its own");
+            return Determined::standalone(identity);
+        }
+    };
+
+    defmt::debug!("Connected to other half with address {}", connection.peer_address());
+
+    let candidate = own_candidate(softdevice, flash_token).await;
+
+    defmt::debug!("Candidate is {}", candidate);
 
     let mut is_master = false;
     let _ = gatt_server::run(&connection, server, |event| match event {
         MasterServerEvent::MasterService(event) => match event {
-            MasterServiceEvent::OtherRandomNumberWrite(other_random_number) => {
-                // Determine which side is the master based on our random numbers.
-                is_master = random_number > other_random_number;
-
-                defmt::debug!("Other random number is {}", other_random_number);
-
-                // Update is_master so that the other side can read it.
-                defmt::unwrap!(server.master_service.is_master_set(&is_master));
-            }
+            MasterServiceEvent::OtherCandidateWrite(message) => match decode_message::<Candidate>(&message) {
+                Ok(other_candidate) => {
+                    defmt::debug!("Other candidate is {}", other_candidate);
+
+                    is_master = candidate.wins_against(&other_candidate);
+
+                    // Update is_master so that the other side can read it.
+                    defmt::unwrap!(server.master_service.is_master_set(&is_master));
+                }
+                // The other half still reads that it is the master, so the halves agree.
+                Err(error) => defmt::error!("Failed to decode candidate of the other half: {}", error),
+            },
         },
     })
     .await;
 
-    is_master
+    record_election(&candidate, is_master).await;
+
+    Determined::elected(is_master, identity)
 }
 
 #[allow(dead_code)]
-pub async fn connect_determine_master(softdevice: &Softdevice, address: &Address) -> bool {
-    let addresses = [address];
-    let mut config = central::ConnectConfig::default();
-    config.scan_config.whitelist = Some(&addresses);
-    config.conn_params.min_conn_interval = 6;
-    config.conn_params.max_conn_interval = 6;
+pub async fn connect_determine_master(
+    softdevice: &Softdevice,
+    bonder: &'static PartnerBonder,
+    flash_token: FlashToken,
+    matrix_pins: &mut MatrixPins<'_, { <crate::Used as Scannable>::COLUMNS }, { <crate::Used as Scannable>::ROWS }>,
+) -> Determined {
+    let mut conn_params = central::ConnectConfig::default().conn_params;
+    conn_params.min_conn_interval = 6;
+    conn_params.max_conn_interval = 6;
+
+    let connection = match with_timeout(
+        <crate::Used as Keyboard>::STANDALONE_TIMEOUT,
+        connect_to_partner(softdevice, bonder, conn_params, matrix_pins),
+    )
+    .await
+    {
+        Ok(connection) => connection,
+        Err(..) => return without_other_half(softdevice, bonder, flash_token).await,
+    };
+    let client: MasterServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&connection).await);
 
-    defmt::debug!("Start scanning");
+    defmt::debug!("Connected to other half with address {}", connection.peer_address());
 
-    let connection = defmt::unwrap!(central::connect(softdevice, &config).await);
-    let client: MasterServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&connection).await);
+    let identity = Identity::from_bytes(&defmt::unwrap!(client.identity_read().await));
 
-    defmt::debug!("Connected to other half");
+    // Remember the identity, so this half can take over as master after it joined
+    // a left half that ran on its own.
+    if get_settings(flash_token).identity != identity.to_bytes() {
+        store_identity(Side::This, identity.to_bytes()).await;
+    }
 
-    let random_number = generate_random_u32(softdevice).await;
+    let candidate = own_candidate(softdevice, flash_token).await;
 
-    defmt::debug!("Random number is {}", random_number);
-    defmt::debug!("Writing random number to the master service");
+    defmt::debug!("Candidate is {}", candidate);
+    defmt::debug!("Writing candidate to the master service");
 
-    defmt::unwrap!(client.other_random_number_write(&random_number).await);
+    let message: WireMessage<CANDIDATE_MESSAGE_SIZE> = defmt::unwrap!(encode_message(&candidate));
+    defmt::unwrap!(client.other_candidate_write(&message).await);
 
     defmt::debug!("Reading is_master from the master service");
 
-    !defmt::unwrap!(client.is_master_read().await)
+    let is_master = !defmt::unwrap!(client.is_master_read().await);
+
+    record_election(&candidate, is_master).await;
+
+    Determined::elected(is_master, identity)
+}
+
+// Identity of the keyboard that the right half received from the left half, if
+// the halves ever connected.
+pub(super) fn known_identity(flash_token: FlashToken) -> Option<Identity> {
+    let identity = get_settings(flash_token).identity;
+    is_known_identity(&identity).then(|| Identity::from_bytes(&identity))
+}
+
+// Identity of the keyboard that the right half received from the left half.
+// Falls back to the identity of this half if the halves never connected, which
+// only happens if the right half runs on its own before it ever met the left
+// half. Hosts need to pair with it separately in that case.
+pub(super) fn stored_identity(flash_token: FlashToken) -> Identity {
+    known_identity(flash_token).unwrap_or_else(|| {
+        defmt::warn!("Identity of the keyboard is unknown, using the identity of this half");
+        Identity::from_ficr()
+    })
+}
+
+// Called on the right half if the left half did not show up in time. It might
+// already run on its own, so give it a chance to connect before this half
+// becomes the master on its own. Either way, it presents the identity it
+// received from the left half, so hosts do not need to pair again.
+async fn without_other_half(softdevice: &Softdevice, bonder: &'static PartnerBonder, flash_token: FlashToken) -> Determined {
+    defmt::info!("Other half not found, checking if it already runs on its own");
+
+    match join_running_master(softdevice, bonder, known_identity(flash_token).as_ref()).await {
+        Some(connection) => Determined {
+            is_master: false,
+            identity: stored_identity(flash_token),
+            standalone: false,
+            master_connection: Some(connection),
+        },
+        None => {
+            defmt::info!("Becoming master on its own");
+            Determined::standalone(stored_identity(flash_token))
+        }
+    }
 }
@@ -0,0 +1,75 @@
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use logic::split::BATTERY_LEVEL_MARGIN;
+
+use crate::battery::BatteryLevel;
+use crate::interface::Keyboard;
+
+// Time the halves wait for each other while handing over.
+pub(super) const HANDOVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The master asks the slave to take over, and only lets go of the hosts once
+// the slave accepted. The slave only takes over once the master acknowledged
+// the acceptance, so the halves never both end up as master. If the link
+// breaks in between, both halves might end up as slaves until they elect a
+// new master.
+static HANDOVER_REQUESTED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+static HANDOVER_ORDERED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+static HANDOVER_ACCEPTED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+static ACCEPTANCE_ACKNOWLEDGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Let the slave take over as master. Only has an effect on the master.
+pub fn request_handover() {
+    HANDOVER_REQUESTED.signal(());
+}
+
+/// Request a handover if the battery of the master runs low and the slave has
+/// noticeably more charge.
+pub(super) fn check_battery_levels(master_battery_level: &BatteryLevel, slave_battery_level: &BatteryLevel) {
+    if master_battery_level.0 < <crate::Used as Keyboard>::HANDOVER_BATTERY_LEVEL
+        && slave_battery_level.0 >= master_battery_level.0.saturating_add(BATTERY_LEVEL_MARGIN)
+    {
+        defmt::info!("Battery of the master is low, handing over to the slave");
+        request_handover();
+    }
+}
+
+pub(super) async fn handover_requested() {
+    HANDOVER_REQUESTED.wait().await
+}
+
+// Called when the master asks the slave to take over.
+pub(super) fn order_handover() {
+    HANDOVER_ORDERED.signal(());
+}
+
+pub(super) async fn handover_ordered() {
+    HANDOVER_ORDERED.wait().await
+}
+
+// Called when the slave agreed to take over.
+pub(super) fn accept_handover() {
+    HANDOVER_ACCEPTED.signal(());
+}
+
+pub(super) async fn handover_accepted() {
+    HANDOVER_ACCEPTED.wait().await
+}
+
+// Called when the master acknowledged that it received the acceptance.
+pub(super) fn acknowledge_acceptance() {
+    ACCEPTANCE_ACKNOWLEDGED.signal(());
+}
+
+pub(super) async fn acceptance_acknowledged() {
+    ACCEPTANCE_ACKNOWLEDGED.wait().await
+}
+
+/// Forget handovers of a previous session.
+pub(super) fn reset_handover() {
+    HANDOVER_REQUESTED.reset();
+    HANDOVER_ORDERED.reset();
+    HANDOVER_ACCEPTED.reset();
+    ACCEPTANCE_ACKNOWLEDGED.reset();
+}
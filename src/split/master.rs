@@ -1,174 +1,506 @@
-use core::convert::Infallible;
-use core::ops::ControlFlow;
-
+use core::future::pending;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_futures::join::join_array;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::driver::now;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use futures::future::{select, Either};
 use futures::{pin_mut, FutureExt};
 use heapless::Vec;
 use nrf_softdevice::ble::gatt_server::NotifyValueError;
-use nrf_softdevice::ble::{gatt_server, peripheral, set_address, Connection};
+use nrf_softdevice::ble::{gatt_client, peripheral, Connection};
 use nrf_softdevice::Softdevice;
 
-use super::event::event_sender;
-use super::{event_receiver, HalfDisconnected};
-use crate::battery::battery_level_receiver;
+use super::clock::use_own_clock;
+use super::determine::{known_identity, stored_identity};
+use super::handover::{check_battery_levels, handover_accepted, handover_requested, reset_handover, HANDOVER_TIMEOUT};
+use super::partner::{
+    advertise_to_partner, connect_to_joining_slave, join_running_master, partner_connection_parameters, reconnect_to_slave, PartnerBonder,
+};
+use super::transport::{release_slave_keys, reset_transport, run_transport};
+use super::{event_receiver, send_to_other_half, slave_key_state_receiver, Determined, HalfDisconnected, Handover, SplitMessage};
+use crate::battery::{battery_level_receiver, slave_battery_level_receiver, BatteryLevel};
 use crate::ble::{
-    Bonder, CommunicationServer, CommunicationServerEvent, EventServiceClient, EventServiceEvent, FlashServiceClient, FlashServiceEvent,
-    KeyStateServiceEvent, PowerServiceClient, PowerServiceEvent, Server,
+    advertising_activity, bonded_host, clear_reports, enter_pairing_mode, find_bond, manage_connection_parameters, profile_request,
+    queue_report, register_activity, release_all_keys, report_statistics, resolve_bonded_hosts, run_report_queue, set_pairing_slot,
+    stored_profile, update_bond_metadata, use_identity, Bonder, CommunicationServer, DutyCycle, HostRequest, Hosts, Identity,
+    KeyboardReport, PasskeyInput, ProfileRequest, RawHidReport, RawHidResponse, RawHidStatus, Server, TransportServiceClient,
+    KEYBOARD_INPUT_REPORT_SIZE, MAXIMUM_HOSTS,
 };
-#[cfg(feature = "lighting")]
-use crate::ble::{LightingServiceClient, LightingServiceEvent};
-use crate::flash::flash_sender;
+use crate::flash::{get_settings, store_active_profile, BondSlot, FlashToken, NO_ADDRESS};
 use crate::hardware::{ActiveModifier, BitOperations, MasterState, MatrixPins};
 use crate::interface::{Keyboard, KeyboardExtension, Scannable};
 use crate::keys::{Mapping, Modifiers, TapAction};
 #[cfg(feature = "lighting")]
-use crate::led::lighting_sender;
-use crate::power::power_sender;
+use crate::led::{set_animation, Animation};
+use crate::side::Side;
 use crate::split::UsedEvent;
 
+// Time after starting in which holding the pairing keys enters pairing mode.
+const PAIRING_KEYS_WINDOW: Duration = Duration::from_secs(5);
+
+// Set while the master runs on its own, because the other half was not found
+// when it started. Cleared once the other half joins.
+static STANDALONE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the master runs on its own, so only the keys of this half are
+/// available.
+pub fn is_standalone() -> bool {
+    STANDALONE.load(Ordering::Relaxed)
+}
+
+// Time that the right half advertises to the left half while it runs on its
+// own. Only one advertisement can run at a time, so advertising to hosts
+// pauses meanwhile.
+const JOIN_WINDOW: Duration = Duration::from_secs(2);
+
+// Time between advertising to the left half while the right half runs on its
+// own.
+const JOIN_INTERVAL: Duration = Duration::from_secs(10);
+
+// Link to the left half once it found the right half running on its own.
+static JOINED_MASTER: Signal<ThreadModeRawMutex, Connection> = Signal::new();
+
+// If both halves run on their own, one of them has to advertise for them to
+// find each other. The left half keeps searching, while the right half
+// advertises to it now and then and joins it as the slave.
+fn joins_running_master() -> bool {
+    cfg!(feature = "right") && is_standalone()
+}
+
 pub async fn do_master(
     softdevice: &Softdevice,
     keyboard: &mut crate::Used,
     server: &Server,
     communication_server: &CommunicationServer,
     bonder: &'static Bonder,
+    partner_bonder: &'static PartnerBonder,
+    flash_token: FlashToken,
+    identity: &Identity,
     adv_data: &[u8],
     scan_data: &[u8],
+    standalone: bool,
     matrix_pins: &mut MatrixPins<'_, { <crate::Used as Scannable>::COLUMNS }, { <crate::Used as Scannable>::ROWS }>,
-) -> Result<Infallible, HalfDisconnected> {
+) -> Result<Handover, HalfDisconnected> {
     defmt::debug!("Stating master");
 
+    reset_handover();
+    reset_transport();
+    JOINED_MASTER.reset();
+
+    // The slave synchronizes to the clock of the master.
+    use_own_clock();
+
     keyboard.pre_sides_connected(true).await;
 
-    // Connect to the other half
-    let config = peripheral::Config::default();
-    let adv = peripheral::ConnectableAdvertisement::ScannableUndirected { adv_data, scan_data };
-    let slave_connection = defmt::unwrap!(peripheral::advertise_connectable(softdevice, adv, &config).await);
+    // Connect to the other half, unless it was not found and this half runs on its
+    // own until it joins.
+    let slave_connection = match standalone {
+        true => {
+            defmt::info!("Running without the other half");
+            None
+        }
+        false => {
+            let connection = advertise_to_partner(softdevice, partner_bonder, matrix_pins).await;
+            defmt::info!("Connected to other half");
+            Some(connection)
+        }
+    };
 
-    // Get the flash client of the other side.
-    let flash_client: FlashServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&slave_connection).await);
-    let other_flash_operations = crate::flash::other_flash_receiver();
+    STANDALONE.store(standalone, Ordering::Relaxed);
 
-    // Get the power client of the other side.
-    let power_client: PowerServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&slave_connection).await);
-    let other_power_operations = crate::power::other_power_receiver();
+    keyboard.post_sides_connected(true).await;
 
-    // Get the lighting client of the other side.
-    #[cfg(feature = "lighting")]
-    let lighting_client: LightingServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&slave_connection).await);
-    #[cfg(feature = "lighting")]
-    let other_lighting_operations = crate::led::other_lighting_receiver();
+    // Present the same identity to hosts, no matter which half is the master.
+    use_identity(softdevice, identity);
+
+    let hosts = Hosts::new(ProfileRequest::Select(stored_profile(flash_token)));
+
+    let mut keyboard_state = MasterState::new();
+
+    // Serve every host slot. There is one future per slot, since we can not
+    // allocate.
+    let host_futures: [_; MAXIMUM_HOSTS] = core::array::from_fn(|index| hosts.run(index, server, flash_token));
+    let host_future = join_array(host_futures).fuse();
+
+    let advertise_future = advertise_hosts(softdevice, bonder, partner_bonder, flash_token, &hosts, adv_data, scan_data).fuse();
+    let report_future = run_report_queue(server, &hosts).fuse();
+    let profile_future = switch_profiles(server, flash_token, &hosts).fuse();
+    let parameters_future = manage_connection_parameters(&hosts).fuse();
+    let state_future = update_master_state(keyboard, &mut keyboard_state, matrix_pins, server, &hosts, bonder, flash_token).fuse();
+    let slave_future = run_slave_link(softdevice, partner_bonder, communication_server, flash_token, slave_connection).fuse();
+
+    pin_mut!(host_future);
+    pin_mut!(advertise_future);
+    pin_mut!(report_future);
+    pin_mut!(profile_future);
+    pin_mut!(parameters_future);
+    pin_mut!(state_future);
+    pin_mut!(slave_future);
+
+    // Only the slave future returns, if the slave took over or could not be found
+    // again. All other futures run forever.
+    let result = futures::select_biased! {
+        [never, ..] = host_future => never,
+        never = advertise_future => never,
+        never = report_future => never,
+        never = profile_future => never,
+        never = parameters_future => never,
+        never = state_future => never,
+        result = slave_future => result,
+    };
 
-    // Get the event client of the other side.
-    let event_client: EventServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&slave_connection).await);
-    let other_events = crate::split::event::other_event_receiver();
+    // Hosts should not stay connected to a keyboard that is not running.
+    for connection in hosts.connections() {
+        let _ = connection.disconnect();
+    }
 
-    defmt::info!("Connected to other half");
+    result
+}
 
-    keyboard.post_sides_connected(true).await;
+// Exchange messages with the slave. If the link breaks, the master keeps
+// serving hosts alone while it searches for the slave again. Only returns if
+// the slave took over or was not found before the search stopped. Without a
+// connection, the master runs on its own until the other half joins. If the
+// right half runs on its own, it joins the left half instead.
+async fn run_slave_link(
+    softdevice: &Softdevice,
+    partner_bonder: &'static PartnerBonder,
+    communication_server: &CommunicationServer,
+    flash_token: FlashToken,
+    slave_connection: Option<Connection>,
+) -> Result<Handover, HalfDisconnected> {
+    let mut slave_connection = match slave_connection {
+        Some(connection) => connection,
+        None if joins_running_master() => {
+            let connection = JOINED_MASTER.wait().await;
+
+            defmt::info!("Joined the other half as the slave");
+
+            return Ok(Handover::Joined(Determined {
+                is_master: false,
+                identity: stored_identity(flash_token),
+                standalone: false,
+                master_connection: Some(connection),
+            }));
+        }
+        None => {
+            let connection = connect_to_joining_slave(softdevice, partner_bonder, partner_connection_parameters()).await;
 
-    // Set unified address.
-    set_address(softdevice, &<crate::Used as Keyboard>::ADDRESS);
-
-    let inner_future = async {
-        let mut keyboard_state = MasterState::new();
-
-        loop {
-            // Advertise
-            let config = peripheral::Config::default();
-            let adv = peripheral::ConnectableAdvertisement::ScannableUndirected { adv_data, scan_data };
-            let advertise_future = peripheral::advertise_pairable(softdevice, adv, &config, bonder);
-            pin_mut!(advertise_future);
-
-            let host_connection = loop {
-                let scan_future = master_scan(
-                    keyboard,
-                    &mut keyboard_state,
-                    matrix_pins,
-                    communication_server,
-                    &slave_connection,
-                );
-
-                pin_mut!(scan_future);
-
-                match select(advertise_future, scan_future).await {
-                    Either::Left((advertise_result, _)) => break defmt::unwrap!(advertise_result),
-                    Either::Right((result, passed_advertise_future)) => {
-                        // We just want to make sure that the slave did not disconnect, so we discard
-                        // all other information.
-                        if result.is_err() {
-                            return HalfDisconnected;
-                        }
+            defmt::info!("Other half joined");
 
-                        advertise_future = passed_advertise_future;
-                        continue;
-                    }
+            // The keys of the other half are available again.
+            STANDALONE.store(false, Ordering::Relaxed);
+            connection
+        }
+    };
+
+    loop {
+        match gatt_client::discover::<TransportServiceClient>(&slave_connection).await {
+            Ok(client) => {
+                let transport_future = run_transport(&slave_connection, communication_server, &client);
+                let handover_future = async {
+                    handover_requested().await;
+
+                    defmt::info!("Asking the slave to take over");
+
+                    send_to_other_half(SplitMessage::Handover).await;
+                    handover_accepted().await;
+                };
+
+                pin_mut!(transport_future);
+                pin_mut!(handover_future);
+
+                if let Either::Right((_, transport_future)) = select(transport_future, handover_future).await {
+                    defmt::info!("Slave accepted the handover");
+
+                    // Keep the link up until the slave closed it, so it receives the
+                    // acknowledgement of its acceptance.
+                    let _ = with_timeout(HANDOVER_TIMEOUT, transport_future).await;
+                    let _ = slave_connection.disconnect();
+
+                    return Ok(Handover::Swapped);
                 }
-            };
-
-            defmt::warn!("Connected to host");
-
-            let host_future = gatt_server::run(&host_connection, server, |_| {});
-            let state_future = update_master_state(
-                keyboard,
-                &mut keyboard_state,
-                matrix_pins,
-                server,
-                communication_server,
-                &slave_connection,
-                &host_connection,
-            );
-
-            pin_mut!(host_future);
-            pin_mut!(state_future);
-
-            match select(host_future, state_future).await {
-                // Keyboard disconnected from host, so just continue.
-                Either::Left(..) => {}
-                // Only returns if the halves disconnected, so we break.
-                Either::Right(..) => break HalfDisconnected,
+            }
+            Err(error) => {
+                defmt::error!("Failed to discover the transport service of the slave: {:?}", error);
+                let _ = slave_connection.disconnect();
             }
         }
-    };
 
-    let client_future = super::common::run_clients(
-        &flash_client,
-        &other_flash_operations,
-        &power_client,
-        &other_power_operations,
+        defmt::warn!("Link to the slave broke, searching for it again");
+
+        release_slave_keys();
+
         #[cfg(feature = "lighting")]
-        &lighting_client,
+        set_animation(
+            Side::This,
+            <crate::Used as Keyboard>::STATUS_LEDS,
+            <crate::Used as Keyboard>::DISCONNECTED_ANIMATION,
+        )
+        .await;
+
+        slave_connection = match reconnect_to_slave(softdevice, partner_bonder, partner_connection_parameters()).await {
+            Some(connection) => connection,
+            None => return Err(HalfDisconnected),
+        };
+
+        defmt::info!("Reconnected to slave");
+
         #[cfg(feature = "lighting")]
-        &other_lighting_operations,
-        &event_client,
-        &other_events,
-    );
+        set_animation(
+            Side::This,
+            <crate::Used as Keyboard>::STATUS_LEDS,
+            <crate::Used as Keyboard>::MASTER_ANIMATION,
+        )
+        .await;
+    }
+}
+
+// Advertise as long as there is room for another host. Never returns, but
+// starts advertising again whenever a new profile is selected, so the host of
+// that profile can connect.
+async fn advertise_hosts(
+    softdevice: &Softdevice,
+    bonder: &'static Bonder,
+    partner_bonder: &'static PartnerBonder,
+    flash_token: FlashToken,
+    hosts: &Hosts,
+    adv_data: &[u8],
+    scan_data: &[u8],
+) -> ! {
+    let mut duty_cycle = Some(DutyCycle::Fast);
+    // Time when advertising with the current duty cycle times out, once it started.
+    let mut deadline = None;
+
+    loop {
+        hosts.wait_for_free_slot().await;
+
+        // Once advertising timed out, only a key press or a new profile starts it
+        // again.
+        let current_duty_cycle = match duty_cycle {
+            Some(duty_cycle) => duty_cycle,
+            None => {
+                defmt::info!("Stopped advertising, waiting for a key press");
+
+                let activity_future = advertising_activity();
+                let profile_future = hosts.profile_changed();
+
+                pin_mut!(activity_future);
+                pin_mut!(profile_future);
+
+                select(activity_future, profile_future).await;
+                duty_cycle = Some(DutyCycle::Fast);
+                deadline = None;
+                continue;
+            }
+        };
+
+        if joins_running_master() {
+            let identity = known_identity(flash_token);
+
+            if let Ok(Some(connection)) =
+                with_timeout(JOIN_WINDOW, join_running_master(softdevice, partner_bonder, identity.as_ref())).await
+            {
+                // The slave link picks up the connection, which stops the master.
+                JOINED_MASTER.signal(connection);
+                pending::<()>().await;
+            }
+        }
+
+        let (bond_slot, pairing) = match hosts.profile() {
+            ProfileRequest::Select(bond_slot) => (bond_slot, false),
+            ProfileRequest::Pair(bond_slot) => (bond_slot, true),
+        };
+
+        let bonds = &get_settings(flash_token).bonds;
+        let bonded_address = bonds[bond_slot.0].peer.peer_id.addr;
+
+        // Hosts connect with private addresses, which need to be resolved to find their
+        // bond.
+        resolve_bonded_hosts(flash_token);
+
+        defmt::debug!(
+            "Advertising for profile {} with {} duty cycle (pairing: {})",
+            bond_slot,
+            current_duty_cycle,
+            pairing
+        );
+
+        set_pairing_slot(pairing.then_some(bond_slot));
+
+        let mut config = peripheral::Config::default();
+        current_duty_cycle.configure_advertising(&mut config);
+
+        let adv = match pairing {
+            // Advertise to everyone, so the new host can find us.
+            true => peripheral::ConnectableAdvertisement::ScannableUndirected { adv_data, scan_data },
+            // Advertise only to the host of the selected profile so it can connect quickly.
+            false if bonded_address != NO_ADDRESS && !hosts.is_connected(bond_slot) => {
+                peripheral::ConnectableAdvertisement::NonscannableDirected { peer: bonded_address }
+            }
+            // Otherwise only allow bonded hosts to connect.
+            false => {
+                if whitelist_bonded_hosts(flash_token) == 0 {
+                    defmt::debug!("No bonded hosts, waiting for pairing mode");
+
+                    // Keep advertising to the left half now and then in the meantime.
+                    match joins_running_master() {
+                        true => {
+                            let _ = with_timeout(JOIN_INTERVAL, hosts.profile_changed()).await;
+                        }
+                        false => hosts.profile_changed().await,
+                    }
+                    continue;
+                }
+
+                config.filter_policy = peripheral::FilterPolicy::Both;
+                peripheral::ConnectableAdvertisement::ScannableUndirected { adv_data, scan_data }
+            }
+        };
+        // Advertising is interrupted now and then to advertise to the left half, but
+        // the duty cycle only times out once the whole timeout passed.
+        let current_time = Instant::now();
+        let current_deadline = *deadline.get_or_insert(current_time + current_duty_cycle.advertising_timeout());
+        let remaining = current_deadline.max(current_time) - current_time;
+        let timeout = match joins_running_master() {
+            true => remaining.min(JOIN_INTERVAL),
+            false => remaining,
+        };
+
+        let advertise_future = with_timeout(timeout, peripheral::advertise_pairable(softdevice, adv, &config, bonder));
+        let profile_future = hosts.profile_changed();
+
+        pin_mut!(advertise_future);
+        pin_mut!(profile_future);
+
+        let connection = match select(advertise_future, profile_future).await {
+            Either::Left((Ok(advertise_result), _)) => defmt::unwrap!(advertise_result),
+            Either::Left((Err(..), _)) => {
+                if Instant::now() >= current_deadline {
+                    duty_cycle = current_duty_cycle.next();
+                    deadline = None;
+                }
+                continue;
+            }
+            Either::Right(..) => {
+                duty_cycle = Some(DutyCycle::Fast);
+                deadline = None;
+                continue;
+            }
+        };
+
+        duty_cycle = Some(DutyCycle::Fast);
+        deadline = None;
+
+        defmt::warn!("Connected to host");
+
+        // Hosts that connect in pairing mode only get the pairing slot once they bond,
+        // which is handled when switching profiles.
+        let connected_slot = find_bond(flash_token, connection.peer_address());
+
+        hosts.add(connection, connected_slot);
+    }
+}
+
+// Switch the host that receives input reports. Selected profiles are persisted
+// so the same host is used after a restart.
+async fn switch_profiles(server: &Server, flash_token: FlashToken, hosts: &Hosts) -> ! {
+    loop {
+        let request_future = profile_request(flash_token);
+        pin_mut!(request_future);
+
+        // A host that bonded is selected and leaves pairing mode. Hosts can only bond
+        // in pairing mode, but the bond might complete just after it ended.
+        let bonded_future = bonded_host().map(|(handle, bond_slot)| {
+            defmt::info!("New host bonded in slot {}", bond_slot);
+            hosts.assign_bond(handle, bond_slot);
+            ProfileRequest::Select(bond_slot)
+        });
+        pin_mut!(bonded_future);
+
+        let request_future = select(request_future, bonded_future).map(|either| either.factor_first().0);
+
+        let request = match hosts.profile() {
+            // Leave pairing mode if no new host bonded in time.
+            ProfileRequest::Pair(..) => {
+                let timeout_future = Timer::after(<crate::Used as Keyboard>::PAIRING_TIMEOUT);
+                pin_mut!(timeout_future);
+
+                match select(request_future, timeout_future).await {
+                    Either::Left((request, _)) => request,
+                    Either::Right(..) => {
+                        defmt::info!("Pairing mode timed out");
+                        ProfileRequest::Select(stored_profile(flash_token))
+                    }
+                }
+            }
+            ProfileRequest::Select(..) => request_future.await,
+        };
+
+        defmt::info!("Switching host profile: {}", request);
+
+        if let ProfileRequest::Select(bond_slot) = request {
+            store_active_profile(Side::Both, bond_slot).await;
+        }
+
+        let previous_index = hosts.active_index();
+        let previous_connection = hosts.active_connection();
+
+        hosts.set_profile(request);
+        hosts.update_protocol_mode(server);
+        show_pairing_mode(matches!(request, ProfileRequest::Pair(..))).await;
 
-    pin_mut!(inner_future);
-    pin_mut!(client_future);
+        // Reports that were queued for the previous host are meaningless to the new
+        // one.
+        if hosts.active_index() != previous_index {
+            clear_reports();
 
-    // FIX: match ?
-    let _ = select(inner_future, client_future).await;
+            if let Some((connection, protocol_mode)) = previous_connection {
+                release_all_keys(server, &connection, protocol_mode);
+            }
+        }
+    }
+}
+
+// Show on the status LEDs of the master if the keyboard is in pairing mode.
+async fn show_pairing_mode(pairing: bool) {
+    #[cfg(feature = "lighting")]
+    match pairing {
+        true => {
+            set_animation(
+                Side::This,
+                <crate::Used as Keyboard>::STATUS_LEDS,
+                <crate::Used as Keyboard>::PAIRING_ANIMATION,
+            )
+            .await
+        }
+        false => {
+            set_animation(
+                Side::This,
+                <crate::Used as Keyboard>::STATUS_LEDS,
+                <crate::Used as Keyboard>::MASTER_ANIMATION,
+            )
+            .await
+        }
+    }
 
-    Err(HalfDisconnected)
+    #[cfg(not(feature = "lighting"))]
+    let _ = pairing;
 }
 
 async fn master_scan(
     keyboard: &mut crate::Used,
     state: &mut MasterState,
     matrix_pins: &mut MatrixPins<'_, { <crate::Used as Scannable>::COLUMNS }, { <crate::Used as Scannable>::ROWS }>,
-    communication_server: &CommunicationServer,
-    slave_connection: &Connection,
-) -> Result<(Vec<ActiveModifier, 8>, usize, u64, u64), HalfDisconnected> {
-    let event_sender = event_sender();
-    let flash_sender = flash_sender();
-    let power_sender = power_sender();
-    #[cfg(feature = "lighting")]
-    let lighting_sender = lighting_sender();
+) -> (Vec<ActiveModifier, 8>, usize, u64, u64) {
+    let slave_key_state_receiver = slave_key_state_receiver();
     let event_receiver = event_receiver();
 
     enum ScanEvent {
-        KeyState(u64, u64),
+        KeyState(u64, u64, u64),
         Event(UsedEvent),
     }
 
@@ -179,96 +511,46 @@ async fn master_scan(
         let scan_event = {
             // Create futures.
             let scan_future = crate::hardware::do_scan(state, matrix_pins).fuse();
-            let slave_future = gatt_server::run_until(slave_connection, communication_server, |event| match event {
-                CommunicationServerEvent::KeyStateService(event) => match event {
-                    KeyStateServiceEvent::KeyStateWrite(key_state) => ControlFlow::Break(key_state),
-                },
-                CommunicationServerEvent::FlashService(event) => match event {
-                    FlashServiceEvent::FlashOperationWrite(flash_operation) => {
-                        defmt::debug!("Received flash operation {:?}", flash_operation);
-
-                        if flash_sender.try_send(flash_operation).is_err() {
-                            defmt::error!("Failed to send flash operation to the flash task");
-                        }
-
-                        ControlFlow::Continue(())
-                    }
-                },
-                CommunicationServerEvent::PowerService(event) => match event {
-                    PowerServiceEvent::PowerOperationWrite(power_operation) => {
-                        defmt::debug!("Received power operation {:?}", power_operation);
-
-                        if power_sender.try_send(power_operation).is_err() {
-                            defmt::error!("Failed to send power operation to the power task");
-                        }
-
-                        ControlFlow::Continue(())
-                    }
-                },
-                CommunicationServerEvent::EventService(event) => match event {
-                    EventServiceEvent::EventWrite(event) => {
-                        defmt::debug!("Received event {:?}", event);
-
-                        if event_sender.try_send(event).is_err() {
-                            defmt::error!("Failed to send event");
-                        }
-
-                        ControlFlow::Continue(())
-                    }
-                },
-                #[cfg(feature = "lighting")]
-                CommunicationServerEvent::LightingService(event) => match event {
-                    LightingServiceEvent::LightingOperationWrite(lighting_operation) => {
-                        defmt::debug!("Received lighting operation {:?}", lighting_operation);
-
-                        if lighting_sender.try_send(lighting_operation).is_err() {
-                            defmt::error!("Failed to send lighting operation to the lighting task");
-                        }
-
-                        ControlFlow::Continue(())
-                    }
-                },
-            })
-            .fuse();
+            let slave_future = slave_key_state_receiver.recv().fuse();
             let event_future = event_receiver.recv().fuse();
 
             pin_mut!(scan_future);
             pin_mut!(slave_future);
             pin_mut!(event_future);
 
+            // Changes of the slave that already arrived happened before the current time,
+            // so they are applied before a change of the master to keep the order.
             futures::select_biased! {
-                key_state = scan_future => {
+                (key_state, time) = slave_future => {
                     #[cfg(feature = "left")]
-                    let combined_state = slave_raw_state | (key_state << <crate::Used as KeyboardExtension>::KEYS_PER_SIDE);
+                    let combined_state = (master_raw_state << <crate::Used as KeyboardExtension>::KEYS_PER_SIDE) | key_state;
 
                     #[cfg(feature = "right")]
-                    let combined_state = (slave_raw_state << <crate::Used as KeyboardExtension>::KEYS_PER_SIDE) | key_state;
+                    let combined_state = master_raw_state | (key_state << <crate::Used as KeyboardExtension>::KEYS_PER_SIDE);
 
-                    ScanEvent::KeyState(combined_state, slave_raw_state)
+                    ScanEvent::KeyState(combined_state, key_state, time)
                 }
-                key_state = slave_future => {
-                    let key_state = key_state.map_err(|_| HalfDisconnected)?;
-
+                key_state = scan_future => {
                     #[cfg(feature = "left")]
-                    let combined_state = (master_raw_state << <crate::Used as KeyboardExtension>::KEYS_PER_SIDE) | key_state;
+                    let combined_state = slave_raw_state | (key_state << <crate::Used as KeyboardExtension>::KEYS_PER_SIDE);
 
                     #[cfg(feature = "right")]
-                    let combined_state = master_raw_state | (key_state << <crate::Used as KeyboardExtension>::KEYS_PER_SIDE);
+                    let combined_state = (slave_raw_state << <crate::Used as KeyboardExtension>::KEYS_PER_SIDE) | key_state;
 
-                    ScanEvent::KeyState(combined_state, key_state)
+                    ScanEvent::KeyState(combined_state, slave_raw_state, now())
                 }
                 event = event_future => ScanEvent::Event(event),
             }
         };
 
         match scan_event {
-            ScanEvent::KeyState(key_state, slave_raw_state) => {
+            ScanEvent::KeyState(key_state, slave_raw_state, time) => {
                 // We do this update down here because we cannot mutably access the state inside
                 // of the scope above.
                 state.slave_raw_state = slave_raw_state;
 
-                if let Some(output_state) = state.apply(keyboard, key_state).await {
-                    return Ok(output_state);
+                if let Some(output_state) = state.apply(keyboard, key_state, time).await {
+                    return output_state;
                 }
             }
             ScanEvent::Event(event) => {
@@ -283,62 +565,220 @@ async fn update_master_state(
     state: &mut MasterState,
     matrix_pins: &mut MatrixPins<'_, { <crate::Used as Scannable>::COLUMNS }, { <crate::Used as Scannable>::ROWS }>,
     server: &Server,
-    communication_server: &CommunicationServer,
-    slave_connection: &Connection,
-    host_connection: &Connection,
-) -> Result<(), HalfDisconnected> {
+    hosts: &Hosts,
+    bonder: &Bonder,
+    flash_token: FlashToken,
+) -> ! {
     let battery_level_receiver = battery_level_receiver();
+    let slave_battery_level_receiver = slave_battery_level_receiver();
+
+    // Holding the pairing keys while the keyboard starts enters pairing mode.
+    let started_at = Instant::now();
+    let mut check_pairing_keys = !<crate::Used as Keyboard>::PAIRING_KEYS.is_empty();
+
+    // Keep track of the state that host applications can query over raw HID.
+    let mut current_layer = 0;
+    let mut current_battery_level = None;
+    let mut current_slave_battery_level = None;
+
+    // While a host waits for a passkey, the input is used to type it instead of
+    // being sent to the host.
+    let mut passkey_input: Option<PasskeyInput> = None;
+
+    enum MasterEvent {
+        Scan((Vec<ActiveModifier, 8>, usize, u64, u64)),
+        BatteryLevel(BatteryLevel),
+        SlaveBatteryLevel(BatteryLevel),
+        Suspend(bool),
+        RawHid(usize, RawHidReport),
+    }
 
     loop {
-        let scan_future = master_scan(keyboard, state, matrix_pins, communication_server, slave_connection);
-        let battery_level_future = battery_level_receiver.recv();
+        let master_event = {
+            let scan_future = master_scan(keyboard, state, matrix_pins).fuse();
+            let battery_level_future = battery_level_receiver.recv().fuse();
+            let slave_battery_level_future = slave_battery_level_receiver.recv().fuse();
+            let suspend_future = hosts.suspend_changed().fuse();
+            let raw_hid_future = hosts.raw_hid_request().fuse();
+
+            pin_mut!(scan_future);
+            pin_mut!(battery_level_future);
+            pin_mut!(slave_battery_level_future);
+            pin_mut!(suspend_future);
+            pin_mut!(raw_hid_future);
+
+            futures::select_biased! {
+                output_state = scan_future => MasterEvent::Scan(output_state),
+                battery_level = battery_level_future => MasterEvent::BatteryLevel(battery_level),
+                battery_level = slave_battery_level_future => MasterEvent::SlaveBatteryLevel(battery_level),
+                suspended = suspend_future => MasterEvent::Suspend(suspended),
+                (index, report) = raw_hid_future => MasterEvent::RawHid(index, report),
+            }
+        };
+
+        match master_event {
+            MasterEvent::Scan((active_modifiers, active_layer, key_state, injected_keys)) => {
+                current_layer = active_layer;
+                register_activity();
+
+                if check_pairing_keys {
+                    let raw_key_state = state.raw_key_state();
+                    check_pairing_keys = started_at.elapsed() < PAIRING_KEYS_WINDOW;
+
+                    if check_pairing_keys
+                        && <crate::Used as Keyboard>::PAIRING_KEYS
+                            .iter()
+                            .all(|key| raw_key_state.test_bit(*key))
+                    {
+                        defmt::info!("Pairing keys held on startup");
+                        enter_pairing_mode();
+                        check_pairing_keys = false;
+                    }
+                }
+
+                if bonder.is_entering_passkey() {
+                    let report = build_input_report(&active_modifiers, active_layer, key_state);
 
-        pin_mut!(scan_future);
-        pin_mut!(battery_level_future);
+                    if passkey_input.is_none() {
+                        defmt::debug!("Starting passkey entry");
 
-        match select(scan_future, battery_level_future).await {
-            Either::Left((result, _)) => {
-                let (active_modifiers, active_layer, key_state, injected_keys) = result?;
+                        // Release all keys, so the host does not see keys that are held while typing
+                        // the passkey.
+                        queue_report([0; KEYBOARD_INPUT_REPORT_SIZE]).await;
+                    }
+
+                    passkey_input.get_or_insert_with(PasskeyInput::new).update(bonder, &report);
+
+                    continue;
+                }
+
+                passkey_input = None;
 
                 // If there are any, send the input once with the injected keys.
                 if injected_keys != 0 {
-                    //let input_report = InputReport::new(active_layer, key_state |
-                    // injected_keys); defmt::unwrap!(server.hid_service.
-                    // input_report_notify(&host_connection, &input_report));
-                    send_input_report(
-                        server,
-                        &host_connection,
-                        &active_modifiers,
-                        active_layer,
-                        key_state | injected_keys,
-                    );
+                    queue_report(build_input_report(&active_modifiers, active_layer, key_state | injected_keys)).await;
+                }
+
+                queue_report(build_input_report(&active_modifiers, active_layer, key_state)).await;
+            }
+            MasterEvent::BatteryLevel(battery_level) => {
+                current_battery_level = Some(battery_level.0);
+                report_battery_level(server, hosts, Side::This, &battery_level);
+
+                if let Some(slave_battery_level) = &current_slave_battery_level {
+                    check_battery_levels(&battery_level, slave_battery_level);
+                }
+            }
+            MasterEvent::SlaveBatteryLevel(battery_level) => {
+                report_battery_level(server, hosts, Side::Other, &battery_level);
+
+                if let Some(master_battery_level) = current_battery_level {
+                    check_battery_levels(&BatteryLevel(master_battery_level), &battery_level);
                 }
 
-                //let input_report = InputReport::new(active_layer, key_state);
-                //defmt::unwrap!(server.hid_service.input_report_notify(&host_connection,
-                // &input_report));
-                send_input_report(server, &host_connection, &active_modifiers, active_layer, key_state);
+                current_slave_battery_level = Some(battery_level);
             }
-            Either::Right((battery_level, _)) => {
-                match server.battery_service.battery_level_notify(host_connection, &battery_level.0) {
-                    Ok(..) => {}
-                    Err(NotifyValueError::Disconnected) => return Err(HalfDisconnected),
-                    Err(error) => defmt::warn!("Error when sending battery level: {:?}", error),
+            MasterEvent::Suspend(suspended) => {
+                keyboard.host_suspended(suspended).await;
+            }
+            MasterEvent::RawHid(index, report) => {
+                let (command, request) = HostRequest::parse(&report);
+                let mut response = RawHidResponse::new();
+
+                match request {
+                    Ok(HostRequest::GetLayer) => response.push(&[current_layer as u8]),
+                    // If the battery level was not measured yet, the response is empty.
+                    Ok(HostRequest::GetBattery) => {
+                        if let Some(battery_level) = current_battery_level {
+                            response.push(&[battery_level]);
+                        }
+                    }
+                    Ok(HostRequest::TriggerCallback(callback)) => keyboard.callback(callback).await,
+                    #[cfg(feature = "lighting")]
+                    Ok(HostRequest::SetLed { index, color }) => set_animation(Side::Both, index, Animation::Static { color }).await,
+                    // Respond with [used, last connected (4 bytes), name length, name..].
+                    Ok(HostRequest::GetBond(bond_slot)) => {
+                        let bond = &get_settings(flash_token).bonds[bond_slot.0];
+                        let name = bond.metadata.name();
+
+                        response.push(&[bond.is_used() as u8]);
+                        response.push(&bond.metadata.last_connected.to_le_bytes());
+                        response.push(&[name.len() as u8]);
+                        response.push(name);
+                    }
+                    // Respond with the negotiated parameters of the connection to the requesting host as
+                    // [interval (2 bytes), slave latency (2 bytes), supervision timeout (2 bytes)].
+                    Ok(HostRequest::GetConnection) => match hosts.connection(index) {
+                        Some(connection) => {
+                            let parameters = connection.conn_params();
+
+                            // Once the parameters are negotiated, the minimum and maximum interval are the
+                            // same.
+                            response.push(&parameters.min_conn_interval.to_le_bytes());
+                            response.push(&parameters.slave_latency.to_le_bytes());
+                            response.push(&parameters.conn_sup_timeout.to_le_bytes());
+                        }
+                        None => response.set_status(RawHidStatus::NotConnected),
+                    },
+                    // Respond with [sent, coalesced, delayed, lost], each as 4 bytes.
+                    Ok(HostRequest::GetReportStatistics) => {
+                        let statistics = report_statistics();
+
+                        response.push(&statistics.sent.to_le_bytes());
+                        response.push(&statistics.coalesced.to_le_bytes());
+                        response.push(&statistics.delayed.to_le_bytes());
+                        response.push(&statistics.lost.to_le_bytes());
+                    }
+                    Ok(HostRequest::Board(command)) => keyboard.host_command(command, &mut response).await,
+                    Err(status) => {
+                        defmt::warn!("Failed to handle raw HID request with command {}: {}", command, status);
+                        response.set_status(status);
+                    }
+                }
+
+                let response = response.finish(command);
+
+                // The host might have disconnected in the meantime.
+                let Some(connection) = hosts.connection(index) else {
+                    continue;
+                };
+
+                match server.hid_service.raw_input_report_notify(&connection, &response) {
+                    Ok(..) | Err(NotifyValueError::Disconnected) => {}
+                    Err(error) => defmt::warn!("Error when sending raw HID response: {:?}", error),
                 };
             }
         }
     }
 }
 
-pub fn send_input_report(
-    server: &Server,
-    connection: &Connection,
-    active_modifiers: &heapless::Vec<ActiveModifier, 8>,
-    active_layer: usize,
-    key_state: u64,
-) {
+// Update the battery level of one half and notify every connected host.
+fn report_battery_level(server: &Server, hosts: &Hosts, side: Side, battery_level: &BatteryLevel) {
+    // Resolve the side relative to this half to the left or right half.
+    let is_left = side.includes_this() == cfg!(feature = "left");
+
+    // Hosts that connect later can read the value.
+    match is_left {
+        true => defmt::unwrap!(server.left_battery_service.battery_level_set(&battery_level.0)),
+        false => defmt::unwrap!(server.right_battery_service.battery_level_set(&battery_level.0)),
+    }
+
+    for connection in hosts.connections() {
+        let result = match is_left {
+            true => server.left_battery_service.battery_level_notify(&connection, &battery_level.0),
+            false => server.right_battery_service.battery_level_notify(&connection, &battery_level.0),
+        };
+
+        match result {
+            Ok(..) | Err(NotifyValueError::Disconnected) => {}
+            Err(error) => defmt::warn!("Error when sending battery level: {:?}", error),
+        };
+    }
+}
+
+pub fn build_input_report(active_modifiers: &heapless::Vec<ActiveModifier, 8>, active_layer: usize, key_state: u64) -> KeyboardReport {
     const SCAN_CODE_POSITION: usize = 2;
-    const REPORT_SIZE: usize = 8;
+    const REPORT_SIZE: usize = KEYBOARD_INPUT_REPORT_SIZE;
 
     let mut input_report = [0; REPORT_SIZE];
     let mut offset = SCAN_CODE_POSITION;
@@ -376,7 +816,5 @@ pub fn send_input_report(
 
     input_report[0] |= modifiers.bits();
 
-    defmt::info!("Sending input report with value {:?}", input_report);
-
-    defmt::unwrap!(server.hid_service.input_report_notify(connection, &input_report));
+    input_report
 }
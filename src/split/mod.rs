@@ -1,12 +1,33 @@
-mod common;
+mod clock;
 mod determine;
 mod event;
+mod handover;
 mod master;
+mod partner;
 mod slave;
+mod transport;
 
 pub struct HalfDisconnected;
 
-pub use self::determine::{advertise_determine_master, connect_determine_master};
-pub use self::event::{event_receiver, other_event_receiver, trigger_event, EventReceiver, OtherEventReceiver, UsedEvent};
-pub use self::master::do_master;
+/// The roles of the halves changed.
+pub enum Handover {
+    /// The halves swapped their roles.
+    Swapped,
+    /// Both halves ran on their own, and this half joined the other one as the
+    /// slave.
+    Joined(Determined),
+}
+
+pub use logic::split::{
+    decode_message, encode_message, message_size, Candidate, Wire, WireError, WireMessage, WireReader, WireWriter, WIRE_VERSION,
+};
+
+#[cfg(feature = "lighting")]
+pub use self::clock::shared_time;
+pub use self::determine::{advertise_determine_master, connect_determine_master, Determined};
+pub use self::event::{event_receiver, trigger_event, EventReceiver, UsedEvent};
+pub use self::handover::request_handover;
+pub use self::master::{do_master, is_standalone};
+pub use self::partner::PartnerBonder;
 pub use self::slave::do_slave;
+pub use self::transport::{send_to_other_half, slave_key_state_receiver, try_send_to_other_half, Frame, SplitMessage};
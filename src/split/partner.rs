@@ -0,0 +1,380 @@
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration};
+use nrf_softdevice::ble::security::{IoCapabilities, SecurityHandler};
+use nrf_softdevice::ble::{central, peripheral, Address, Connection, EncryptionInfo, IdentityKey, MasterId, SecurityMode};
+use nrf_softdevice::{raw, Softdevice};
+
+use crate::ble::{advertises_service, AdvertisingData, DutyCycle, Identity, MASTER_SERVICE_UUID};
+use crate::flash::{get_settings, try_store_partner, FlashToken, Peer};
+use crate::hardware::MatrixPins;
+use crate::interface::Scannable;
+use crate::side::Side;
+
+// Until the halves are paired, the left half advertises the master service, so
+// the right half can find it without knowing its address.
+const SPLIT_ADVERTISING_DATA: AdvertisingData = AdvertisingData::new()
+    .add_flags(raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8)
+    .add_service_uuid(&MASTER_SERVICE_UUID);
+
+// Time to wait for the link to the other half to be encrypted before trying
+// again.
+const ENCRYPTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection parameters for the link between the halves. Key states need to
+/// arrive quickly, and a broken link should be noticed within a second.
+pub fn partner_connection_parameters() -> raw::ble_gap_conn_params_t {
+    let mut conn_params = central::ConnectConfig::default().conn_params;
+    conn_params.min_conn_interval = 6;
+    conn_params.max_conn_interval = 6;
+    conn_params.conn_sup_timeout = 100; // 1 second timeout
+    conn_params
+}
+
+/// Security handler for the link between the halves. The halves pair once and
+/// store each other as partner. After that, they only connect to their partner
+/// and every link between them is encrypted.
+pub struct PartnerBonder {
+    flash_token: FlashToken,
+    encrypted: Signal<ThreadModeRawMutex, ()>,
+}
+
+impl PartnerBonder {
+    pub fn new(flash_token: FlashToken) -> Self {
+        Self {
+            flash_token,
+            encrypted: Signal::new(),
+        }
+    }
+
+    fn partner(&self) -> Option<&'static Peer> {
+        get_settings(self.flash_token).partner()
+    }
+
+    // Wait until the link is encrypted. Returns `false` if the other half does not
+    // have a matching key.
+    async fn wait_for_encryption(&self) -> bool {
+        with_timeout(ENCRYPTION_TIMEOUT, self.encrypted.wait()).await.is_ok()
+    }
+}
+
+impl SecurityHandler for PartnerBonder {
+    fn io_capabilities(&self) -> IoCapabilities {
+        IoCapabilities::None
+    }
+
+    // Only pair with a new half if this half does not have a partner yet.
+    fn can_bond(&self, _conn: &Connection) -> bool {
+        self.partner().is_none()
+    }
+
+    fn on_bonded(&self, _conn: &Connection, master_id: MasterId, key: EncryptionInfo, peer_id: IdentityKey) {
+        defmt::info!("Paired with other half with address {}", peer_id.addr);
+
+        // Every half stores the key of its partner.
+        try_store_partner(Side::This, Peer { master_id, key, peer_id });
+    }
+
+    fn get_key(&self, _conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
+        self.partner()
+            .filter(|partner| partner.master_id == master_id)
+            .map(|partner| partner.key)
+    }
+
+    fn get_peripheral_key(&self, _conn: &Connection) -> Option<(MasterId, EncryptionInfo)> {
+        self.partner().map(|partner| (partner.master_id, partner.key))
+    }
+
+    fn on_security_update(&self, _conn: &Connection, security_mode: SecurityMode) {
+        defmt::debug!("Security of the link to the other half changed to {}", security_mode);
+
+        if !matches!(security_mode, SecurityMode::NoAccess | SecurityMode::Open) {
+            self.encrypted.signal(());
+        }
+    }
+}
+
+// Centrals that may connect once the halves are paired. Until then, any half
+// may connect, so a new half can pair.
+#[derive(Clone, Copy)]
+enum AllowedCentral<'a> {
+    // The partner, with the address it paired with.
+    Partner,
+    // The master, with a private address of the identity it presents to hosts.
+    Master(&'a Identity),
+    // Any central. Only the partner can encrypt the link anyway.
+    Anyone,
+}
+
+// Only allow the given address to connect when advertising with a filter
+// policy.
+fn whitelist_address(address: &Address) {
+    let address = address.into_raw();
+    let pointers = [&address as *const raw::ble_gap_addr_t];
+
+    let result = unsafe { raw::sd_ble_gap_whitelist_set(pointers.as_ptr(), pointers.len() as u8) };
+
+    if result != raw::NRF_SUCCESS {
+        defmt::error!("Failed to set whitelist: {}", result);
+    }
+}
+
+// Only allow the master to connect when advertising with a filter policy. The
+// softdevice resolves its private address with the key of the identity, so it
+// can be found on the whitelist.
+fn whitelist_master(identity: &Identity) {
+    let identity_key = raw::ble_gap_id_key_t {
+        id_info: raw::ble_gap_irk_t { irk: identity.key },
+        id_addr_info: identity.address.into_raw(),
+    };
+    let pointers = [&identity_key as *const raw::ble_gap_id_key_t];
+
+    // Passing no local keys uses the key of our own identity.
+    let result = unsafe { raw::sd_ble_gap_device_identities_set(pointers.as_ptr(), core::ptr::null(), pointers.len() as u8) };
+
+    if result != raw::NRF_SUCCESS {
+        defmt::error!("Failed to set device identities: {}", result);
+    }
+
+    whitelist_address(&identity.address);
+}
+
+// Scan for a half that is advertising the master service.
+async fn find_other_half(softdevice: &Softdevice, duty_cycle: DutyCycle) -> Address {
+    let mut config = central::ScanConfig::default();
+    duty_cycle.configure_scanning(&mut config);
+
+    defmt::debug!("Start scanning");
+
+    defmt::unwrap!(
+        central::scan(softdevice, &config, |report| {
+            let data = unsafe { core::slice::from_raw_parts(report.data.p_data, report.data.len as usize) };
+            advertises_service(data, &MASTER_SERVICE_UUID).then(|| Address::from_raw(report.peer_addr))
+        })
+        .await
+    )
+}
+
+// Move on to the next duty cycle. If the search stopped, wait for a key press
+// on this half before searching quickly again.
+async fn next_duty_cycle(
+    duty_cycle: DutyCycle,
+    matrix_pins: &mut MatrixPins<'_, { <crate::Used as Scannable>::COLUMNS }, { <crate::Used as Scannable>::ROWS }>,
+) -> DutyCycle {
+    match duty_cycle.next() {
+        Some(duty_cycle) => duty_cycle,
+        None => {
+            defmt::info!("Other half not found, searching again once a key is pressed");
+            matrix_pins.wait_for_key_press().await;
+            DutyCycle::Fast
+        }
+    }
+}
+
+// Advertise with the given duty cycle until the other half connected over an
+// encrypted link. Returns `None` if the other half did not connect in time.
+async fn advertise_with_duty_cycle(
+    softdevice: &Softdevice,
+    bonder: &'static PartnerBonder,
+    duty_cycle: DutyCycle,
+    allowed_central: AllowedCentral<'_>,
+) -> Option<Connection> {
+    let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
+        adv_data: SPLIT_ADVERTISING_DATA.get_slice(),
+        scan_data: SPLIT_ADVERTISING_DATA.get_scan_response_slice(),
+    };
+
+    let search_future = async {
+        loop {
+            let mut config = peripheral::Config::default();
+            duty_cycle.configure_advertising(&mut config);
+
+            match (bonder.partner(), allowed_central) {
+                (None, _) => defmt::info!("Halves are not paired, waiting for a new half"),
+                (Some(partner), AllowedCentral::Partner) => {
+                    whitelist_address(&partner.peer_id.addr);
+                    config.filter_policy = peripheral::FilterPolicy::Both;
+                }
+                (Some(..), AllowedCentral::Master(identity)) => {
+                    whitelist_master(identity);
+                    config.filter_policy = peripheral::FilterPolicy::Both;
+                }
+                (Some(..), AllowedCentral::Anyone) => {}
+            }
+
+            bonder.encrypted.reset();
+
+            defmt::debug!("Start advertising with {} duty cycle", duty_cycle);
+
+            let connection = defmt::unwrap!(peripheral::advertise_pairable(softdevice, adv, &config, bonder).await);
+
+            if bonder.wait_for_encryption().await {
+                return connection;
+            }
+
+            defmt::warn!("Failed to encrypt the link to the other half");
+            let _ = connection.disconnect();
+        }
+    };
+
+    with_timeout(duty_cycle.search_timeout(), search_future).await.ok()
+}
+
+// Connect to the other half with the given duty cycle over an encrypted link.
+// Returns `None` if the other half was not found in time.
+async fn connect_with_duty_cycle(
+    softdevice: &Softdevice,
+    bonder: &'static PartnerBonder,
+    conn_params: raw::ble_gap_conn_params_t,
+    duty_cycle: DutyCycle,
+) -> Option<Connection> {
+    let search_future = async {
+        loop {
+            defmt::debug!("Searching for the other half with {} duty cycle", duty_cycle);
+
+            let address = match bonder.partner() {
+                Some(partner) => partner.peer_id.addr,
+                None => {
+                    defmt::info!("Halves are not paired, searching for a new half");
+                    find_other_half(softdevice, duty_cycle).await
+                }
+            };
+
+            let addresses = [&address];
+            let mut config = central::ConnectConfig::default();
+            config.scan_config.whitelist = Some(&addresses);
+            duty_cycle.configure_scanning(&mut config.scan_config);
+            config.conn_params = conn_params;
+
+            bonder.encrypted.reset();
+
+            let connection = defmt::unwrap!(central::connect_with_security(softdevice, &config, bonder).await);
+
+            // The central starts encryption, which pairs the halves if they were not paired
+            // before.
+            if let Err(error) = connection.request_security() {
+                defmt::warn!("Failed to request security from the other half: {:?}", error);
+            }
+
+            if bonder.wait_for_encryption().await {
+                return connection;
+            }
+
+            defmt::warn!("Failed to encrypt the link to the other half");
+            let _ = connection.disconnect();
+        }
+    };
+
+    with_timeout(duty_cycle.search_timeout(), search_future).await.ok()
+}
+
+/// Advertise until the other half connected over an encrypted link. If the
+/// halves are paired, only the partner may connect.
+pub async fn advertise_to_partner(
+    softdevice: &Softdevice,
+    bonder: &'static PartnerBonder,
+    matrix_pins: &mut MatrixPins<'_, { <crate::Used as Scannable>::COLUMNS }, { <crate::Used as Scannable>::ROWS }>,
+) -> Connection {
+    let mut duty_cycle = DutyCycle::Fast;
+
+    loop {
+        if let Some(connection) = advertise_with_duty_cycle(softdevice, bonder, duty_cycle, AllowedCentral::Partner).await {
+            return connection;
+        }
+
+        duty_cycle = next_duty_cycle(duty_cycle, matrix_pins).await;
+    }
+}
+
+/// Connect to the other half over an encrypted link. If the halves are
+/// paired, only the partner is connected to.
+pub async fn connect_to_partner(
+    softdevice: &Softdevice,
+    bonder: &'static PartnerBonder,
+    conn_params: raw::ble_gap_conn_params_t,
+    matrix_pins: &mut MatrixPins<'_, { <crate::Used as Scannable>::COLUMNS }, { <crate::Used as Scannable>::ROWS }>,
+) -> Connection {
+    let mut duty_cycle = DutyCycle::Fast;
+
+    loop {
+        if let Some(connection) = connect_with_duty_cycle(softdevice, bonder, conn_params, duty_cycle).await {
+            return connection;
+        }
+
+        duty_cycle = next_duty_cycle(duty_cycle, matrix_pins).await;
+    }
+}
+
+/// Advertise to the master after the link to it broke. The master keeps
+/// serving hosts in the meantime, so it connects as the central with a
+/// private address of the identity it presents to hosts. Returns `None` if
+/// the master did not connect before the search stopped.
+pub async fn advertise_to_master(softdevice: &Softdevice, bonder: &'static PartnerBonder, identity: &Identity) -> Option<Connection> {
+    let mut duty_cycle = Some(DutyCycle::Fast);
+
+    while let Some(current_duty_cycle) = duty_cycle {
+        if let Some(connection) = advertise_with_duty_cycle(softdevice, bonder, current_duty_cycle, AllowedCentral::Master(identity)).await
+        {
+            return Some(connection);
+        }
+
+        duty_cycle = current_duty_cycle.next();
+    }
+
+    None
+}
+
+/// Connect to the slave after the link to it broke. Returns `None` if the
+/// slave was not found before the search stopped.
+pub async fn reconnect_to_slave(
+    softdevice: &Softdevice,
+    bonder: &'static PartnerBonder,
+    conn_params: raw::ble_gap_conn_params_t,
+) -> Option<Connection> {
+    let mut duty_cycle = Some(DutyCycle::Fast);
+
+    while let Some(current_duty_cycle) = duty_cycle {
+        if let Some(connection) = connect_with_duty_cycle(softdevice, bonder, conn_params, current_duty_cycle).await {
+            return Some(connection);
+        }
+
+        duty_cycle = current_duty_cycle.next();
+    }
+
+    None
+}
+
+/// Advertise to a master that already runs on its own, so this half can join
+/// it as the slave. The master presents the given identity to hosts, if this
+/// half knows it. Returns `None` if no master connected in time.
+pub async fn join_running_master(
+    softdevice: &Softdevice,
+    bonder: &'static PartnerBonder,
+    identity: Option<&Identity>,
+) -> Option<Connection> {
+    let allowed_central = match identity {
+        Some(identity) => AllowedCentral::Master(identity),
+        None => AllowedCentral::Anyone,
+    };
+
+    advertise_with_duty_cycle(softdevice, bonder, DutyCycle::Fast, allowed_central).await
+}
+
+/// Connect to the other half while the master runs on its own. The other
+/// half might only be switched on much later, so the search does not stop,
+/// but it drops to the slow duty cycle to save battery.
+pub async fn connect_to_joining_slave(
+    softdevice: &Softdevice,
+    bonder: &'static PartnerBonder,
+    conn_params: raw::ble_gap_conn_params_t,
+) -> Connection {
+    let mut duty_cycle = DutyCycle::Fast;
+
+    loop {
+        if let Some(connection) = connect_with_duty_cycle(softdevice, bonder, conn_params, duty_cycle).await {
+            return connection;
+        }
+
+        duty_cycle = DutyCycle::Slow;
+    }
+}
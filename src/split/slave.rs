@@ -1,179 +1,161 @@
-use core::convert::Infallible;
-
-use futures::future::select;
+use embassy_time::with_timeout;
+use futures::future::{join, select, Either};
 use futures::{pin_mut, FutureExt};
-use nrf_softdevice::ble::{central, Address, Connection};
+use nrf_softdevice::ble::{gatt_client, Connection};
 use nrf_softdevice::Softdevice;
 
-use super::event::event_sender;
-use super::{event_receiver, HalfDisconnected};
-use crate::ble::{
-    CommunicationServer, CommunicationServerEvent, EventServiceClient, EventServiceEvent, FlashServiceClient, FlashServiceEvent,
-    KeyStateServiceClient, KeyStateServiceEvent, PowerServiceClient, PowerServiceEvent,
-};
-#[cfg(feature = "lighting")]
-use crate::ble::{LightingServiceClient, LightingServiceEvent};
-use crate::flash::flash_sender;
+use super::clock::{master_time, reset_clock, synchronize_clock};
+use super::handover::{acceptance_acknowledged, handover_ordered, reset_handover, HANDOVER_TIMEOUT};
+use super::partner::{advertise_to_master, connect_to_partner, partner_connection_parameters, PartnerBonder};
+use super::transport::{reset_transport, run_transport};
+use super::{event_receiver, send_to_other_half, HalfDisconnected, Handover, SplitMessage};
+use crate::battery::battery_level_receiver;
+use crate::ble::{CommunicationServer, Identity, TransportServiceClient};
 use crate::hardware::{MatrixPins, SlaveState};
 use crate::interface::{Keyboard, Scannable};
 #[cfg(feature = "lighting")]
-use crate::led::lighting_sender;
-use crate::power::power_sender;
+use crate::led::set_animation;
+#[cfg(feature = "lighting")]
+use crate::side::Side;
 
 pub async fn do_slave(
     softdevice: &Softdevice,
     keyboard: &mut crate::Used,
     communication_server: &CommunicationServer,
     matrix_pins: &mut MatrixPins<'_, { <crate::Used as Scannable>::COLUMNS }, { <crate::Used as Scannable>::ROWS }>,
-    address: &Address,
-) -> Result<Infallible, HalfDisconnected> {
+    bonder: &'static PartnerBonder,
+    identity: &Identity,
+    master_connection: Option<Connection>,
+) -> Result<Handover, HalfDisconnected> {
     defmt::debug!("Stating slave");
 
-    keyboard.pre_sides_connected(false).await;
-
-    // Connect to the other half
-    let addresses = [address];
-    let mut config = central::ConnectConfig::default();
-    config.scan_config.whitelist = Some(&addresses);
-    config.conn_params.min_conn_interval = 6;
-    config.conn_params.max_conn_interval = 6;
-    config.conn_params.conn_sup_timeout = 100; // 1 second timeout
-
-    let master_connection = defmt::unwrap!(central::connect(softdevice, &config).await);
+    reset_handover();
+    reset_transport();
+    reset_clock();
 
-    // Get the flash client of the other side.
-    let flash_client: FlashServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&master_connection).await);
-    let other_flash_operations = crate::flash::other_flash_receiver();
+    keyboard.pre_sides_connected(false).await;
 
-    // Get the power client of the other side.
-    let power_client: PowerServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&master_connection).await);
-    let other_power_operations = crate::power::other_power_receiver();
+    // Connect to the other half, unless this half already joined a master that ran
+    // on its own.
+    let mut master_connection = match master_connection {
+        Some(connection) => connection,
+        None => connect_to_partner(softdevice, bonder, partner_connection_parameters(), matrix_pins).await,
+    };
 
-    // Get the led client of the other side.
-    #[cfg(feature = "lighting")]
-    let lighting_client: LightingServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&master_connection).await);
-    #[cfg(feature = "lighting")]
-    let other_lighting_operations = crate::led::other_lighting_receiver();
+    defmt::info!("Connected to other half");
 
-    // Get the event client of the other side.
-    let event_client: EventServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&master_connection).await);
-    let other_events = crate::split::event::other_event_receiver();
+    keyboard.post_sides_connected(false).await;
 
-    //
-    let key_state_client: KeyStateServiceClient = defmt::unwrap!(nrf_softdevice::ble::gatt_client::discover(&master_connection).await);
+    loop {
+        match gatt_client::discover::<TransportServiceClient>(&master_connection).await {
+            Ok(client) => {
+                // Start from a fresh state, so keys that are held after reconnecting are sent
+                // again.
+                let mut keyboard_state = SlaveState::new();
+
+                let state_future = join(
+                    update_slave_state(keyboard, &mut keyboard_state, matrix_pins),
+                    synchronize_clock(),
+                );
+                let transport_future = run_transport(&master_connection, communication_server, &client);
+                let handover_future = handover_ordered();
+
+                pin_mut!(state_future);
+                pin_mut!(transport_future);
+                pin_mut!(handover_future);
+
+                // The transport future returns if the link broke, the handover future if the
+                // master asked us to take over.
+                if let Either::Right((_, transport_future)) = select(transport_future, select(state_future, handover_future)).await {
+                    defmt::info!("Accepting the handover");
+
+                    send_to_other_half(SplitMessage::HandoverAccepted).await;
+
+                    // The master keeps serving hosts until it received the acceptance, so we only
+                    // take over once it acknowledged it. Otherwise we stay the slave.
+                    let acknowledged_future = acceptance_acknowledged();
+                    pin_mut!(acknowledged_future);
+
+                    let acknowledged = matches!(
+                        with_timeout(HANDOVER_TIMEOUT, select(transport_future, acknowledged_future)).await,
+                        Ok(Either::Right(..))
+                    );
+
+                    let _ = master_connection.disconnect();
+
+                    if acknowledged {
+                        defmt::info!("Taking over as master");
+                        return Ok(Handover::Swapped);
+                    }
 
-    defmt::info!("Connected to other half");
+                    defmt::warn!("Master did not acknowledge the handover");
+                }
+            }
+            Err(error) => {
+                defmt::error!("Failed to discover the transport service of the master: {:?}", error);
+                let _ = master_connection.disconnect();
+            }
+        }
 
-    keyboard.post_sides_connected(false).await;
+        defmt::warn!("Link to the master broke, waiting for it to reconnect");
 
-    let mut keyboard_state = SlaveState::new();
-
-    let connection_future = slave_connection(
-        keyboard,
-        &mut keyboard_state,
-        matrix_pins,
-        &master_connection,
-        communication_server,
-        &key_state_client,
-    );
-    let client_future = super::common::run_clients(
-        &flash_client,
-        &other_flash_operations,
-        &power_client,
-        &other_power_operations,
         #[cfg(feature = "lighting")]
-        &lighting_client,
-        #[cfg(feature = "lighting")]
-        &other_lighting_operations,
-        &event_client,
-        &other_events,
-    );
+        set_animation(
+            Side::This,
+            <crate::Used as Keyboard>::STATUS_LEDS,
+            <crate::Used as Keyboard>::DISCONNECTED_ANIMATION,
+        )
+        .await;
 
-    pin_mut!(connection_future);
-    pin_mut!(client_future);
+        // The master keeps serving hosts, so the halves keep their roles.
+        master_connection = match advertise_to_master(softdevice, bonder, identity).await {
+            Some(connection) => connection,
+            None => return Err(HalfDisconnected),
+        };
 
-    // FIX: match ?
-    let _ = select(connection_future, client_future).await;
+        defmt::info!("Reconnected to master");
 
-    Err(HalfDisconnected)
+        #[cfg(feature = "lighting")]
+        set_animation(
+            Side::This,
+            <crate::Used as Keyboard>::STATUS_LEDS,
+            <crate::Used as Keyboard>::SLAVE_ANIMATION,
+        )
+        .await;
+    }
 }
 
-async fn slave_connection(
+async fn update_slave_state(
     keyboard: &mut crate::Used,
     state: &mut SlaveState,
     matrix_pins: &mut MatrixPins<'_, { <crate::Used as Scannable>::COLUMNS }, { <crate::Used as Scannable>::ROWS }>,
-    master_connection: &Connection,
-    communication_server: &CommunicationServer,
-    key_state_client: &KeyStateServiceClient,
-) -> Result<Infallible, HalfDisconnected> {
-    let event_sender = event_sender();
-    let flash_sender = flash_sender();
-    let power_sender = power_sender();
-    #[cfg(feature = "lighting")]
-    let lighting_sender = lighting_sender();
-
+) -> ! {
     let event_receiver = event_receiver();
+    let battery_level_receiver = battery_level_receiver();
 
     loop {
         // Returns any time there is any change in the key state. This state is already
         // debounced.
         let scan_future = crate::hardware::do_scan(state, matrix_pins).fuse();
         let event_future = event_receiver.recv().fuse();
-        let connection_future = nrf_softdevice::ble::gatt_server::run(&master_connection, communication_server, |event| match event {
-            CommunicationServerEvent::KeyStateService(event) => match event {
-                KeyStateServiceEvent::KeyStateWrite(..) => defmt::warn!("Unexpected write to the key state service"),
-            },
-            CommunicationServerEvent::FlashService(event) => match event {
-                FlashServiceEvent::FlashOperationWrite(flash_operation) => {
-                    defmt::debug!("Received flash operation {:?}", flash_operation);
-
-                    if flash_sender.try_send(flash_operation).is_err() {
-                        defmt::error!("Failed to send flash operation to the flash task");
-                    }
-                }
-            },
-            CommunicationServerEvent::PowerService(event) => match event {
-                PowerServiceEvent::PowerOperationWrite(power_operation) => {
-                    defmt::debug!("Received power operation {:?}", power_operation);
-
-                    if power_sender.try_send(power_operation).is_err() {
-                        defmt::error!("Failed to send power operation to the power task");
-                    }
-                }
-            },
-            CommunicationServerEvent::EventService(event) => match event {
-                EventServiceEvent::EventWrite(event) => {
-                    defmt::debug!("Received event {:?}", event);
-
-                    if event_sender.try_send(event).is_err() {
-                        defmt::error!("Failed to send event");
-                    }
-                }
-            },
-            #[cfg(feature = "lighting")]
-            CommunicationServerEvent::LightingService(event) => match event {
-                LightingServiceEvent::LightingOperationWrite(lighting_operation) => {
-                    defmt::debug!("Received lighting operation {:?}", lighting_operation);
-
-                    if lighting_sender.try_send(lighting_operation).is_err() {
-                        defmt::error!("Failed to send lighting operation to the lighting task");
-                    }
-                }
-            },
-        })
-        .fuse();
+        let battery_level_future = battery_level_receiver.recv().fuse();
 
         pin_mut!(scan_future);
         pin_mut!(event_future);
-        pin_mut!(connection_future);
+        pin_mut!(battery_level_future);
 
         futures::select_biased! {
+            // Update the key state on the master.
             raw_state = scan_future => {
-                // Update the key state on the master.
-                key_state_client.key_state_write(&raw_state).await.map_err(|_| HalfDisconnected)?;
-            },
+                let message = SplitMessage::KeyState {
+                    state: raw_state,
+                    time: master_time(),
+                };
+                send_to_other_half(message).await;
+            }
             event = event_future => keyboard.event(event).await,
-            _ = connection_future => return Err(HalfDisconnected),
+            // The master reports the battery level of both halves to the host.
+            battery_level = battery_level_future => send_to_other_half(SplitMessage::BatteryLevel(battery_level)).await,
         }
     }
 }
@@ -0,0 +1,423 @@
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::{Channel, Receiver};
+use embassy_sync::signal::Signal;
+use embassy_time::driver::now;
+use embassy_time::{Duration, Instant, Timer};
+use futures::future::{pending, select, Either};
+use futures::{pin_mut, FutureExt};
+use nrf_softdevice::ble::gatt_client::WriteError;
+use nrf_softdevice::ble::{gatt_server, Connection};
+use nrf_softdevice::RawError;
+
+use super::clock::{answer_clock_request, stamp_clock_message, update_clock_offset};
+use super::event::event_sender;
+use super::handover::{accept_handover, acknowledge_acceptance, order_handover};
+use super::{decode_message, encode_message, HalfDisconnected, UsedEvent, Wire, WireMessage};
+use crate::battery::{slave_battery_level_sender, BatteryLevel};
+use crate::ble::{CommunicationServer, CommunicationServerEvent, TransportServiceClient, TransportServiceEvent, SPLIT_FRAME_SIZE};
+use crate::flash::{flash_sender, FlashOperation};
+#[cfg(feature = "lighting")]
+use crate::led::{lighting_sender, LightingOperation};
+use crate::power::{power_sender, PowerOperation};
+
+/// Message for the other half. Subsystems of the firmware that need to talk to
+/// the other half add a variant here and deliver it in `dispatch`. Keyboards
+/// can not add variants, they send their own messages as
+/// [`Keyboard::Events`](crate::interface::Keyboard::Events) instead.
+#[derive(Clone, defmt::Format, Wire)]
+pub enum SplitMessage {
+    /// Key state of the slave and the time of the master when it changed, if
+    /// the clocks are synchronized.
+    KeyState {
+        state: u64,
+        time: Option<u64>,
+    },
+    ClockRequest {
+        slave_time: u64,
+    },
+    ClockResponse {
+        slave_time: u64,
+        master_received: u64,
+        master_sent: u64,
+    },
+    BatteryLevel(BatteryLevel),
+    Flash(FlashOperation),
+    Power(PowerOperation),
+    #[cfg(feature = "lighting")]
+    Lighting(LightingOperation),
+    Event(UsedEvent),
+    Handover,
+    HandoverAccepted,
+}
+
+impl SplitMessage {
+    // Key states are needed for typing, so they skip ahead of everything else.
+    // Clock messages are timed, so they should not wait either.
+    fn is_priority(&self) -> bool {
+        matches!(
+            self,
+            Self::KeyState { .. } | Self::ClockRequest { .. } | Self::ClockResponse { .. }
+        )
+    }
+
+    // Handovers only make sense on the link they were sent on. Every other message
+    // is sent again after reconnecting if the other half might have missed it.
+    fn resend_after_reconnect(&self) -> bool {
+        !matches!(self, Self::Handover | Self::HandoverAccepted)
+    }
+}
+
+/// Frame that is written to the transport characteristic of the other half.
+/// Messages carry a sequence number, which the other half sends back once it
+/// received the message. Priority messages are only confirmed by the write
+/// response, so they never wait for another message to be acknowledged.
+#[derive(Clone, defmt::Format, Wire)]
+pub enum Frame {
+    Message { sequence: u8, message: SplitMessage },
+    Acknowledge { sequence: u8 },
+    Priority { message: SplitMessage },
+}
+
+// Time to wait for the other half to acknowledge a message before sending it
+// again.
+const ACKNOWLEDGE_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Number of times a message is sent again before the link is considered broken.
+const MAXIMUM_RETRIES: usize = 5;
+
+const MESSAGE_CHANNEL_SIZE: usize = 16;
+const ACKNOWLEDGE_CHANNEL_SIZE: usize = 8;
+const KEY_STATE_CHANNEL_SIZE: usize = 16;
+
+pub type KeyStateReceiver = Receiver<'static, ThreadModeRawMutex, (u64, u64), KEY_STATE_CHANNEL_SIZE>;
+
+static PRIORITY_MESSAGES: Channel<ThreadModeRawMutex, SplitMessage, MESSAGE_CHANNEL_SIZE> = Channel::new();
+static MESSAGES: Channel<ThreadModeRawMutex, SplitMessage, MESSAGE_CHANNEL_SIZE> = Channel::new();
+static SLAVE_KEY_STATES: Channel<ThreadModeRawMutex, (u64, u64), KEY_STATE_CHANNEL_SIZE> = Channel::new();
+
+// Message that was not acknowledged when the link broke, together with its
+// sequence number. It is sent first once the halves reconnect.
+static UNACKNOWLEDGED: Mutex<ThreadModeRawMutex, RefCell<Option<(u8, SplitMessage)>>> = Mutex::new(RefCell::new(None));
+
+// Sequence number of the next message and of the last message received from
+// the other half. They are kept across links, since a message that was not
+// acknowledged is sent again after reconnecting and must not be delivered
+// twice. Both halves only start over together, once they took on new roles.
+static NEXT_SEQUENCE: Mutex<ThreadModeRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+static LAST_RECEIVED: Mutex<ThreadModeRawMutex, Cell<Option<u8>>> = Mutex::new(Cell::new(None));
+
+// Whether the transport is currently running. While the link is down, messages
+// are queued without waiting, so a master that works alone does not block.
+static LINK_UP: AtomicBool = AtomicBool::new(false);
+
+/// Key states the master received from the slave, together with the time in
+/// ticks when they changed.
+pub fn slave_key_state_receiver() -> KeyStateReceiver {
+    SLAVE_KEY_STATES.receiver()
+}
+
+/// Release all keys of the slave, so keys that were held when the link broke
+/// do not get stuck.
+pub fn release_slave_keys() {
+    if SLAVE_KEY_STATES.try_send((0, now())).is_err() {
+        defmt::error!("Failed to release the keys of the slave");
+    }
+}
+
+/// Start counting messages from the beginning. Needs to be called by both
+/// halves whenever they take on a new role.
+pub(super) fn reset_transport() {
+    NEXT_SEQUENCE.lock(|next_sequence| next_sequence.set(0));
+    LAST_RECEIVED.lock(|last_received| last_received.set(None));
+}
+
+/// Queue a message for the other half. Messages are kept until the halves are
+/// connected. While the link is down, key states are dropped, since they are
+/// outdated by the time the halves reconnect, and other messages are dropped
+/// if the queue is full.
+pub async fn send_to_other_half(message: SplitMessage) {
+    if !LINK_UP.load(Ordering::Relaxed) {
+        if !message.is_priority() && !try_send_to_other_half(message) {
+            defmt::warn!("Dropped message for the other half while the link is down");
+        }
+        return;
+    }
+
+    match message.is_priority() {
+        true => PRIORITY_MESSAGES.send(message).await,
+        false => MESSAGES.send(message).await,
+    }
+}
+
+/// Queue a message for the other half without waiting. Returns `false` if
+/// the queue is full.
+pub fn try_send_to_other_half(message: SplitMessage) -> bool {
+    let result = match message.is_priority() {
+        true => PRIORITY_MESSAGES.try_send(message),
+        false => MESSAGES.try_send(message),
+    };
+
+    result.is_ok()
+}
+
+// Hand a message from the other half to the subsystem it belongs to.
+fn dispatch(message: SplitMessage) {
+    defmt::debug!("Received {:?} from the other half", message);
+
+    let delivered = match message {
+        SplitMessage::KeyState { state, time } => {
+            // Without synchronized clocks, the arrival is the best guess. The time can
+            // never be in the future, even if the clocks drifted apart.
+            let current_time = now();
+            let time = time.map_or(current_time, |time| time.min(current_time));
+
+            SLAVE_KEY_STATES.try_send((state, time)).is_ok()
+        }
+        SplitMessage::ClockRequest { slave_time } => {
+            answer_clock_request(slave_time);
+            true
+        }
+        SplitMessage::ClockResponse {
+            slave_time,
+            master_received,
+            master_sent,
+        } => {
+            update_clock_offset(slave_time, master_received, master_sent);
+            true
+        }
+        SplitMessage::BatteryLevel(battery_level) => slave_battery_level_sender().try_send(battery_level).is_ok(),
+        SplitMessage::Flash(flash_operation) => flash_sender().try_send(flash_operation).is_ok(),
+        SplitMessage::Power(power_operation) => power_sender().try_send(power_operation).is_ok(),
+        #[cfg(feature = "lighting")]
+        SplitMessage::Lighting(lighting_operation) => lighting_sender().try_send(lighting_operation).is_ok(),
+        SplitMessage::Event(event) => event_sender().try_send(event).is_ok(),
+        SplitMessage::Handover => {
+            order_handover();
+            true
+        }
+        SplitMessage::HandoverAccepted => {
+            accept_handover();
+            true
+        }
+    };
+
+    if !delivered {
+        defmt::error!("Failed to deliver message from the other half");
+    }
+}
+
+async fn write_frame(client: &TransportServiceClient, frame: &Frame) -> Result<(), HalfDisconnected> {
+    // Frames are sized to fit every message, so encoding can only fail because of a
+    // bug.
+    let encoded: WireMessage<SPLIT_FRAME_SIZE> = defmt::unwrap!(encode_message(frame));
+
+    loop {
+        match client.frame_write(&encoded).await {
+            Ok(..) => return Ok(()),
+            Err(WriteError::Raw(RawError::Busy)) => {
+                defmt::warn!("Link to the other half busy");
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            Err(error) => {
+                defmt::error!("Failed to write to the other half: {:?}", error);
+                return Err(HalfDisconnected);
+            }
+        }
+    }
+}
+
+// Message that was sent but not acknowledged yet.
+struct InFlight {
+    message: SplitMessage,
+    sequence: u8,
+    retries: usize,
+    deadline: Instant,
+}
+
+impl InFlight {
+    fn frame(&self) -> Frame {
+        Frame::Message {
+            sequence: self.sequence,
+            message: self.message.clone(),
+        }
+    }
+}
+
+// Send queued messages one at a time, waiting for every message to be
+// acknowledged. Priority messages and acknowledgements for the other half are
+// sent in the meantime, so they never wait behind a message and both halves
+// can wait for each other.
+async fn write_frames(
+    client: &TransportServiceClient,
+    pending_acknowledgements: &Channel<ThreadModeRawMutex, u8, ACKNOWLEDGE_CHANNEL_SIZE>,
+    acknowledged: &Signal<ThreadModeRawMutex, u8>,
+    in_flight: &mut Option<InFlight>,
+) -> Result<!, HalfDisconnected> {
+    enum WriterEvent {
+        Acknowledge(u8),
+        Acknowledged(u8),
+        Timeout,
+        SendPriority(SplitMessage),
+        Send(SplitMessage),
+    }
+
+    // The message keeps its sequence number, so the other half can tell if it
+    // already received it before the link broke.
+    if let Some((sequence, message)) = UNACKNOWLEDGED.lock(|unacknowledged| unacknowledged.borrow_mut().take()) {
+        let sent = in_flight.insert(InFlight {
+            message,
+            sequence,
+            retries: 0,
+            deadline: Instant::now() + ACKNOWLEDGE_TIMEOUT,
+        });
+
+        write_frame(client, &sent.frame()).await?;
+    }
+
+    loop {
+        let writer_event = {
+            let deadline = in_flight.as_ref().map(|in_flight| in_flight.deadline);
+
+            let acknowledge_future = pending_acknowledgements.recv().fuse();
+            let acknowledged_future = acknowledged.wait().fuse();
+            let timeout_future = async {
+                match deadline {
+                    Some(deadline) => Timer::at(deadline).await,
+                    None => pending().await,
+                }
+            }
+            .fuse();
+            let priority_future = PRIORITY_MESSAGES.recv().fuse();
+            let message_future = async {
+                match deadline {
+                    Some(..) => pending().await,
+                    None => MESSAGES.recv().await,
+                }
+            }
+            .fuse();
+
+            pin_mut!(acknowledge_future);
+            pin_mut!(acknowledged_future);
+            pin_mut!(timeout_future);
+            pin_mut!(priority_future);
+            pin_mut!(message_future);
+
+            futures::select_biased! {
+                sequence = acknowledge_future => WriterEvent::Acknowledge(sequence),
+                sequence = acknowledged_future => WriterEvent::Acknowledged(sequence),
+                message = priority_future => WriterEvent::SendPriority(message),
+                _ = timeout_future => WriterEvent::Timeout,
+                message = message_future => WriterEvent::Send(message),
+            }
+        };
+
+        match writer_event {
+            WriterEvent::Acknowledge(sequence) => write_frame(client, &Frame::Acknowledge { sequence }).await?,
+            WriterEvent::Acknowledged(sequence) => {
+                if matches!(in_flight, Some(in_flight) if in_flight.sequence == sequence) {
+                    if let Some(InFlight {
+                        message: SplitMessage::HandoverAccepted,
+                        ..
+                    }) = in_flight.take()
+                    {
+                        acknowledge_acceptance();
+                    }
+                }
+            }
+            WriterEvent::Timeout => {
+                if let Some(in_flight) = in_flight.as_mut() {
+                    if in_flight.retries == MAXIMUM_RETRIES {
+                        defmt::error!("Other half did not acknowledge message {}", in_flight.sequence);
+                        return Err(HalfDisconnected);
+                    }
+
+                    defmt::warn!("Sending message {} to the other half again", in_flight.sequence);
+
+                    in_flight.retries += 1;
+                    in_flight.deadline = Instant::now() + ACKNOWLEDGE_TIMEOUT;
+                    write_frame(client, &in_flight.frame()).await?;
+                }
+            }
+            WriterEvent::SendPriority(mut message) => {
+                stamp_clock_message(&mut message);
+                write_frame(client, &Frame::Priority { message }).await?;
+            }
+            WriterEvent::Send(message) => {
+                let sequence = NEXT_SEQUENCE.lock(|next_sequence| next_sequence.replace(next_sequence.get().wrapping_add(1)));
+
+                // The message counts as in flight before it is written, so it is not lost if
+                // the write fails.
+                let sent = in_flight.insert(InFlight {
+                    message,
+                    sequence,
+                    retries: 0,
+                    deadline: Instant::now() + ACKNOWLEDGE_TIMEOUT,
+                });
+
+                write_frame(client, &sent.frame()).await?;
+            }
+        }
+    }
+}
+
+/// Exchange messages with the other half until the link breaks.
+pub async fn run_transport(connection: &Connection, server: &CommunicationServer, client: &TransportServiceClient) -> HalfDisconnected {
+    let pending_acknowledgements = Channel::<ThreadModeRawMutex, u8, ACKNOWLEDGE_CHANNEL_SIZE>::new();
+    let acknowledged = Signal::<ThreadModeRawMutex, u8>::new();
+
+    let server_future = gatt_server::run(connection, server, |event| match event {
+        CommunicationServerEvent::TransportService(TransportServiceEvent::FrameWrite(data)) => match decode_message(&data) {
+            Ok(Frame::Acknowledge { sequence }) => acknowledged.signal(sequence),
+            Ok(Frame::Priority { message }) => dispatch(message),
+            Ok(Frame::Message { sequence, message }) => {
+                // If the acknowledgement got lost, the other half sends the same message
+                // again. The same goes for the message that was not acknowledged when the
+                // link broke. It is acknowledged again, but only delivered once.
+                if LAST_RECEIVED.lock(|last_received| last_received.replace(Some(sequence))) != Some(sequence) {
+                    dispatch(message);
+                }
+
+                if pending_acknowledgements.try_send(sequence).is_err() {
+                    defmt::warn!("Too many pending acknowledgements for the other half");
+                }
+            }
+            Err(error) => defmt::error!("Failed to decode frame from the other half: {}", error),
+        },
+    });
+    let mut in_flight = None;
+
+    {
+        let writer_future = write_frames(client, &pending_acknowledgements, &acknowledged, &mut in_flight);
+
+        pin_mut!(server_future);
+        pin_mut!(writer_future);
+
+        LINK_UP.store(true, Ordering::Relaxed);
+
+        match select(server_future, writer_future).await {
+            Either::Left(..) => defmt::warn!("Link to the other half closed"),
+            Either::Right(..) => {
+                // Make sure the other half notices as well.
+                let _ = connection.disconnect();
+            }
+        }
+
+        LINK_UP.store(false, Ordering::Relaxed);
+    }
+
+    // The other half might have missed the message that was not acknowledged, so
+    // it is sent again once the halves reconnect.
+    if let Some(in_flight) = in_flight.filter(|in_flight| in_flight.message.resend_after_reconnect()) {
+        defmt::debug!("Keeping message {} until the halves reconnect", in_flight.sequence);
+        UNACKNOWLEDGED.lock(|unacknowledged| *unacknowledged.borrow_mut() = Some((in_flight.sequence, in_flight.message)));
+    }
+
+    // Key states that were not sent yet are outdated once the halves reconnect.
+    while PRIORITY_MESSAGES.try_recv().is_ok() {}
+
+    HalfDisconnected
+}